@@ -0,0 +1,319 @@
+// Derives a data object's `Bindable` & `DataObject` impls, along with its
+// `Request*` twin where every field is optional:
+//
+//     #[derive(DataObject)]
+//     #[authrs(table = "notes")]
+//     pub struct Note {
+//         pub id: i64,
+//         #[authrs(required)]
+//         pub owner_id: i64,
+//         #[authrs(required)]
+//         pub contents: String,
+//     }
+//
+// Field attributes:
+//   id        the primary key, defaults to the field named `id`
+//   required  must be set on create
+//   default   nullable column read into a non optional field
+//   internal  never deserialized into the request, set by the server only,
+//             & can't be filtered on
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Fields, GenericArgument, Ident, LitStr, PathArguments, Type,
+    parse_macro_input, spanned::Spanned,
+};
+
+#[proc_macro_derive(DataObject, attributes(authrs))]
+pub fn derive_data_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    id: bool,
+    required: bool,
+    default: bool,
+    internal: bool,
+}
+
+impl Field {
+    fn col(&self) -> String {
+        self.ident.to_string()
+    }
+
+    // Option<T> stays Option<T> in the request, anything else is wrapped
+    fn request_ty(&self) -> TokenStream2 {
+        let ty = &self.ty;
+        match option_inner(ty) {
+            Some(_) => quote!(#ty),
+            None => quote!(::std::option::Option<#ty>),
+        }
+    }
+
+    // the type each request value is bound as
+    fn value_ty(&self) -> &Type {
+        option_inner(&self.ty).unwrap_or(&self.ty)
+    }
+}
+
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let mut table = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("authrs")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("expected `table`"))
+            }
+        })?;
+    }
+    let table = table
+        .ok_or_else(|| syn::Error::new(input.ident.span(), "missing #[authrs(table = \"...\")]"))?;
+
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "DataObject needs named fields",
+                ));
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "DataObject can only be derived for structs",
+            ));
+        }
+    };
+    let mut fields = vec![];
+    for field in named {
+        let ident = field.ident.clone().expect("named field");
+        let mut parsed = Field {
+            id: false,
+            required: false,
+            default: false,
+            internal: false,
+            ident,
+            ty: field.ty.clone(),
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("authrs")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    parsed.id = true;
+                } else if meta.path.is_ident("required") {
+                    parsed.required = true;
+                } else if meta.path.is_ident("default") {
+                    parsed.default = true;
+                } else if meta.path.is_ident("internal") {
+                    parsed.internal = true;
+                } else {
+                    return Err(meta.error("expected `id`, `required`, `default` or `internal`"));
+                }
+                Ok(())
+            })?;
+        }
+        fields.push(parsed);
+    }
+    let id = match fields.iter().position(|f| f.id) {
+        Some(i) => i,
+        None => fields.iter().position(|f| f.ident == "id").ok_or_else(|| {
+            syn::Error::new(named.span(), "no `id` field, mark one #[authrs(id)]")
+        })?,
+    };
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let request = format_ident!("Request{}", name);
+    let id_ident = &fields[id].ident;
+    let id_col = fields[id].col();
+    let sql_cols = fields.iter().map(Field::col).collect::<Vec<_>>().join(",");
+
+    let bind_data = fields.iter().enumerate().map(|(i, f)| {
+        let ident = &f.ident;
+        let idx = i + 1;
+        quote! {
+            ::authrs::types::Column::bind(&self.#ident, statement, #idx)?;
+        }
+    });
+    let read_data = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = &f.ty;
+        let col = f.col();
+        if f.default {
+            quote! {
+                #ident: <::std::option::Option<#ty> as ::authrs::types::Column>::read(statement, #col)
+                    .map_err(::authrs::store::error::StoreError::decode)?
+                    .unwrap_or_default(),
+            }
+        } else {
+            quote! {
+                #ident: <#ty as ::authrs::types::Column>::read(statement, #col)
+                    .map_err(::authrs::store::error::StoreError::decode)?,
+            }
+        }
+    });
+
+    let request_fields = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = f.request_ty();
+        let skip = f.internal.then(|| quote!(#[serde(skip)]));
+        quote! {
+            #skip
+            pub #ident: #ty,
+        }
+    });
+    let bind_request = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = f.value_ty();
+        quote! {
+            if let ::std::option::Option::Some(value) = &self.#ident {
+                <#ty as ::authrs::types::Column>::bind(value, statement, idx)?;
+                idx += 1;
+            }
+        }
+    });
+    let validate_required = fields.iter().filter(|f| f.required).map(|f| {
+        let ident = &f.ident;
+        let col = f.col();
+        quote! {
+            if self.#ident.is_none() {
+                return Err(::authrs::types::ValidationError::MissingRequiredOnCreate(
+                    ::std::string::String::from(#col),
+                ));
+            }
+        }
+    });
+    let request_cols = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let col = f.col();
+        quote! {
+            if self.#ident.is_some() {
+                cols.push(#col);
+            }
+        }
+    });
+    let request_idents = fields.iter().map(|f| &f.ident);
+    let filter_cols = fields.iter().filter(|f| !f.internal).map(|f| {
+        let col = f.col();
+        let ty = &f.ty;
+        quote! {
+            (#col, <#ty as ::authrs::types::Column>::TYPE)
+        }
+    });
+
+    Ok(quote! {
+        impl ::sqlite::Bindable for #name {
+            fn bind(self, statement: &mut ::sqlite::Statement) -> ::sqlite::Result<()> {
+                #(#bind_data)*
+                Ok(())
+            }
+        }
+
+        impl ::authrs::types::DataObject for #name {
+            fn from_rows(
+                statement: &mut ::sqlite::Statement,
+            ) -> ::authrs::store::error::StoreResult<::std::vec::Vec<Self>> {
+                let mut res = vec![];
+                while let ::sqlite::State::Row = statement.next()? {
+                    res.push(Self {
+                        #(#read_data)*
+                    });
+                }
+                Ok(res)
+            }
+
+            fn table_name() -> ::std::string::String {
+                #table.to_string()
+            }
+
+            fn sql_cols() -> ::std::string::String {
+                #sql_cols.to_string()
+            }
+
+            fn id_col() -> ::std::string::String {
+                #id_col.to_string()
+            }
+
+            fn filter_cols() -> &'static [(&'static str, ::authrs::types::ColumnType)] {
+                const COLS: &[(&str, ::authrs::types::ColumnType)] = &[#(#filter_cols),*];
+                COLS
+            }
+        }
+
+        #[derive(Debug, Clone, Default, ::serde::Deserialize, ::serde::Serialize)]
+        #vis struct #request {
+            #(#request_fields)*
+        }
+
+        impl ::sqlite::Bindable for #request {
+            #[allow(unused_assignments)]
+            fn bind(self, statement: &mut ::sqlite::Statement) -> ::sqlite::Result<()> {
+                let mut idx = 1;
+                #(#bind_request)*
+                Ok(())
+            }
+        }
+
+        impl ::authrs::types::RequestObject for #request {
+            fn validate_create(&self) -> Result<(), ::authrs::types::ValidationError> {
+                #(#validate_required)*
+                if self.#id_ident.is_some() {
+                    return Err(::authrs::types::ValidationError::IdProvidedOnCreate);
+                }
+                Ok(())
+            }
+
+            fn validate_update(&self) -> Result<(), ::authrs::types::ValidationError> {
+                match self.#id_ident {
+                    Some(_) => Ok(()),
+                    None => Err(::authrs::types::ValidationError::MissingIdOnUpdate),
+                }
+            }
+
+            fn sql_cols(&self) -> ::std::string::String {
+                let mut cols: ::std::vec::Vec<&str> = vec![];
+                #(#request_cols)*
+                cols.join(",")
+            }
+
+            fn sql_placeholders(&self) -> ::std::string::String {
+                let ct = [#(self.#request_idents.is_some()),*]
+                    .iter()
+                    .filter(|set| **set)
+                    .count();
+                vec!["?"; ct].join(",")
+            }
+
+            fn id(&self) -> Option<i64> {
+                self.#id_ident
+            }
+        }
+    })
+}
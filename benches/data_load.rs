@@ -0,0 +1,190 @@
+// Throughput of /data under concurrent load, once with reads sharing the
+// writer's connection & once with the read pool.
+//
+//     cargo bench --bench data_load
+//
+// AUTHRS_BENCH_CLIENTS & AUTHRS_BENCH_SECONDS change the load, every tenth
+// request is a write.
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use authrs::{
+    AuthrState, SqliteStore, Store,
+    auth::{google_auth::GoogleAuthClient, tokens::hash_secret},
+    config::{AuthConfig, RateLimit, RateLimitConfig, SqliteConfig},
+    run,
+    store::migrations,
+    types::{Note, RequestNote, RequestServiceAccount, RequestUser, ServiceAccount, User},
+};
+use tokio::net::TcpListener;
+
+const NOTES: i64 = 100;
+
+fn main() {
+    // the google client is never used, it only has to be configured
+    for key in ["GOOGLE_OAUTH_CLIENT_ID", "GOOGLE_OAUTH_CLIENT_SECRET"] {
+        if std::env::var(key).is_err() {
+            // nothing else is running yet
+            unsafe { std::env::set_var(key, "bench") };
+        }
+    }
+    let clients = env_number("AUTHRS_BENCH_CLIENTS", 64);
+    let seconds = env_number("AUTHRS_BENCH_SECONDS", 5);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start runtime");
+    for pool_size in [0, SqliteConfig::default().pool_size] {
+        let requests = runtime.block_on(load(pool_size, clients, seconds));
+        println!(
+            "pool_size {}: {} requests in {}s, {:.0} req/s",
+            pool_size,
+            requests,
+            seconds,
+            requests as f64 / seconds as f64
+        );
+    }
+}
+
+fn env_number(key: &str, default: u64) -> u64 {
+    std::env::var(key)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn load(pool_size: u32, clients: u64, seconds: u64) -> u64 {
+    let dir = std::env::temp_dir().join(format!("authrs-bench-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create bench dir");
+    let store = SqliteStore::new(&SqliteConfig {
+        path: dir.join("bench.db").to_string_lossy().to_string(),
+        pool_size,
+        ..SqliteConfig::default()
+    })
+    .expect("Failed to open sqlite");
+    migrations::migrate(&store).expect("Failed to migrate");
+    store
+        .create::<_, ServiceAccount>(RequestServiceAccount {
+            client_id: Some("bench".to_string()),
+            secret_hash: Some(hash_secret("bench")),
+            name: Some("bench".to_string()),
+            scopes: Some("data:read data:write".to_string()),
+            ..Default::default()
+        })
+        .expect("Failed to create service account");
+    let owner = store
+        .create::<_, User>(RequestUser {
+            guid: Some("bench/1".to_string()),
+            name: Some("bench".to_string()),
+            email: Some("bench@example.com".to_string()),
+            picture: Some(String::new()),
+            ..Default::default()
+        })
+        .expect("Failed to create user");
+    for i in 0..NOTES {
+        store
+            .create::<_, Note>(RequestNote {
+                id: None,
+                owner_id: Some(owner.id),
+                contents: Some(format!("note {}", i)),
+            })
+            .expect("Failed to create note");
+    }
+
+    let unlimited = RateLimit {
+        capacity: f64::MAX,
+        per_second: f64::MAX,
+    };
+    let config = AuthConfig {
+        rate_limits: RateLimitConfig {
+            auth: unlimited,
+            data_read: unlimited,
+            data_write: unlimited,
+            ..RateLimitConfig::default()
+        },
+        ..AuthConfig::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind address");
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let note = format!(r#"{{"owner_id":{},"contents":"bench"}}"#, owner.id);
+    let server = tokio::spawn(run(
+        listener,
+        AuthrState::new(GoogleAuthClient::from_env(), store, config),
+    ));
+
+    let client = reqwest::Client::new();
+    let token = access_token(&client, &base).await;
+    let requests = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let workers = (0..clients)
+        .map(|worker| {
+            let client = client.clone();
+            let base = base.clone();
+            let token = token.clone();
+            let note = note.clone();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut i = worker;
+                while Instant::now() < deadline {
+                    let request = if i % 10 == 0 {
+                        client
+                            .post(format!("{}/data/note", base))
+                            .header("content-type", "application/json")
+                            .body(note.clone())
+                    } else {
+                        client.get(format!("{}/data/note/{}", base, i as i64 % NOTES + 1))
+                    };
+                    let response = request.bearer_auth(&token).send().await;
+                    match response {
+                        Ok(response) if response.status().is_success() => {
+                            requests.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(response) => panic!(
+                            "Unexpected response from {}: {}",
+                            response.url(),
+                            response.status()
+                        ),
+                        Err(e) => panic!("Request failed: {:?}", e),
+                    }
+                    i += 1;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.await.expect("Worker failed");
+    }
+    server.abort();
+    let _ = std::fs::remove_dir_all(&dir);
+    requests.load(Ordering::Relaxed)
+}
+
+async fn access_token(client: &reqwest::Client, base: &str) -> String {
+    let response = client
+        .post(format!("{}/oauth/token", base))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", "bench"),
+            ("client_secret", "bench"),
+        ])
+        .send()
+        .await
+        .expect("Failed to request a token")
+        .text()
+        .await
+        .expect("Failed to read the token");
+    let response: serde_json::Value = serde_json::from_str(&response).expect("Invalid token");
+    response["access_token"]
+        .as_str()
+        .expect("No access token")
+        .to_string()
+}
@@ -0,0 +1,22 @@
+-- append-only, so there's no down migration
+CREATE TABLE IF NOT EXISTS audit_log (
+    id bigserial primary key,
+    timestamp bigint not null,
+    actor text not null,
+    action text not null,
+    target_type text,
+    target_id bigint,
+    before text,
+    after text,
+    prev_hash text not null,
+    hash text not null unique);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
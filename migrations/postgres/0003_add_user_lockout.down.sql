@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN IF EXISTS known_devices;
+ALTER TABLE users DROP COLUMN IF EXISTS locked_until;
+ALTER TABLE users DROP COLUMN IF EXISTS lockouts;
+ALTER TABLE users DROP COLUMN IF EXISTS failed_logins;
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS failed_logins bigint not null default 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS lockouts bigint not null default 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS locked_until bigint not null default 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS known_devices text not null default '';
@@ -0,0 +1,8 @@
+-- IF NOT EXISTS, later bootstrap binaries created the table before migrations
+CREATE TABLE IF NOT EXISTS service_accounts (
+    id bigserial primary key,
+    client_id text not null unique,
+    secret_hash text not null,
+    name text not null,
+    scopes text not null,
+    disabled bigint not null default 0);
@@ -0,0 +1,12 @@
+-- IF NOT EXISTS, later bootstrap binaries created the table before migrations
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id bigserial primary key,
+    token_hash text not null unique,
+    family text not null,
+    user_id bigint not null,
+    client_id text not null,
+    scopes text not null,
+    expires bigint not null,
+    used bigint not null default 0,
+    revoked bigint not null default 0,
+    foreign key(user_id) references users(id));
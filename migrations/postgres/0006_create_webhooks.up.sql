@@ -0,0 +1,16 @@
+-- IF NOT EXISTS, later bootstrap binaries created the tables before migrations
+CREATE TABLE IF NOT EXISTS webhooks (
+    id bigserial primary key,
+    url text not null,
+    events text not null,
+    secret text not null);
+
+CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+    id bigserial primary key,
+    webhook_id bigint not null,
+    delivery_id text not null,
+    event text not null,
+    payload text not null,
+    attempts bigint not null,
+    last_error text not null,
+    created bigint not null);
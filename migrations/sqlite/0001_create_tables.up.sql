@@ -0,0 +1,14 @@
+-- the tables the first bootstrap binary created, IF NOT EXISTS lets databases
+-- it set up adopt them. Tables & columns added since are later migrations
+CREATE TABLE IF NOT EXISTS users (
+    id integer primary key autoincrement,
+    guid text not null,
+    name text,
+    email text,
+    picture text);
+
+CREATE TABLE IF NOT EXISTS notes (
+    id integer primary key autoincrement,
+    owner_id integer,
+    contents text,
+    foreign key(owner_id) references users(id));
@@ -0,0 +1,22 @@
+-- append-only, so there's no down migration
+CREATE TABLE IF NOT EXISTS audit_log (
+    id integer primary key autoincrement,
+    timestamp integer not null,
+    actor text not null,
+    action text not null,
+    target_type text,
+    target_id integer,
+    before text,
+    after text,
+    prev_hash text not null,
+    hash text not null unique);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
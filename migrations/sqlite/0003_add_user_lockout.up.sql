@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN failed_logins integer not null default 0;
+ALTER TABLE users ADD COLUMN lockouts integer not null default 0;
+ALTER TABLE users ADD COLUMN locked_until integer not null default 0;
+ALTER TABLE users ADD COLUMN known_devices text not null default '';
@@ -0,0 +1,12 @@
+-- IF NOT EXISTS, later bootstrap binaries created the table before migrations
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id integer primary key autoincrement,
+    token_hash text not null unique,
+    family text not null,
+    user_id integer not null,
+    client_id text not null,
+    scopes text not null,
+    expires integer not null,
+    used integer not null default 0,
+    revoked integer not null default 0,
+    foreign key(user_id) references users(id));
@@ -0,0 +1,16 @@
+-- IF NOT EXISTS, later bootstrap binaries created the tables before migrations
+CREATE TABLE IF NOT EXISTS webhooks (
+    id integer primary key autoincrement,
+    url text not null,
+    events text not null,
+    secret text not null);
+
+CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+    id integer primary key autoincrement,
+    webhook_id integer not null,
+    delivery_id text not null,
+    event text not null,
+    payload text not null,
+    attempts integer not null,
+    last_error text not null,
+    created integer not null);
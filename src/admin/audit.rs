@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    response::IntoResponse,
+    routing::get,
+};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    AuthState, Store,
+    types::{
+        AuditEntryByActor, AuditEntryByTargetType, AuditEntryFrom, AuditEntryQuery, AuditEntryTo,
+        QueryTypes,
+    },
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/audit", get(list))
+        .route("/audit/verify", get(verify))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditFilter {
+    actor: Option<String>,
+    #[serde(rename = "type")]
+    target_type: Option<String>,
+    // unix timestamps, inclusive
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+pub async fn list<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Query(filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    let mut queries = vec![];
+    if let Some(actor) = filter.actor {
+        queries.push(AuditEntryQuery::ByActor(AuditEntryByActor::new(actor)));
+    }
+    if let Some(target_type) = filter.target_type {
+        queries.push(AuditEntryQuery::ByTargetType(AuditEntryByTargetType::new(
+            target_type,
+        )));
+    }
+    if let Some(from) = filter.from {
+        queries.push(AuditEntryQuery::From(AuditEntryFrom::new(from)));
+    }
+    if let Some(to) = filter.to {
+        queries.push(AuditEntryQuery::To(AuditEntryTo::new(to)));
+    }
+    let queries = queries.into_iter().map(QueryTypes::named).collect();
+    match state.audit.query(queries) {
+        Ok(entries) => Json(entries).into_response(),
+        Err(e) => {
+            error!("Could not read audit log: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+// Walks the hash chain, reporting the first entry that was tampered with
+pub async fn verify<S: Store>(State(state): State<Arc<AuthState<S>>>) -> impl IntoResponse {
+    match state.audit.verify() {
+        Ok(status) => Json(status).into_response(),
+        Err(e) => {
+            error!("Could not verify audit log: {:?}", e);
+            e.into_response()
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, header::SET_COOKIE},
+    response::{AppendHeaders, IntoResponse},
+    routing::post,
+};
+use axum_extra::extract::CookieJar;
+use tracing::{error, info};
+
+use crate::{
+    AuthState, Store,
+    auth::{
+        Principal,
+        client_info::ClientInfo,
+        sessions::{
+            Impersonator, Session, get_session, remove_session, session_cookie, session_id,
+            start_session,
+        },
+    },
+    error::AuthrError,
+    types::User,
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/impersonate/{user_id}", post(start))
+        .with_state(state)
+}
+
+// Swaps the admin's session cookie for a short lived session as `user_id`
+pub async fn start<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    jar: CookieJar,
+    client: ClientInfo,
+    Path(user_id): Path<i64>,
+) -> impl IntoResponse {
+    let admin = match principal {
+        Principal::User(admin) => admin,
+        _ => return AuthrError::NotAuthorized.into_response(),
+    };
+    // impersonation replaces the browser session, so the admin must have one
+    let admin_session = match session_id(&state, &jar) {
+        Some(session_id) => session_id.to_string(),
+        None => {
+            return AuthrError::BadRequest("Impersonation needs a browser session".to_string())
+                .into_response();
+        }
+    };
+    match get_session(&state, &admin_session) {
+        Some(session) if session.user.id == admin.id && session.impersonator.is_none() => {}
+        _ => {
+            return AuthrError::BadRequest("Already impersonating a user".to_string())
+                .into_response();
+        }
+    }
+
+    let user = match state.store.get::<User>(user_id) {
+        Ok(Some(user)) => user,
+        Ok(None) => return AuthrError::NotFound.into_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            return e.into_response();
+        }
+    };
+    if user.id == admin.id || state.config.admins.contains(&user.guid) {
+        return AuthrError::NotAuthorized.into_response();
+    }
+
+    let duration = state.config.impersonation_duration;
+    let impersonator = Impersonator {
+        admin: admin.clone(),
+        admin_session,
+    };
+    let session_id = match start_session(
+        &state,
+        user.clone(),
+        duration,
+        client,
+        "impersonation",
+        Some(impersonator),
+        None,
+    ) {
+        Some(session_id) => session_id,
+        None => return AuthrError::Internal.into_response(),
+    };
+    info!(target: "audit", "user:{} started impersonating user:{}", admin.id, user.id);
+
+    (
+        AppendHeaders([(
+            SET_COOKIE,
+            session_cookie(&state, session_id, duration).to_string(),
+        )]),
+        Json(user),
+    )
+        .into_response()
+}
+
+// Ends an impersonation session & restores the admin's own session
+pub async fn stop<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let session_id = match session_id(&state, &jar) {
+        Some(session_id) => session_id.to_string(),
+        None => return AuthrError::Unauthenticated.into_response(),
+    };
+    let (user, impersonator) = match get_session(&state, &session_id) {
+        Some(Session {
+            user,
+            impersonator: Some(impersonator),
+            ..
+        }) => (user, impersonator),
+        _ => return AuthrError::BadRequest("Not impersonating a user".to_string()).into_response(),
+    };
+    remove_session(&state, &session_id);
+    info!(target: "audit", "user:{} stopped impersonating user:{}", impersonator.admin.id, user.id);
+
+    // the admin's session may have expired while impersonating
+    let cookie = match get_session(&state, &impersonator.admin_session) {
+        Some(admin_session) => session_cookie(
+            &state,
+            impersonator.admin_session,
+            admin_session.expires - time::OffsetDateTime::now_utc(),
+        ),
+        None => session_cookie(&state, String::new(), time::Duration::ZERO),
+    };
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([(SET_COOKIE, cookie.to_string())]),
+    )
+        .into_response()
+}
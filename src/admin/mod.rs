@@ -0,0 +1,40 @@
+use crate::{AuthState, Store, auth::Principal, error::AuthrError};
+use axum::{
+    Router,
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+pub mod audit;
+pub mod impersonation;
+pub mod service_accounts;
+pub mod sessions;
+pub mod users;
+pub mod webhooks;
+
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .merge(audit::routes(state.clone()))
+        .merge(service_accounts::routes(state.clone()))
+        .merge(impersonation::routes(state.clone()))
+        .merge(sessions::routes(state.clone()))
+        .merge(users::routes(state.clone()))
+        .merge(webhooks::routes(state.clone()))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+// admin middleware, must run after auth::request_authorizer
+pub async fn require_admin<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match req.extensions().get::<Principal>() {
+        Some(Principal::User(user)) if state.config.admins.contains(&user.guid) => {
+            next.run(req).await
+        }
+        _ => AuthrError::NotAuthorized.into_response(),
+    }
+}
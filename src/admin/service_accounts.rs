@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    AuthState, Store,
+    auth::{Principal, tokens},
+    error::AuthrError,
+    types::{RequestServiceAccount, ServiceAccount},
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/service_accounts", get(list).post(create))
+        .route("/service_accounts/{id}/rotate", post(rotate))
+        .route("/service_accounts/{id}/disable", post(disable))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateServiceAccount {
+    name: String,
+    scopes: Option<String>,
+}
+
+// the client secret is only ever returned from create & rotate
+#[derive(Debug, Serialize)]
+pub struct ServiceAccountCredentials {
+    service_account: ServiceAccount,
+    client_secret: String,
+}
+
+pub async fn list<S: Store>(State(state): State<Arc<AuthState<S>>>) -> impl IntoResponse {
+    match state.store.get_queries::<ServiceAccount>(vec![]) {
+        Ok(service_accounts) => Json(service_accounts).into_response(),
+        Err(e) => {
+            error!("Could not list service accounts: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn create<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<CreateServiceAccount>,
+) -> impl IntoResponse {
+    let scopes = payload
+        .scopes
+        .unwrap_or_else(|| tokens::SCOPE_DATA_READ.to_string());
+    if !tokens::parse_scopes(&scopes)
+        .iter()
+        .all(|s| tokens::SCOPES.contains(&s.as_str()))
+    {
+        return AuthrError::BadRequest(format!("Unknown scope in `{}`", scopes)).into_response();
+    }
+
+    let client_secret = tokens::generate_secret();
+    let request = RequestServiceAccount {
+        client_id: Some(format!("sa-{}", tokens::generate_secret())),
+        secret_hash: Some(tokens::hash_secret(&client_secret)),
+        name: Some(payload.name),
+        scopes: Some(scopes),
+        ..Default::default()
+    };
+    match state.store.create::<_, ServiceAccount>(request) {
+        Ok(service_account) => {
+            info!(target: "audit", "{} created service_account:{}", principal, service_account.client_id);
+            Json(ServiceAccountCredentials {
+                service_account,
+                client_secret,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            error!("Could not create service account: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn rotate<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    let client_secret = tokens::generate_secret();
+    let request = RequestServiceAccount {
+        id: Some(id),
+        secret_hash: Some(tokens::hash_secret(&client_secret)),
+        ..Default::default()
+    };
+    match state.store.update::<_, ServiceAccount>(request) {
+        Ok(service_account) => {
+            info!(target: "audit", "{} rotated secret for service_account:{}", principal, service_account.client_id);
+            Json(ServiceAccountCredentials {
+                service_account,
+                client_secret,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            error!("Could not rotate service account secret: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn disable<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    let request = RequestServiceAccount {
+        id: Some(id),
+        disabled: Some(true),
+        ..Default::default()
+    };
+    let service_account = match state.store.update::<_, ServiceAccount>(request) {
+        Ok(service_account) => service_account,
+        Err(e) => {
+            error!("Could not disable service account: {:?}", e);
+            return e.into_response();
+        }
+    };
+    info!(target: "audit", "{} disabled service_account:{}", principal, service_account.client_id);
+
+    // outstanding tokens die with the account
+    match state.access_tokens.lock() {
+        Ok(mut access_tokens) => access_tokens.retain(|_, t| match &t.principal {
+            Principal::ServiceAccount(account) => account.id != service_account.id,
+            _ => true,
+        }),
+        Err(e) => {
+            error!("{:?}", e);
+        }
+    }
+    Json(service_account).into_response()
+}
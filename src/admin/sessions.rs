@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use crate::{
+    AuthState, Store,
+    auth::{
+        Principal,
+        sessions::{audit_revocation, end_session_family, list_sessions, remove_session_by_handle},
+    },
+    error::AuthrError,
+};
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get},
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/users/{user_id}/sessions", get(list))
+        .route("/users/{user_id}/sessions/{id}", delete(revoke))
+        .with_state(state)
+}
+
+pub async fn list<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Path(user_id): Path<i64>,
+) -> impl IntoResponse {
+    Json(list_sessions(&state, user_id, None))
+}
+
+pub async fn revoke<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path((user_id, handle)): Path<(i64, String)>,
+) -> impl IntoResponse {
+    match remove_session_by_handle(&state, user_id, &handle) {
+        Some(session) => {
+            end_session_family(&state, &session);
+            audit_revocation(&state, &principal, session);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => AuthrError::NotFound.into_response(),
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::post,
+};
+use tracing::info;
+
+use crate::{
+    AuthState, Store,
+    auth::{Principal, security},
+    error::AuthrError,
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/users/{user_id}/unlock", post(unlock))
+        .with_state(state)
+}
+
+pub async fn unlock<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(user_id): Path<i64>,
+) -> impl IntoResponse {
+    match security::unlock(&state, user_id) {
+        Some(user) => {
+            info!(target: "audit", "{} unlocked user:{}", principal, user.id);
+            Json(user).into_response()
+        }
+        None => AuthrError::NotFound.into_response(),
+    }
+}
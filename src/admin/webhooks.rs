@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
+};
+use oauth2::url::Url;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    AuthState, Store,
+    auth::{Principal, tokens},
+    error::AuthrError,
+    types::{RequestWebhook, Webhook, WebhookDeadLetter},
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/webhooks", get(list).post(create))
+        .route("/webhooks/{id}", delete(remove))
+        .route("/webhooks/dead_letters", get(dead_letters))
+        .route("/webhooks/dead_letters/{id}/retry", post(retry))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhook {
+    url: String,
+    events: Vec<String>,
+    // generated when not provided
+    secret: Option<String>,
+}
+
+// the signing secret is only ever returned from create
+#[derive(Debug, Serialize)]
+pub struct WebhookCredentials {
+    webhook: Webhook,
+    secret: String,
+}
+
+pub async fn list<S: Store>(State(state): State<Arc<AuthState<S>>>) -> impl IntoResponse {
+    match state.store.get_queries::<Webhook>(vec![]) {
+        Ok(webhooks) => Json(webhooks).into_response(),
+        Err(e) => {
+            error!("Could not list webhooks: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn create<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<CreateWebhook>,
+) -> impl IntoResponse {
+    let valid_url = Url::parse(&payload.url)
+        .map(|url| matches!(url.scheme(), "http" | "https"))
+        .unwrap_or(false);
+    let events = state.registry.events();
+    let valid_events =
+        !payload.events.is_empty() && payload.events.iter().all(|e| events.contains(e));
+    if !valid_url {
+        return AuthrError::BadRequest("The url must be http or https".to_string()).into_response();
+    }
+    if !valid_events {
+        return AuthrError::BadRequest(format!("Events must be some of {}", events.join(", ")))
+            .into_response();
+    }
+
+    let secret = payload.secret.unwrap_or_else(tokens::generate_secret);
+    let request = RequestWebhook {
+        url: Some(payload.url),
+        events: Some(payload.events.join(" ")),
+        secret: Some(secret.clone()),
+        ..Default::default()
+    };
+    match state.store.create::<_, Webhook>(request) {
+        Ok(webhook) => {
+            info!(target: "audit", "{} created webhook {} for {}", principal, webhook.id, webhook.url);
+            Json(WebhookCredentials { webhook, secret }).into_response()
+        }
+        Err(e) => {
+            error!("Could not create webhook: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn remove<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match state.store.delete::<Webhook>(id) {
+        Ok(webhook) => {
+            info!(target: "audit", "{} deleted webhook {}", principal, webhook.id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Could not delete webhook: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn dead_letters<S: Store>(State(state): State<Arc<AuthState<S>>>) -> impl IntoResponse {
+    match state.store.get_queries::<WebhookDeadLetter>(vec![]) {
+        Ok(dead_letters) => Json(dead_letters).into_response(),
+        Err(e) => {
+            error!("Could not list dead letters: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+pub async fn retry<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    let dead_letter = match state.store.get::<WebhookDeadLetter>(id) {
+        Ok(Some(dead_letter)) => dead_letter,
+        Ok(None) => return AuthrError::NotFound.into_response(),
+        Err(e) => {
+            error!("Could not read dead letter: {:?}", e);
+            return e.into_response();
+        }
+    };
+    let delivery_id = dead_letter.delivery_id.clone();
+    match state.webhooks.redeliver(dead_letter) {
+        true => {
+            info!(target: "audit", "{} retried webhook delivery {}", principal, delivery_id);
+            StatusCode::ACCEPTED.into_response()
+        }
+        false => AuthrError::NotFound.into_response(),
+    }
+}
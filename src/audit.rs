@@ -0,0 +1,295 @@
+use std::{fmt, sync::Arc, sync::Mutex};
+
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    Store,
+    auth::tokens,
+    store::error::StoreResult,
+    types::{AuditEntry, DataType, QueryTypes, RequestAuditEntry},
+};
+
+// Something worth recording, built up before it's appended to the log
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    actor: String,
+    action: &'static str,
+    target: Option<(DataType, i64)>,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(actor: impl fmt::Display, action: &'static str) -> Self {
+        Self {
+            actor: actor.to_string(),
+            action,
+            target: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub(crate) fn target(mut self, data_type: DataType, id: i64) -> Self {
+        self.target = Some((data_type, id));
+        self
+    }
+
+    pub(crate) fn before(mut self, snapshot: &impl Serialize) -> Self {
+        self.before = snapshot_json(snapshot);
+        self
+    }
+
+    pub(crate) fn after(mut self, snapshot: &impl Serialize) -> Self {
+        self.after = snapshot_json(snapshot);
+        self
+    }
+}
+
+fn snapshot_json(snapshot: &impl Serialize) -> Option<String> {
+    match serde_json::to_string(snapshot) {
+        Ok(json) => Some(json),
+        Err(e) => {
+            error!("Could not snapshot audit target: {:?}", e);
+            None
+        }
+    }
+}
+
+// Result of walking the hash chain
+#[derive(Debug, Serialize)]
+pub struct ChainStatus {
+    pub valid: bool,
+    pub entries: usize,
+    // first entry whose hash doesn't match its contents or predecessor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<i64>,
+}
+
+// Append-only, hash chained log. Each entry's hash covers its contents & the
+// previous entry's hash, so editing or removing a row breaks every later link.
+pub struct Audit<S: Store> {
+    store: Arc<S>,
+    // hash of the newest entry, loaded on the first append. Holding it also
+    // serializes appends so two entries can't claim the same predecessor.
+    head: Mutex<Option<String>>,
+}
+
+impl<S: Store> Audit<S> {
+    pub fn new(store: Arc<S>) -> Self {
+        Self {
+            store,
+            head: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn record(&self, record: AuditRecord) {
+        let mut head = match self.head.lock() {
+            Ok(head) => head,
+            Err(e) => {
+                error!("{:?}", e);
+                return;
+            }
+        };
+        let prev_hash = match head.as_ref() {
+            Some(hash) => hash.clone(),
+            // appending without the real head would break the chain
+            None => match self.store.get_queries::<AuditEntry>(vec![]) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .max_by_key(|e| e.id)
+                    .map(|e| e.hash)
+                    .unwrap_or_default(),
+                Err(e) => {
+                    error!("Could not read audit log head: {:?}", e);
+                    return;
+                }
+            },
+        };
+
+        let mut request = RequestAuditEntry {
+            timestamp: Some(time::OffsetDateTime::now_utc().unix_timestamp()),
+            actor: Some(record.actor),
+            action: Some(record.action.to_string()),
+            target_type: record.target.map(|(t, _)| t.to_string()),
+            target_id: record.target.map(|(_, id)| id),
+            before: record.before,
+            after: record.after,
+            prev_hash: Some(prev_hash),
+            ..Default::default()
+        };
+        request.hash = Some(chain_hash(&request));
+        match self.store.create::<_, AuditEntry>(request) {
+            Ok(entry) => *head = Some(entry.hash),
+            Err(e) => error!("Could not append audit entry: {:?}", e),
+        }
+    }
+
+    pub(crate) fn query(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<AuditEntry>> {
+        self.store.get_queries::<AuditEntry>(queries)
+    }
+
+    pub(crate) fn verify(&self) -> StoreResult<ChainStatus> {
+        let mut entries = self.store.get_queries::<AuditEntry>(vec![])?;
+        entries.sort_by_key(|e| e.id);
+        let mut prev_hash = String::new();
+        for entry in entries.iter() {
+            let request = RequestAuditEntry {
+                timestamp: Some(entry.timestamp),
+                actor: Some(entry.actor.clone()),
+                action: Some(entry.action.clone()),
+                target_type: entry.target_type.clone(),
+                target_id: entry.target_id,
+                before: entry.before.clone(),
+                after: entry.after.clone(),
+                prev_hash: Some(entry.prev_hash.clone()),
+                ..Default::default()
+            };
+            if entry.prev_hash != prev_hash || entry.hash != chain_hash(&request) {
+                return Ok(ChainStatus {
+                    valid: false,
+                    entries: entries.len(),
+                    broken_at: Some(entry.id),
+                });
+            }
+            prev_hash = entry.hash.clone();
+        }
+        Ok(ChainStatus {
+            valid: true,
+            entries: entries.len(),
+            broken_at: None,
+        })
+    }
+}
+
+// hash of everything but the id, which sqlite assigns
+fn chain_hash(entry: &RequestAuditEntry) -> String {
+    let contents = serde_json::json!([
+        entry.prev_hash,
+        entry.timestamp,
+        entry.actor,
+        entry.action,
+        entry.target_type,
+        entry.target_id,
+        entry.before,
+        entry.after,
+    ]);
+    tokens::hash_secret(&contents.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemStore, SqliteStore, config::SqliteConfig, store::migrations};
+
+    // the memory store has no append-only triggers, so rows can be tampered with
+    fn chain() -> (Arc<MemStore>, Audit<MemStore>) {
+        let store = Arc::new(MemStore::new().unwrap());
+        let audit = Audit::new(store.clone());
+        for (i, action) in ["login", "created", "updated", "logout"].iter().enumerate() {
+            audit.record(
+                AuditRecord::new(format!("user:{}", i), action)
+                    .target(DataType::NOTE, i as i64)
+                    .after(&serde_json::json!({ "contents": action })),
+            );
+        }
+        (store, audit)
+    }
+
+    fn entry(store: &MemStore, id: i64) -> AuditEntry {
+        store.get::<AuditEntry>(id).unwrap().unwrap()
+    }
+
+    #[test]
+    fn verify_accepts_untouched_chain() {
+        let (_, audit) = chain();
+        let status = audit.verify().unwrap();
+        assert!(status.valid);
+        assert_eq!(status.entries, 4);
+        assert_eq!(status.broken_at, None);
+    }
+
+    #[test]
+    fn verify_detects_edited_entry() {
+        let (store, audit) = chain();
+        let edit = RequestAuditEntry {
+            id: Some(2),
+            actor: Some("user:someone-else".to_string()),
+            ..Default::default()
+        };
+        store.update::<_, AuditEntry>(edit).unwrap();
+        let status = audit.verify().unwrap();
+        assert!(!status.valid);
+        assert_eq!(status.broken_at, Some(2));
+    }
+
+    // rehashing the edited entry moves the break to the next one
+    #[test]
+    fn verify_detects_rehashed_entry() {
+        let (store, audit) = chain();
+        let entry = entry(&store, 2);
+        let mut edit = RequestAuditEntry {
+            id: Some(2),
+            timestamp: Some(entry.timestamp),
+            actor: Some(entry.actor),
+            action: Some("deleted".to_string()),
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            before: entry.before,
+            after: entry.after,
+            prev_hash: Some(entry.prev_hash),
+            ..Default::default()
+        };
+        edit.hash = Some(chain_hash(&edit));
+        store.update::<_, AuditEntry>(edit).unwrap();
+        let status = audit.verify().unwrap();
+        assert!(!status.valid);
+        assert_eq!(status.broken_at, Some(3));
+    }
+
+    #[test]
+    fn verify_detects_deleted_entry() {
+        let (store, audit) = chain();
+        store.delete::<AuditEntry>(2).unwrap();
+        let status = audit.verify().unwrap();
+        assert!(!status.valid);
+        assert_eq!(status.entries, 3);
+        assert_eq!(status.broken_at, Some(3));
+
+        let (store, audit) = chain();
+        store.delete::<AuditEntry>(1).unwrap();
+        assert_eq!(audit.verify().unwrap().broken_at, Some(2));
+    }
+
+    #[test]
+    fn record_continues_the_stored_chain() {
+        let (store, _) = chain();
+        // a fresh log, e.g. after a restart, appends after the newest entry
+        let audit = Audit::new(store.clone());
+        audit.record(AuditRecord::new("user:9", "login"));
+        assert_eq!(entry(&store, 5).prev_hash, entry(&store, 4).hash);
+        assert!(audit.verify().unwrap().valid);
+    }
+
+    #[test]
+    fn sqlite_refuses_to_change_the_log() {
+        let store = SqliteStore::new(&SqliteConfig {
+            path: ":memory:".to_string(),
+            ..SqliteConfig::default()
+        })
+        .unwrap();
+        migrations::migrate(&store).unwrap();
+        let store = Arc::new(store);
+        let audit = Audit::new(store.clone());
+        audit.record(AuditRecord::new("user:1", "login"));
+        let edit = RequestAuditEntry {
+            id: Some(1),
+            actor: Some("user:2".to_string()),
+            ..Default::default()
+        };
+        assert!(store.update::<_, AuditEntry>(edit).is_err());
+        assert!(store.delete::<AuditEntry>(1).is_err());
+        assert!(audit.verify().unwrap().valid);
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    Form, Json, Router,
+    extract::{Query, State},
+    http::header::CACHE_CONTROL,
+    response::{Html, IntoResponse, Redirect},
+    routing::get,
+};
+use axum_extra::extract::CookieJar;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{
+    Principal, authorize_session,
+    client_info::ClientInfo,
+    error::OAuthError,
+    oauth::{TokenRequest, TokenResponse, issue_access_token},
+    refresh::issue_refresh_token,
+    security, tokens,
+};
+use crate::{AuthState, Store, rate_limit::too_many_requests, types::User};
+
+pub(crate) const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// no vowels so user codes can't spell words, see RFC 8628 section 6.1
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum DeviceStatus {
+    Pending,
+    Approved(User),
+    Denied,
+}
+
+// Outstanding device authorization request, keyed by device code
+#[derive(Debug, Clone)]
+pub struct DeviceAuthorization {
+    pub user_code: String,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    pub expires: time::OffsetDateTime,
+    pub interval: time::Duration,
+    pub last_polled: Option<time::OffsetDateTime>,
+    pub status: DeviceStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCodeRequest {
+    client_id: String,
+    scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i64,
+}
+
+// page routes, the user must already have a browser session
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/device", get(page).post(verify))
+        .with_state(state)
+}
+
+pub async fn device_code<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Form(params): Form<DeviceCodeRequest>,
+) -> impl IntoResponse {
+    if !state.config.device_clients.contains(&params.client_id) {
+        return OAuthError::InvalidClient.into_response();
+    }
+    let scopes = match params.scope {
+        Some(scope) => tokens::parse_scopes(&scope),
+        None => tokens::SCOPES.iter().map(|s| s.to_string()).collect(),
+    };
+    if !scopes.iter().all(|s| tokens::SCOPES.contains(&s.as_str())) {
+        return OAuthError::InvalidScope.into_response();
+    }
+
+    let now = time::OffsetDateTime::now_utc();
+    let duration = state.config.device_code_duration;
+    let expires = match now.checked_add(duration) {
+        Some(expires) => expires,
+        None => {
+            error!("Could not add {:?} and {:?}", now, duration);
+            return OAuthError::ServerError.into_response();
+        }
+    };
+    let device_code = tokens::generate_secret();
+    let user_code = generate_user_code();
+    match state.device_codes.lock() {
+        Ok(mut device_codes) => {
+            device_codes.retain(|_, d| d.expires > now);
+            device_codes.insert(
+                device_code.clone(),
+                DeviceAuthorization {
+                    user_code: user_code.clone(),
+                    client_id: params.client_id,
+                    scopes,
+                    expires,
+                    interval: state.config.device_poll_interval,
+                    last_polled: None,
+                    status: DeviceStatus::Pending,
+                },
+            );
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return OAuthError::ServerError.into_response();
+        }
+    }
+
+    let verification_uri = format!("{}/device", state.config.base_url);
+    (
+        [(CACHE_CONTROL, "no-store")],
+        Json(DeviceCodeResponse {
+            device_code,
+            verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+            verification_uri,
+            user_code,
+            expires_in: duration.whole_seconds(),
+            interval: state.config.device_poll_interval.whole_seconds(),
+        }),
+    )
+        .into_response()
+}
+
+// device_code grant for the /oauth/token endpoint
+pub(crate) fn poll<S: Store>(
+    state: &AuthState<S>,
+    client: &ClientInfo,
+    params: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let (device_code, client_id) = match (&params.device_code, &params.client_id) {
+        (Some(device_code), Some(client_id)) => (device_code, client_id),
+        _ => return Err(OAuthError::InvalidRequest),
+    };
+
+    let now = time::OffsetDateTime::now_utc();
+    let authorization = match state.device_codes.lock() {
+        Ok(mut device_codes) => {
+            let authorization = match device_codes.get_mut(device_code) {
+                Some(authorization) if &authorization.client_id == client_id => authorization,
+                _ => {
+                    security::record_failure(state, client, None);
+                    return Err(OAuthError::InvalidGrant);
+                }
+            };
+            if authorization.expires <= now {
+                device_codes.remove(device_code);
+                return Err(OAuthError::ExpiredToken);
+            }
+            if let DeviceStatus::Pending = authorization.status {
+                let too_fast = authorization
+                    .last_polled
+                    .is_some_and(|last| now - last < authorization.interval);
+                authorization.last_polled = Some(now);
+                if too_fast {
+                    authorization.interval += time::Duration::seconds(5);
+                    return Err(OAuthError::SlowDown);
+                }
+                return Err(OAuthError::AuthorizationPending);
+            }
+            // approved or denied codes can only be redeemed once
+            device_codes.remove(device_code)
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(OAuthError::ServerError);
+        }
+    };
+
+    match authorization {
+        Some(DeviceAuthorization {
+            status: DeviceStatus::Approved(user),
+            client_id,
+            scopes,
+            ..
+        }) => {
+            info!(target: "audit", "user:{} issued device token for {}", user.id, client_id);
+            let (refresh_token, issued) =
+                issue_refresh_token(state, user.id, client_id.clone(), scopes.clone(), None)?;
+            let mut response = issue_access_token(
+                state,
+                Principal::User(user),
+                client_id,
+                scopes,
+                Some(issued.family),
+            )?;
+            response.refresh_token = Some(refresh_token);
+            Ok(response)
+        }
+        Some(_) => Err(OAuthError::AccessDenied),
+        None => Err(OAuthError::InvalidGrant),
+    }
+}
+
+fn generate_user_code() -> String {
+    let mut rng = rand::rng();
+    let code: String = (0..USER_CODE_LEN)
+        .map(|_| USER_CODE_CHARSET[rng.random_range(0..USER_CODE_CHARSET.len())] as char)
+        .collect();
+    format!(
+        "{}-{}",
+        &code[..USER_CODE_LEN / 2],
+        &code[USER_CODE_LEN / 2..]
+    )
+}
+
+// user codes are case insensitive and may be entered without the dash
+fn normalize_user_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() != USER_CODE_LEN {
+        return code;
+    }
+    format!(
+        "{}-{}",
+        &code[..USER_CODE_LEN / 2],
+        &code[USER_CODE_LEN / 2..]
+    )
+}
+
+fn session_user<S: Store>(state: &AuthState<S>, jar: &CookieJar) -> Option<User> {
+    match authorize_session(state, jar) {
+        Some(Principal::User(user)) => Some(user),
+        _ => None,
+    }
+}
+
+pub async fn page<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    jar: CookieJar,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if session_user(&state, &jar).is_none() {
+        return Redirect::temporary("/auth/google/login").into_response();
+    }
+    let user_code = params
+        .get("user_code")
+        .map(|c| normalize_user_code(c))
+        .unwrap_or_default();
+    render(&format!(
+        r#"<form method="post" action="/device">
+                <p>Enter the code shown on your device</p>
+                <input name="user_code" value="{}" autocomplete="off" />
+                <button name="action" value="approve">Approve</button>
+                <button name="action" value="deny">Deny</button>
+            </form>"#,
+        user_code
+    ))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyRequest {
+    user_code: String,
+    action: String,
+}
+
+pub async fn verify<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    jar: CookieJar,
+    client: ClientInfo,
+    Form(params): Form<VerifyRequest>,
+) -> impl IntoResponse {
+    let user = match session_user(&state, &jar) {
+        Some(user) => user,
+        None => return Redirect::to("/auth/google/login").into_response(),
+    };
+    // user codes are short, so guessing them is throttled like a password
+    if let Err(retry_after) = security::check_ip(&state, &client) {
+        return too_many_requests(retry_after);
+    }
+    let user = match state.store.get::<User>(user.id) {
+        Ok(Some(user)) if !user.is_locked() => user,
+        Err(e) => {
+            error!("{:?}", e);
+            return e.into_response();
+        }
+        _ => {
+            return render("<p>Your account is temporarily locked, try again later.</p>")
+                .into_response();
+        }
+    };
+    let user_code = normalize_user_code(&params.user_code);
+    let now = time::OffsetDateTime::now_utc();
+    let approve = params.action == "approve";
+
+    let client_id = match state.device_codes.lock() {
+        Ok(mut device_codes) => device_codes
+            .values_mut()
+            .find(|d| {
+                d.user_code == user_code
+                    && d.expires > now
+                    && matches!(d.status, DeviceStatus::Pending)
+            })
+            .map(|d| {
+                d.status = if approve {
+                    DeviceStatus::Approved(user.clone())
+                } else {
+                    DeviceStatus::Denied
+                };
+                d.client_id.clone()
+            }),
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    };
+
+    // a mistyped code says nothing about the signed in user, only the ip is
+    // counted so codes can't be guessed
+    match &client_id {
+        Some(_) => security::record_success(&state, &client, &user),
+        None => security::record_failure(&state, &client, None),
+    }
+    match client_id {
+        Some(client_id) if approve => {
+            info!(target: "audit", "user:{} approved device for {}", user.id, client_id);
+            render("<p>Device approved, you can return to your terminal.</p>").into_response()
+        }
+        Some(client_id) => {
+            info!(target: "audit", "user:{} denied device for {}", user.id, client_id);
+            render("<p>Device denied.</p>").into_response()
+        }
+        None => {
+            render(r#"<p>That code is invalid or has expired.</p><a href="/device">Try again</a>"#)
+                .into_response()
+        }
+    }
+}
+
+fn render(body: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+    <head>
+        <title>Glonk - Device Login</title>
+    </head>
+    <body style="background-color: #181818; color: #ffffff; font-family: sans-serif;">
+        <main>
+            {}
+        </main>
+    </body>
+</html>"#,
+        body
+    ))
+}
@@ -0,0 +1,109 @@
+use axum::{
+    Json,
+    http::{
+        HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, WWW_AUTHENTICATE},
+    },
+    response::IntoResponse,
+};
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+// OAuth2 error kinds (RFC 6749 section 5.2, RFC 8628 section 3.5)
+#[derive(Debug)]
+pub enum OAuthError {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    InvalidScope,
+    UnsupportedGrantType,
+    AuthorizationPending,
+    SlowDown,
+    AccessDenied,
+    ExpiredToken,
+    ServerError,
+}
+
+impl OAuthError {
+    fn code(&self) -> &'static str {
+        match *self {
+            OAuthError::InvalidRequest => "invalid_request",
+            OAuthError::InvalidClient => "invalid_client",
+            OAuthError::InvalidGrant => "invalid_grant",
+            OAuthError::InvalidScope => "invalid_scope",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::AuthorizationPending => "authorization_pending",
+            OAuthError::SlowDown => "slow_down",
+            OAuthError::AccessDenied => "access_denied",
+            OAuthError::ExpiredToken => "expired_token",
+            OAuthError::ServerError => "server_error",
+        }
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            OAuthError::InvalidClient => StatusCode::UNAUTHORIZED,
+            OAuthError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let body = Json(json!({ "error": self.code(), "error_description": self.to_string() }));
+        let mut response = (status, [(CACHE_CONTROL, "no-store")], body).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"authrs\""),
+            );
+        }
+        response
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            OAuthError::InvalidRequest => {
+                write!(fmt, "The request is missing a required parameter")
+            }
+            OAuthError::InvalidClient => {
+                write!(fmt, "Client authentication failed")
+            }
+            OAuthError::InvalidGrant => {
+                write!(fmt, "The provided grant is invalid or expired")
+            }
+            OAuthError::InvalidScope => {
+                write!(fmt, "The requested scope is invalid")
+            }
+            OAuthError::UnsupportedGrantType => {
+                write!(fmt, "The grant type is not supported")
+            }
+            OAuthError::AuthorizationPending => {
+                write!(fmt, "The user has not yet approved the device")
+            }
+            OAuthError::SlowDown => {
+                write!(fmt, "Polling too frequently, increase the interval")
+            }
+            OAuthError::AccessDenied => {
+                write!(fmt, "The user denied the request")
+            }
+            OAuthError::ExpiredToken => {
+                write!(fmt, "The device code has expired")
+            }
+            OAuthError::ServerError => {
+                write!(fmt, "The server could not complete the request")
+            }
+        }
+    }
+}
+
+impl Error for OAuthError {
+    fn description(&self) -> &str {
+        self.code()
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
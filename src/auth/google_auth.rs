@@ -10,7 +10,6 @@ use axum::{
     response::{self, AppendHeaders, IntoResponse},
     routing::get,
 };
-use axum_extra::extract::cookie::Cookie;
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet,
     PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
@@ -27,8 +26,17 @@ use std::env;
 
 use crate::{
     AuthState, Store,
+    audit::AuditRecord,
+    auth::{
+        client_info::ClientInfo,
+        refresh::{SESSION_CLIENT_ID, issue_refresh_token, refresh_cookie},
+        security,
+        sessions::{create_session, session_cookie},
+        tokens,
+    },
     error::AuthrError,
-    types::{QueryTypes, RequestUser, User, UserByGuid, UserQuery},
+    rate_limit::too_many_requests,
+    types::{DataType, QueryTypes, RequestUser, User, UserByGuid, UserQuery},
 };
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
@@ -63,6 +71,10 @@ impl GoogleAuthClient {
     pub fn from_env() -> Self {
         let client_id = env::var("GOOGLE_OAUTH_CLIENT_ID").expect("client id");
         let client_secret = env::var("GOOGLE_OAUTH_CLIENT_SECRET").expect("client secret");
+        Self::new(client_id, client_secret)
+    }
+
+    pub fn new(client_id: String, client_secret: String) -> Self {
         let auth_uri = AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
             .expect("auth_uri");
         let token_uri =
@@ -81,6 +93,23 @@ impl GoogleAuthClient {
     }
 }
 
+// how long a login attempt may take before its state is discarded
+const OAUTH_STATE_LIFETIME: time::Duration = time::Duration::minutes(10);
+
+// An outstanding login, keyed by its csrf state
+#[derive(Debug, Clone)]
+pub struct OAuthState {
+    pub verifier: String,
+    pub ip: Option<String>,
+    pub created: time::OffsetDateTime,
+}
+
+impl OAuthState {
+    pub fn is_expired(&self) -> bool {
+        self.created + OAUTH_STATE_LIFETIME <= time::OffsetDateTime::now_utc()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct GoogleUserInfo {
     id: String,
@@ -100,19 +129,23 @@ impl From<GoogleUserInfo> for RequestUser {
             email: Some(value.email),
             name: Some(value.name),
             picture: Some(value.picture),
+            ..Default::default()
         }
     }
 }
 
 // routes
-pub fn routes(state: Arc<AuthState>) -> Router {
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
     Router::new()
         .route("/login", get(login))
         .route("/callback", get(callback))
         .with_state(state)
 }
 
-pub async fn login(State(state): State<Arc<AuthState>>) -> impl IntoResponse {
+pub async fn login<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    client: ClientInfo,
+) -> impl IntoResponse {
     // Generate a PKCE challenge.
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -130,7 +163,28 @@ pub async fn login(State(state): State<Arc<AuthState>>) -> impl IntoResponse {
 
     match state.oauth_sessions.lock() {
         Ok(mut oauth_sessions) => {
-            oauth_sessions.insert(csrf_token.into_secret(), pkce_verifier.secret().clone());
+            oauth_sessions.retain(|_, s| !s.is_expired());
+            let outstanding: Vec<_> = oauth_sessions
+                .values()
+                .filter(|s| s.ip == client.ip)
+                .map(|s| s.created)
+                .collect();
+            if outstanding.len() >= state.config.rate_limits.max_oauth_states_per_ip {
+                debug!("too many outstanding logins from {:?}", client.ip);
+                // a slot frees up when the oldest attempt expires
+                let now = time::OffsetDateTime::now_utc();
+                let oldest = outstanding.into_iter().min().unwrap_or(now);
+                let retry_after = oldest + OAUTH_STATE_LIFETIME - now;
+                return too_many_requests(retry_after.unsigned_abs()).into_response();
+            }
+            oauth_sessions.insert(
+                csrf_token.into_secret(),
+                OAuthState {
+                    verifier: pkce_verifier.secret().clone(),
+                    ip: client.ip,
+                    created: time::OffsetDateTime::now_utc(),
+                },
+            );
         }
         Err(e) => {
             error!("{:?}", e);
@@ -141,15 +195,17 @@ pub async fn login(State(state): State<Arc<AuthState>>) -> impl IntoResponse {
     response::Redirect::temporary(auth_url.as_str()).into_response()
 }
 
-pub async fn callback(
+pub async fn callback<S: Store>(
     Query(params): Query<HashMap<String, String>>,
-    State(state): State<Arc<AuthState>>,
+    State(state): State<Arc<AuthState<S>>>,
+    client: ClientInfo,
 ) -> impl IntoResponse {
     let csrf_token_header = params.get("state");
     let token = match csrf_token_header {
         Some(token) => token,
         None => {
-            return AuthrError::NotAuthorized.into_response();
+            security::audit_failure(&state, &client, None);
+            return AuthrError::BadRequest("Missing state".to_string()).into_response();
         }
     };
 
@@ -157,7 +213,7 @@ pub async fn callback(
     let code = match code_header {
         Some(code) => code.to_string(),
         None => {
-            return AuthrError::NotAuthorized.into_response();
+            return AuthrError::BadRequest("Missing code".to_string()).into_response();
         }
     };
 
@@ -165,14 +221,16 @@ pub async fn callback(
         Ok(mut sessions) => sessions.remove(token.as_str()),
         Err(e) => {
             error!("{:?}", e);
-            return AuthrError::NotAuthorized.into_response();
+            return AuthrError::Internal.into_response();
         }
     };
 
     let pkce_verifier = match pkce_verifier {
-        Some(verifier) => verifier,
-        None => {
-            return AuthrError::NotAuthorized.into_response();
+        Some(oauth_state) if !oauth_state.is_expired() => oauth_state.verifier,
+        // unknown or expired
+        _ => {
+            security::audit_failure(&state, &client, None);
+            return AuthrError::Unauthenticated.into_response();
         }
     };
 
@@ -183,52 +241,68 @@ pub async fn callback(
         match get_google_user_info(pkce_verifier, code, state.google_client.client.clone()).await {
             Ok(u) => u,
             Err(_) => {
-                return AuthrError::NotAuthorized.into_response();
+                security::audit_failure(&state, &client, None);
+                return AuthrError::Unauthenticated.into_response();
             }
         };
 
     let retrieved = match retrieve_or_create_user(user_info, state.clone()).await {
         Some(r) => r,
         None => {
-            return AuthrError::NotAuthorized.into_response();
+            return AuthrError::Internal.into_response();
         }
     };
 
     debug!("{:?}", retrieved);
+    let actor = format!("user:{}", retrieved.id);
+    if retrieved.is_locked() {
+        info!(target: "audit", "user:{} refused login, account locked", retrieved.id);
+        state
+            .audit
+            .record(AuditRecord::new(&actor, "login_failed").target(DataType::USER, retrieved.id));
+        return AuthrError::NotAuthorized.into_response();
+    }
+    security::record_login(&state, &client, &retrieved);
 
-    // Generate a PKCE challenge for a new session_id & set cookie
-    let (_pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-    let cookie_exp_duration = time::Duration::minutes(10);
-    match state.sessions.lock() {
-        Ok(mut sessions) => {
-            let now = time::OffsetDateTime::now_utc();
-            let expires = now.checked_add(cookie_exp_duration);
-            match expires {
-                Some(expires) => {
-                    sessions.insert(pkce_verifier.secret().clone(), (retrieved, expires));
-                }
-                None => {
-                    error!("Could not add {:?} and {:?}", now, cookie_exp_duration);
-                    return AuthrError::NotAuthorized.into_response();
-                }
-            }
+    let (refresh_token, issued) = match issue_refresh_token(
+        &state,
+        retrieved.id,
+        SESSION_CLIENT_ID.to_string(),
+        tokens::SCOPES.iter().map(|s| s.to_string()).collect(),
+        None,
+    ) {
+        Ok(issued) => issued,
+        Err(_) => {
+            return AuthrError::Internal.into_response();
         }
-        Err(e) => {
-            error!("{:?}", e);
-            return AuthrError::NotAuthorized.into_response();
+    };
+    let session_id = match create_session(
+        &state,
+        retrieved.clone(),
+        client,
+        "google",
+        Some(issued.family),
+    ) {
+        Some(session_id) => session_id,
+        None => {
+            return AuthrError::Internal.into_response();
         }
     };
+    info!(target: "audit", "user:{} logged in with google", retrieved.id);
+    state
+        .audit
+        .record(AuditRecord::new(&actor, "login").target(DataType::USER, retrieved.id));
 
-    let pkce_str = pkce_verifier.into_secret();
-    let cookie = Cookie::build(("session_id", pkce_str.as_str()))
-        .path("/")
-        .max_age(cookie_exp_duration)
-        .http_only(true)
-        .build();
+    let cookie = session_cookie(&state, session_id, state.config.session_duration);
+    let refresh_cookie = refresh_cookie(&state, refresh_token, state.config.refresh_token_duration);
 
     (
         StatusCode::TEMPORARY_REDIRECT,
-        AppendHeaders([(SET_COOKIE, cookie.to_string().as_str()), (LOCATION, "/")]),
+        AppendHeaders([
+            (SET_COOKIE, cookie.to_string()),
+            (SET_COOKIE, refresh_cookie.to_string()),
+            (LOCATION, "/".to_string()),
+        ]),
     )
         .into_response()
 }
@@ -275,26 +349,35 @@ async fn get_google_user_info(
                 Ok(user_info) => Ok(user_info),
                 Err(e) => {
                     error!("{:?}", e);
-                    return Err(());
+                    Err(())
                 }
             }
         }
         Err(e) => {
             error!("{:?}", e);
-            return Err(());
+            Err(())
         }
     }
 }
 
-async fn retrieve_or_create_user(user_info: GoogleUserInfo, state: Arc<AuthState>) -> Option<User> {
+async fn retrieve_or_create_user<S: Store>(
+    user_info: GoogleUserInfo,
+    state: Arc<AuthState<S>>,
+) -> Option<User> {
     let user = RequestUser::from(user_info);
-    let mut retrieved: Vec<User> =
-        state
-            .store
-            .clone()
-            .get_queries::<User>(vec![QueryTypes::UserQuery(UserQuery::ByGuid(
-                UserByGuid::new(user.guid.clone().unwrap()),
-            ))]);
+    let retrieved = state
+        .store
+        .clone()
+        .get_queries::<User>(vec![QueryTypes::named(UserQuery::ByGuid(UserByGuid::new(
+            user.guid.clone().unwrap(),
+        )))]);
+    let mut retrieved: Vec<User> = match retrieved {
+        Ok(retrieved) => retrieved,
+        Err(e) => {
+            error!("Could not look up user: {:?}", e);
+            return None;
+        }
+    };
     match retrieved.len() {
         1 => retrieved.pop(),
         0 => {
@@ -302,6 +385,8 @@ async fn retrieve_or_create_user(user_info: GoogleUserInfo, state: Arc<AuthState
             match state.store.clone().create(user) {
                 Ok(user) => {
                     info!("Created {:?}", user);
+                    state.webhooks.emit(DataType::USER, "created", &user);
+                    state.feed.publish(DataType::USER, "created", &user);
                     Some(user)
                 }
                 Err(e) => {
@@ -0,0 +1,238 @@
+use std::{fmt, sync::Arc};
+
+use axum::{
+    Form, Json,
+    extract::State,
+    http::{HeaderMap, StatusCode, header::CACHE_CONTROL},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{
+    client_info::ClientInfo,
+    error::OAuthError,
+    oauth::{authenticate_client, basic_credentials},
+    refresh::{find_refresh_token, revoke_family},
+    security,
+    sessions::{get_session, remove_session},
+    tokens,
+};
+use crate::{AuthState, Store, rate_limit::too_many_requests};
+
+#[derive(Debug, Deserialize)]
+pub struct TokenLookupRequest {
+    token: String,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+// RFC 7662 section 2.2, only `active` is present for inactive tokens
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<String>,
+}
+
+// Who is allowed to revoke a token
+enum Revoker {
+    // service accounts may revoke any token or session
+    Confidential(String),
+    // public clients may only revoke tokens issued to them
+    Public(String),
+}
+
+impl fmt::Display for Revoker {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Revoker::Confidential(client_id) => write!(fmt, "service_account:{}", client_id),
+            Revoker::Public(client_id) => write!(fmt, "client:{}", client_id),
+        }
+    }
+}
+
+pub async fn introspect<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Form(params): Form<TokenLookupRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = security::check_ip(&state, &client) {
+        return too_many_requests(retry_after);
+    }
+    let account = match authenticate_client(
+        &state,
+        &headers,
+        &client,
+        &params.client_id,
+        &params.client_secret,
+    ) {
+        Ok(account) => account,
+        Err(e) => return e.into_response(),
+    };
+
+    let token = &params.token;
+    let response = match params.token_type_hint.as_deref() {
+        Some("session_id") => lookup_session(&state, token)
+            .or_else(|| lookup_access_token(&state, token))
+            .or_else(|| lookup_refresh_token(&state, token)),
+        Some("refresh_token") => lookup_refresh_token(&state, token)
+            .or_else(|| lookup_access_token(&state, token))
+            .or_else(|| lookup_session(&state, token)),
+        _ => lookup_access_token(&state, token)
+            .or_else(|| lookup_session(&state, token))
+            .or_else(|| lookup_refresh_token(&state, token)),
+    }
+    .unwrap_or_default();
+
+    info!(target: "audit", "service_account:{} introspected token active={}", account.client_id, response.active);
+    ([(CACHE_CONTROL, "no-store")], Json(response)).into_response()
+}
+
+fn lookup_access_token<S: Store>(
+    state: &AuthState<S>,
+    token: &str,
+) -> Option<IntrospectionResponse> {
+    match state.access_tokens.lock() {
+        Ok(access_tokens) => {
+            let access_token = access_tokens.get(token)?;
+            if access_token.is_expired() {
+                return None;
+            }
+            Some(IntrospectionResponse {
+                active: true,
+                sub: Some(access_token.principal.to_string()),
+                scope: Some(access_token.scopes.join(" ")),
+                exp: Some(access_token.expires.unix_timestamp()),
+                client_id: Some(access_token.client_id.clone()),
+                token_type: Some("Bearer".to_string()),
+            })
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+fn lookup_session<S: Store>(
+    state: &AuthState<S>,
+    session_id: &str,
+) -> Option<IntrospectionResponse> {
+    let session = get_session(state, session_id)?;
+    Some(IntrospectionResponse {
+        active: true,
+        sub: Some(format!("user:{}", session.user.id)),
+        scope: Some(tokens::SCOPES.join(" ")),
+        exp: Some(session.expires.unix_timestamp()),
+        client_id: None,
+        token_type: Some("session_id".to_string()),
+    })
+}
+
+fn lookup_refresh_token<S: Store>(
+    state: &AuthState<S>,
+    token: &str,
+) -> Option<IntrospectionResponse> {
+    let refresh_token = find_refresh_token(state, token)?;
+    if refresh_token.used || refresh_token.revoked || refresh_token.is_expired() {
+        return None;
+    }
+    Some(IntrospectionResponse {
+        active: true,
+        sub: Some(format!("user:{}", refresh_token.user_id)),
+        scope: Some(refresh_token.scopes),
+        exp: Some(refresh_token.expires),
+        client_id: Some(refresh_token.client_id),
+        token_type: Some("refresh_token".to_string()),
+    })
+}
+
+pub async fn revoke<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Form(params): Form<TokenLookupRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = security::check_ip(&state, &client) {
+        return too_many_requests(retry_after);
+    }
+    let confidential = basic_credentials(&headers).is_some() || params.client_secret.is_some();
+    let revoker = if confidential {
+        match authenticate_client(
+            &state,
+            &headers,
+            &client,
+            &params.client_id,
+            &params.client_secret,
+        ) {
+            Ok(account) => Revoker::Confidential(account.client_id),
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        match params.client_id {
+            Some(ref client_id) if state.config.device_clients.contains(client_id) => {
+                Revoker::Public(client_id.clone())
+            }
+            _ => return OAuthError::InvalidClient.into_response(),
+        }
+    };
+
+    let revoked = revoke_access_token(&state, &params.token, &revoker)
+        || revoke_refresh_token(&state, &params.token, &revoker)
+        || (matches!(revoker, Revoker::Confidential(_)) && revoke_session(&state, &params.token));
+    if revoked {
+        info!(target: "audit", "{} revoked a token", revoker);
+    }
+
+    // unknown tokens are not an error, see RFC 7009 section 2.2
+    StatusCode::OK.into_response()
+}
+
+fn revoke_access_token<S: Store>(state: &AuthState<S>, token: &str, revoker: &Revoker) -> bool {
+    match state.access_tokens.lock() {
+        Ok(mut access_tokens) => {
+            let allowed = match (access_tokens.get(token), revoker) {
+                (Some(_), Revoker::Confidential(_)) => true,
+                (Some(access_token), Revoker::Public(client_id)) => {
+                    &access_token.client_id == client_id
+                }
+                (None, _) => false,
+            };
+            allowed && access_tokens.remove(token).is_some()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            false
+        }
+    }
+}
+
+// revoking a refresh token revokes its family, see RFC 7009 section 2.1
+fn revoke_refresh_token<S: Store>(state: &AuthState<S>, token: &str, revoker: &Revoker) -> bool {
+    let refresh_token = match find_refresh_token(state, token) {
+        Some(refresh_token) => refresh_token,
+        None => return false,
+    };
+    if let Revoker::Public(client_id) = revoker
+        && &refresh_token.client_id != client_id
+    {
+        return false;
+    }
+    revoke_family(state, &refresh_token.family);
+    true
+}
+
+fn revoke_session<S: Store>(state: &AuthState<S>, session_id: &str) -> bool {
+    remove_session(state, session_id).is_some()
+}
@@ -1,55 +1,183 @@
-use crate::AuthState;
+use crate::{
+    AuthState, Store, admin,
+    audit::AuditRecord,
+    error::AuthrError,
+    types::{DataType, ServiceAccount, User},
+};
 use axum::{
     Router,
     extract::{Request, State},
-    http::StatusCode,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, header::AUTHORIZATION},
     middleware::Next,
     response::{IntoResponse, Response},
+    routing::post,
 };
 use axum_extra::extract::CookieJar;
-use std::{cmp::Ordering, sync::Arc};
-use tracing::{debug, error};
+use std::{fmt, sync::Arc};
+use tracing::{debug, error, info};
 
+pub mod client_info;
+pub mod csrf;
+pub mod device;
+pub mod error;
 pub mod google_auth;
+pub mod introspection;
+pub mod oauth;
+pub mod refresh;
+pub mod security;
+pub mod sessions;
+pub mod tokens;
+
+// marks responses served to an impersonation session
+pub const IMPERSONATED_BY: HeaderName = HeaderName::from_static("x-impersonated-by");
+
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/refresh", post(refresh::refresh_session))
+        .route("/logout", post(sessions::logout))
+        .route("/impersonation/stop", post(admin::impersonation::stop))
+        .with_state(state.clone())
+        .nest("/sessions", sessions::routes(state.clone()))
+        .nest_service("/google/", google_auth::routes(state))
+}
+
+// The identity a request was authenticated as
+#[derive(Debug, Clone)]
+pub enum Principal {
+    User(User),
+    ServiceAccount(ServiceAccount),
+    // an admin acting as `user`
+    Impersonated { user: User, admin: User },
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Principal::User(user) => write!(fmt, "user:{}", user.id),
+            Principal::ServiceAccount(account) => {
+                write!(fmt, "service_account:{}", account.client_id)
+            }
+            Principal::Impersonated { user, admin } => {
+                write!(fmt, "user:{} impersonated by user:{}", user.id, admin.id)
+            }
+        }
+    }
+}
+
+impl From<sessions::Session> for Principal {
+    fn from(session: sessions::Session) -> Self {
+        match session.impersonator {
+            Some(impersonator) => Principal::Impersonated {
+                user: session.user,
+                admin: impersonator.admin,
+            },
+            None => Principal::User(session.user),
+        }
+    }
+}
 
-pub fn routes(state: Arc<AuthState>) -> Router {
-    Router::new().nest_service("/google/", google_auth::routes(state))
+impl Principal {
+    // the user whose data the request acts on, if any
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            Principal::User(user) => Some(user),
+            Principal::Impersonated { user, .. } => Some(user),
+            Principal::ServiceAccount(_) => None,
+        }
+    }
 }
 
 // auth middleware
-pub async fn request_authorizer(
-    State(state): State<Arc<AuthState>>,
+pub async fn request_authorizer<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
     jar: CookieJar,
-    req: Request,
+    mut req: Request,
     next: Next,
 ) -> Response {
-    match jar.get("session_id") {
-        Some(cookie) => {
-            let session_id = cookie.value_trimmed();
-            match state.sessions.lock() {
-                Ok(sessions) => {
-                    if let Some((user, exp)) = sessions.get(session_id) {
-                        if exp.cmp(&time::OffsetDateTime::now_utc()) != Ordering::Greater {
-                            return (StatusCode::FORBIDDEN, "Not Authorized".to_string())
-                                .into_response();
-                        }
-                        debug!("cookie active: {:?}", user);
-                    } else {
-                        return (StatusCode::FORBIDDEN, "Not Authorized".to_string())
-                            .into_response();
-                    }
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    return (StatusCode::FORBIDDEN, "Not Authorized".to_string()).into_response();
-                }
-            }
+    let principal = match bearer_token(req.headers()) {
+        Some(token) => authorize_token(&state, token, req.method()),
+        None => authorize_session(&state, &jar).ok_or(AuthrError::Unauthenticated),
+    };
+    let principal = match principal {
+        Ok(principal) => principal,
+        Err(e) => {
+            return e.into_response();
         }
-        None => {
-            return (StatusCode::FORBIDDEN, "Not Authorized".to_string()).into_response();
+    };
+
+    info!(target: "audit", "{} {} {}", principal, req.method(), req.uri());
+    let impersonated_by = match &principal {
+        Principal::Impersonated { user, admin } => {
+            // the request stands in for a snapshot of the impersonated user
+            let request = serde_json::json!({
+                "method": req.method().as_str(),
+                "uri": req.uri().to_string(),
+            });
+            state.audit.record(
+                AuditRecord::new(&principal, "impersonated_request")
+                    .target(DataType::USER, user.id)
+                    .after(&request),
+            );
+            Some(format!("user:{}", admin.id))
         }
-    }
-    let response = next.run(req).await;
+        _ => None,
+    };
+    req.extensions_mut().insert(principal);
 
+    let mut response = next.run(req).await;
+    if let Some(admin) = impersonated_by.and_then(|a| HeaderValue::from_str(&a).ok()) {
+        response.headers_mut().insert(IMPERSONATED_BY, admin);
+    }
     response
 }
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim())
+}
+
+pub(crate) fn authorize_session<S: Store>(
+    state: &AuthState<S>,
+    jar: &CookieJar,
+) -> Option<Principal> {
+    let session_id = sessions::session_id(state, jar)?;
+    let session = sessions::touch_session(state, session_id)?;
+    debug!("cookie active: {:?}", session.user);
+    Some(Principal::from(session))
+}
+
+fn authorize_token<S: Store>(
+    state: &AuthState<S>,
+    token: &str,
+    method: &Method,
+) -> Result<Principal, AuthrError> {
+    let required = if method.is_safe() {
+        tokens::SCOPE_DATA_READ
+    } else {
+        tokens::SCOPE_DATA_WRITE
+    };
+    match state.access_tokens.lock() {
+        Ok(access_tokens) => {
+            let access_token = match access_tokens.get(token) {
+                Some(access_token) if !access_token.is_expired() => access_token,
+                _ => return Err(AuthrError::Unauthenticated),
+            };
+            // a valid token without the scope is refused, not unauthenticated
+            if !access_token.has_scope(required) {
+                debug!(
+                    "token for {} missing scope {}",
+                    access_token.principal, required
+                );
+                return Err(AuthrError::NotAuthorized);
+            }
+            Ok(access_token.principal.clone())
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            Err(AuthrError::Internal)
+        }
+    }
+}
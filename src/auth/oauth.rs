@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use axum::{
+    Form, Json, Router,
+    extract::State,
+    http::{HeaderMap, header::AUTHORIZATION, header::CACHE_CONTROL},
+    response::IntoResponse,
+    routing::post,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{
+    Principal,
+    client_info::ClientInfo,
+    device,
+    error::OAuthError,
+    introspection, refresh, security,
+    tokens::{self, AccessToken},
+};
+use crate::{
+    AuthState, Store,
+    rate_limit::too_many_requests,
+    types::{QueryTypes, ServiceAccount, ServiceAccountByClientId, ServiceAccountQuery},
+};
+
+// routes
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/token", post(token))
+        .route("/device/code", post(device::device_code))
+        .route("/introspect", post(introspection::introspect))
+        .route("/revoke", post(introspection::revoke))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub(crate) grant_type: String,
+    pub(crate) scope: Option<String>,
+    pub(crate) client_id: Option<String>,
+    pub(crate) client_secret: Option<String>,
+    pub(crate) device_code: Option<String>,
+    pub(crate) refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) refresh_token: Option<String>,
+}
+
+pub async fn token<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Form(params): Form<TokenRequest>,
+) -> impl IntoResponse {
+    if let Err(retry_after) = security::check_ip(&state, &client) {
+        return too_many_requests(retry_after);
+    }
+    let response = match params.grant_type.as_str() {
+        "client_credentials" => client_credentials(&state, &headers, &client, &params),
+        device::DEVICE_CODE_GRANT => device::poll(&state, &client, &params),
+        refresh::REFRESH_TOKEN_GRANT => refresh::refresh(&state, &client, &params),
+        _ => Err(OAuthError::UnsupportedGrantType),
+    };
+    match response {
+        Ok(response) => ([(CACHE_CONTROL, "no-store")], Json(response)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn client_credentials<S: Store>(
+    state: &AuthState<S>,
+    headers: &HeaderMap,
+    client: &ClientInfo,
+    params: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let account = authenticate_client(
+        state,
+        headers,
+        client,
+        &params.client_id,
+        &params.client_secret,
+    )?;
+
+    let allowed = account.scopes();
+    let scopes = match &params.scope {
+        Some(requested) => {
+            let requested = tokens::parse_scopes(requested);
+            if !requested.iter().all(|s| allowed.contains(s)) {
+                return Err(OAuthError::InvalidScope);
+            }
+            requested
+        }
+        None => allowed,
+    };
+
+    info!(target: "audit", "service_account:{} issued token", account.client_id);
+    let client_id = account.client_id.clone();
+    issue_access_token(
+        state,
+        Principal::ServiceAccount(account),
+        client_id,
+        scopes,
+        None,
+    )
+}
+
+// Authenticates a service account by HTTP Basic auth or client_id/client_secret form params,
+// failures count towards locking out the caller's ip
+pub(crate) fn authenticate_client<S: Store>(
+    state: &AuthState<S>,
+    headers: &HeaderMap,
+    client: &ClientInfo,
+    client_id: &Option<String>,
+    client_secret: &Option<String>,
+) -> Result<ServiceAccount, OAuthError> {
+    let (client_id, client_secret) = match basic_credentials(headers) {
+        Some(credentials) => credentials,
+        None => match (client_id, client_secret) {
+            (Some(id), Some(secret)) => (id.clone(), secret.clone()),
+            _ => return Err(OAuthError::InvalidClient),
+        },
+    };
+
+    let accounts = state
+        .store
+        .get_queries::<ServiceAccount>(vec![QueryTypes::named(ServiceAccountQuery::ByClientId(
+            ServiceAccountByClientId::new(client_id.clone()),
+        ))]);
+    let mut accounts = match accounts {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            error!("Could not look up service account: {:?}", e);
+            return Err(OAuthError::ServerError);
+        }
+    };
+    let account = match accounts.pop() {
+        Some(account) if accounts.is_empty() => account,
+        _ => {
+            info!(target: "audit", "service_account:{} unknown client", client_id);
+            security::record_failure(state, client, None);
+            return Err(OAuthError::InvalidClient);
+        }
+    };
+    if account.disabled || account.secret_hash != tokens::hash_secret(&client_secret) {
+        info!(target: "audit", "service_account:{} failed authentication", client_id);
+        security::record_failure(state, client, None);
+        return Err(OAuthError::InvalidClient);
+    }
+    Ok(account)
+}
+
+pub(crate) fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((id.to_string(), secret.to_string()))
+}
+
+pub(crate) fn issue_access_token<S: Store>(
+    state: &AuthState<S>,
+    principal: Principal,
+    client_id: String,
+    scopes: Vec<String>,
+    family: Option<String>,
+) -> Result<TokenResponse, OAuthError> {
+    let duration = state.config.access_token_duration;
+    let expires = match time::OffsetDateTime::now_utc().checked_add(duration) {
+        Some(expires) => expires,
+        None => {
+            error!("Could not add {:?} to now", duration);
+            return Err(OAuthError::ServerError);
+        }
+    };
+    let secret = tokens::generate_secret();
+    let scope = scopes.join(" ");
+    match state.access_tokens.lock() {
+        Ok(mut access_tokens) => {
+            access_tokens.insert(
+                secret.clone(),
+                AccessToken {
+                    principal,
+                    client_id,
+                    scopes,
+                    expires,
+                    family,
+                },
+            );
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(OAuthError::ServerError);
+        }
+    }
+
+    Ok(TokenResponse {
+        access_token: secret,
+        token_type: "Bearer",
+        expires_in: duration.whole_seconds(),
+        scope,
+        refresh_token: None,
+    })
+}
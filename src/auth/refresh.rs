@@ -0,0 +1,378 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{StatusCode, header::SET_COOKIE},
+    response::{AppendHeaders, IntoResponse},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use tracing::{error, info, warn};
+
+use super::{
+    client_info::ClientInfo,
+    error::OAuthError,
+    oauth::{TokenRequest, TokenResponse, issue_access_token},
+    security,
+    sessions::{create_session, session_cookie},
+    tokens,
+};
+use crate::{
+    AuthState, Store,
+    auth::Principal,
+    error::AuthrError,
+    rate_limit::too_many_requests,
+    types::{
+        QueryTypes, RefreshToken, RefreshTokenByFamily, RefreshTokenByTokenHash, RefreshTokenQuery,
+        RefreshTokenUnused, RequestRefreshToken, User,
+    },
+};
+
+pub(crate) const REFRESH_TOKEN_GRANT: &str = "refresh_token";
+pub(crate) const REFRESH_COOKIE: &str = "refresh_token";
+// client id recorded for refresh tokens issued to browser sessions
+pub(crate) const SESSION_CLIENT_ID: &str = "session";
+
+// Issues a new refresh token, continuing `family` when rotating
+pub(crate) fn issue_refresh_token<S: Store>(
+    state: &AuthState<S>,
+    user_id: i64,
+    client_id: String,
+    scopes: Vec<String>,
+    family: Option<RefreshToken>,
+) -> Result<(String, RefreshToken), OAuthError> {
+    let (family, expires) = match family {
+        // rotated tokens keep the family's expiry
+        Some(previous) => (previous.family, previous.expires),
+        None => {
+            let now = time::OffsetDateTime::now_utc();
+            let duration = state.config.refresh_token_duration;
+            match now.checked_add(duration) {
+                Some(expires) => (tokens::generate_secret(), expires.unix_timestamp()),
+                None => {
+                    error!("Could not add {:?} and {:?}", now, duration);
+                    return Err(OAuthError::ServerError);
+                }
+            }
+        }
+    };
+    let secret = tokens::generate_secret();
+    let request = RequestRefreshToken {
+        token_hash: Some(tokens::hash_secret(&secret)),
+        family: Some(family),
+        user_id: Some(user_id),
+        client_id: Some(client_id),
+        scopes: Some(scopes.join(" ")),
+        expires: Some(expires),
+        used: Some(false),
+        revoked: Some(false),
+        ..Default::default()
+    };
+    match state.store.create::<_, RefreshToken>(request) {
+        Ok(refresh_token) => Ok((secret, refresh_token)),
+        Err(e) => {
+            error!("Could not create refresh token: {:?}", e);
+            Err(OAuthError::ServerError)
+        }
+    }
+}
+
+pub(crate) fn find_refresh_token<S: Store>(
+    state: &AuthState<S>,
+    token: &str,
+) -> Option<RefreshToken> {
+    let found = state
+        .store
+        .get_queries::<RefreshToken>(vec![QueryTypes::named(RefreshTokenQuery::ByTokenHash(
+            RefreshTokenByTokenHash::new(tokens::hash_secret(token)),
+        ))]);
+    match found {
+        Ok(mut found) if found.len() == 1 => found.pop(),
+        Ok(_) => None,
+        Err(e) => {
+            error!("Could not look up refresh token: {:?}", e);
+            None
+        }
+    }
+}
+
+// Exchanges a refresh token for its successor. Presenting a token that was
+// already rotated means it leaked, so the whole family is revoked.
+pub(crate) fn rotate_refresh_token<S: Store>(
+    state: &AuthState<S>,
+    token: &str,
+    client_id: &str,
+    client: &ClientInfo,
+) -> Result<(String, RefreshToken, User), OAuthError> {
+    let current = match find_refresh_token(state, token) {
+        Some(current) => current,
+        None => {
+            security::record_failure(state, client, None);
+            return Err(OAuthError::InvalidGrant);
+        }
+    };
+    let user = match state.store.get::<User>(current.user_id) {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            revoke_family(state, &current.family);
+            return Err(OAuthError::InvalidGrant);
+        }
+        Err(e) => {
+            error!("Could not read user {}: {:?}", current.user_id, e);
+            return Err(OAuthError::ServerError);
+        }
+    };
+    if user.is_locked() {
+        info!(target: "audit", "user:{} refused refresh, account locked", user.id);
+        return Err(OAuthError::InvalidGrant);
+    }
+    if current.client_id != client_id || current.revoked || current.is_expired() {
+        security::record_failure(state, client, Some(&user));
+        return Err(OAuthError::InvalidGrant);
+    }
+    // marked used only while it's still unused, so of two requests racing with
+    // the same token one rotates & the other counts as reuse
+    let request = RequestRefreshToken {
+        id: Some(current.id),
+        used: Some(true),
+        ..Default::default()
+    };
+    let unused = QueryTypes::named(RefreshTokenQuery::Unused(RefreshTokenUnused::new()));
+    let rotated = match current.used {
+        true => None,
+        false => match state
+            .store
+            .update_if::<_, RefreshToken>(request, vec![unused])
+        {
+            Ok(rotated) => rotated,
+            Err(e) => {
+                error!("Could not rotate refresh token: {:?}", e);
+                return Err(OAuthError::ServerError);
+            }
+        },
+    };
+    if rotated.is_none() {
+        warn!(target: "audit", "user:{} reused refresh token, revoking family", current.user_id);
+        // revoked before the family is read, the request that rotated it checks
+        // for this once its successor exists, see below
+        revoke_token(state, current.id);
+        revoke_family(state, &current.family);
+        security::record_failure(state, client, Some(&user));
+        return Err(OAuthError::InvalidGrant);
+    }
+    let current_id = current.id;
+    let (secret, refresh_token) = issue_refresh_token(
+        state,
+        current.user_id,
+        current.client_id.clone(),
+        tokens::parse_scopes(&current.scopes),
+        Some(current),
+    )?;
+    // reused while the successor was being issued, which the revocation may
+    // have missed
+    match state.store.get::<RefreshToken>(current_id) {
+        Ok(Some(current)) if !current.revoked => {}
+        Ok(_) => {
+            revoke_family(state, &refresh_token.family);
+            return Err(OAuthError::InvalidGrant);
+        }
+        Err(e) => {
+            error!("Could not read refresh token {}: {:?}", current_id, e);
+            revoke_family(state, &refresh_token.family);
+            return Err(OAuthError::ServerError);
+        }
+    }
+    security::record_success(state, client, &user);
+    Ok((secret, refresh_token, user))
+}
+
+fn revoke_token<S: Store>(state: &AuthState<S>, id: i64) {
+    let request = RequestRefreshToken {
+        id: Some(id),
+        revoked: Some(true),
+        ..Default::default()
+    };
+    if let Err(e) = state.store.update::<_, RefreshToken>(request) {
+        error!("Could not revoke refresh token {}: {:?}", id, e);
+    }
+}
+
+// Revokes every refresh token in `family`, the access tokens they issued & the
+// sessions renewed from them, so a stolen token can't keep one alive
+pub(crate) fn revoke_family<S: Store>(state: &AuthState<S>, family: &str) {
+    let members = state
+        .store
+        .get_queries::<RefreshToken>(vec![QueryTypes::named(RefreshTokenQuery::ByFamily(
+            RefreshTokenByFamily::new(family.to_string()),
+        ))])
+        .unwrap_or_else(|e| {
+            error!("Could not read refresh token family {}: {:?}", family, e);
+            vec![]
+        });
+    for member in members.into_iter().filter(|m| !m.revoked) {
+        revoke_token(state, member.id);
+    }
+    match state.access_tokens.lock() {
+        Ok(mut access_tokens) => {
+            access_tokens.retain(|_, t| t.family.as_deref() != Some(family));
+        }
+        Err(e) => {
+            error!("{:?}", e);
+        }
+    }
+    match state.sessions.lock() {
+        Ok(mut sessions) => {
+            sessions.retain(|_, s| s.refresh_family.as_deref() != Some(family));
+        }
+        Err(e) => {
+            error!("{:?}", e);
+        }
+    }
+}
+
+// refresh_token grant for the /oauth/token endpoint
+pub(crate) fn refresh<S: Store>(
+    state: &AuthState<S>,
+    client: &ClientInfo,
+    params: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let (token, client_id) = match (&params.refresh_token, &params.client_id) {
+        (Some(token), Some(client_id)) => (token, client_id),
+        _ => return Err(OAuthError::InvalidRequest),
+    };
+    let (secret, refresh_token, user) = rotate_refresh_token(state, token, client_id, client)?;
+    info!(target: "audit", "user:{} refreshed token for {}", user.id, client_id);
+
+    let mut response = issue_access_token(
+        state,
+        Principal::User(user),
+        refresh_token.client_id.clone(),
+        tokens::parse_scopes(&refresh_token.scopes),
+        Some(refresh_token.family),
+    )?;
+    response.refresh_token = Some(secret);
+    Ok(response)
+}
+
+// only sent to the refresh endpoint
+const REFRESH_COOKIE_PATH: &str = "/auth/refresh";
+
+pub(crate) fn refresh_cookie<S: Store>(
+    state: &AuthState<S>,
+    refresh_token: String,
+    max_age: time::Duration,
+) -> Cookie<'static> {
+    state
+        .config
+        .cookies
+        .build(REFRESH_COOKIE, refresh_token, REFRESH_COOKIE_PATH, max_age)
+}
+
+pub(crate) fn refresh_cookie_name<S: Store>(state: &AuthState<S>) -> String {
+    state
+        .config
+        .cookies
+        .name(REFRESH_COOKIE, REFRESH_COOKIE_PATH)
+}
+
+// Trades the refresh cookie for a new browser session
+pub async fn refresh_session<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    jar: CookieJar,
+    client: ClientInfo,
+) -> impl IntoResponse {
+    if let Err(retry_after) = security::check_ip(&state, &client) {
+        return too_many_requests(retry_after);
+    }
+    let token = match jar.get(&refresh_cookie_name(&state)) {
+        Some(cookie) => cookie.value_trimmed().to_string(),
+        None => return AuthrError::Unauthenticated.into_response(),
+    };
+    let (secret, refresh_token, user) =
+        match rotate_refresh_token(&state, &token, SESSION_CLIENT_ID, &client) {
+            Ok(rotated) => rotated,
+            Err(OAuthError::ServerError) => return AuthrError::Internal.into_response(),
+            Err(_) => return AuthrError::Unauthenticated.into_response(),
+        };
+    info!(target: "audit", "user:{} refreshed session", user.id);
+    let session_id = match create_session(
+        &state,
+        user,
+        client,
+        "refresh",
+        Some(refresh_token.family.clone()),
+    ) {
+        Some(session_id) => session_id,
+        None => return AuthrError::Internal.into_response(),
+    };
+
+    let max_age = time::Duration::seconds(
+        refresh_token.expires - time::OffsetDateTime::now_utc().unix_timestamp(),
+    );
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([
+            (
+                SET_COOKIE,
+                session_cookie(&state, session_id, state.config.session_duration).to_string(),
+            ),
+            (
+                SET_COOKIE,
+                refresh_cookie(&state, secret, max_age).to_string(),
+            ),
+        ]),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AuthrState, MemStore, auth::google_auth::GoogleAuthClient, config::AuthConfig,
+        types::RequestUser,
+    };
+
+    #[tokio::test]
+    async fn reuse_ends_sessions_renewed_from_the_family() {
+        let state = AuthrState::new(
+            GoogleAuthClient::new("id".to_string(), "secret".to_string()),
+            MemStore::new().unwrap(),
+            AuthConfig::default(),
+        );
+        let state = &state.auth;
+        let user = state
+            .store
+            .create::<_, User>(RequestUser {
+                guid: Some("google/1".to_string()),
+                name: Some("a".to_string()),
+                email: Some("a@b.c".to_string()),
+                picture: Some(String::new()),
+                ..Default::default()
+            })
+            .unwrap();
+        let client = ClientInfo::default();
+        let (stolen, first) =
+            issue_refresh_token(state, user.id, SESSION_CLIENT_ID.to_string(), vec![], None)
+                .unwrap();
+        let (_, rotated, user) =
+            rotate_refresh_token(state, &stolen, SESSION_CLIENT_ID, &client).unwrap();
+        let session_id = create_session(
+            state,
+            user,
+            client.clone(),
+            "google",
+            Some(rotated.family.clone()),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            rotate_refresh_token(state, &stolen, SESSION_CLIENT_ID, &client),
+            Err(OAuthError::InvalidGrant)
+        ));
+        assert!(!state.sessions.lock().unwrap().contains_key(&session_id));
+        for id in [first.id, rotated.id] {
+            let token = state.store.get::<RefreshToken>(id).unwrap().unwrap();
+            assert!(token.revoked);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use super::{client_info::ClientInfo, tokens};
+use crate::{
+    AuthState, Store,
+    audit::AuditRecord,
+    types::{DataType, QueryTypes, RequestUser, User, UserFailures, UserQuery},
+};
+
+// rounds of concurrent failures a user's count is retried through
+const MAX_FAILURE_RETRIES: usize = 16;
+
+// Something an operator may want to tell the user about
+#[derive(Debug, Clone)]
+pub enum SecurityEvent {
+    AccountLocked {
+        user: User,
+        until: time::OffsetDateTime,
+    },
+    NewDevice {
+        user: User,
+        client: ClientInfo,
+    },
+}
+
+// Notification hook for security events, e.g. to email the user
+pub trait SecurityHook: Send + Sync {
+    fn notify(&self, event: &SecurityEvent);
+}
+
+// Failed attempts from a single ip, kept in memory only
+#[derive(Debug, Clone)]
+pub struct IpFailures {
+    failures: i64,
+    lockouts: i64,
+    locked_until: time::OffsetDateTime,
+    last_failure: time::OffsetDateTime,
+}
+
+fn notify<S: Store>(state: &AuthState<S>, event: SecurityEvent) {
+    match &event {
+        SecurityEvent::AccountLocked { user, until } => {
+            warn!(target: "audit", "user:{} locked until {}", user.id, until.unix_timestamp());
+        }
+        SecurityEvent::NewDevice { user, client } => {
+            warn!(target: "audit", "user:{} logged in from new device {:?} {:?}", user.id, client.ip, client.user_agent);
+        }
+    }
+    if let Some(hook) = &state.security_hook {
+        hook.notify(&event);
+    }
+}
+
+// Err with the time left when `client`'s ip is locked out
+pub(crate) fn check_ip<S: Store>(
+    state: &AuthState<S>,
+    client: &ClientInfo,
+) -> Result<(), Duration> {
+    let ip = match &client.ip {
+        Some(ip) => ip,
+        None => return Ok(()),
+    };
+    let now = time::OffsetDateTime::now_utc();
+    match state.failed_ips.lock() {
+        Ok(failed_ips) => match failed_ips.get(ip) {
+            Some(failures) if failures.locked_until > now => {
+                Err((failures.locked_until - now).unsigned_abs())
+            }
+            _ => Ok(()),
+        },
+        Err(e) => {
+            error!("{:?}", e);
+            Ok(())
+        }
+    }
+}
+
+// Counts a failed attempt against `client`'s ip & `user` when the attempt is attributable to one
+pub(crate) fn record_failure<S: Store>(
+    state: &AuthState<S>,
+    client: &ClientInfo,
+    user: Option<&User>,
+) {
+    audit_failure(state, client, user);
+    if let Some(ip) = &client.ip {
+        record_ip_failure(state, ip);
+    }
+    if let Some(user) = user {
+        record_user_failure(state, user);
+    }
+}
+
+// Appends a failed login to the audit log without counting it
+pub(crate) fn audit_failure<S: Store>(
+    state: &AuthState<S>,
+    client: &ClientInfo,
+    user: Option<&User>,
+) {
+    let actor = format!("ip:{}", client.ip.as_deref().unwrap_or("unknown"));
+    let mut record = AuditRecord::new(actor, "login_failed");
+    if let Some(user) = user {
+        record = record.target(DataType::USER, user.id);
+    }
+    state.audit.record(record);
+}
+
+fn record_ip_failure<S: Store>(state: &AuthState<S>, ip: &str) {
+    let config = &state.config.lockout;
+    let now = time::OffsetDateTime::now_utc();
+    match state.failed_ips.lock() {
+        Ok(mut failed_ips) => {
+            // once the longest lock has passed an ip starts over
+            failed_ips.retain(|_, f| f.last_failure + config.max_duration > now);
+            let failures = failed_ips.entry(ip.to_string()).or_insert(IpFailures {
+                failures: 0,
+                lockouts: 0,
+                locked_until: now,
+                last_failure: now,
+            });
+            failures.failures += 1;
+            failures.last_failure = now;
+            if failures.failures >= config.max_failures {
+                failures.failures = 0;
+                failures.lockouts += 1;
+                failures.locked_until = now + config.lock_duration(failures.lockouts);
+                warn!(target: "audit", "ip {} locked until {}", ip, failures.locked_until.unix_timestamp());
+            }
+        }
+        Err(e) => {
+            error!("{:?}", e);
+        }
+    }
+}
+
+fn record_user_failure<S: Store>(state: &AuthState<S>, user: &User) {
+    let config = &state.config.lockout;
+    info!(target: "audit", "user:{} failed authentication", user.id);
+    // written only while the counts are still the ones read, re-read & tried
+    // again when a concurrent failure got there first. Each round one of them
+    // succeeds
+    let mut user = user.clone();
+    for _ in 0..MAX_FAILURE_RETRIES {
+        let mut request = RequestUser {
+            id: Some(user.id),
+            failed_logins: Some(user.failed_logins + 1),
+            ..Default::default()
+        };
+        let mut locked_until = None;
+        if user.failed_logins + 1 >= config.max_failures {
+            let until = time::OffsetDateTime::now_utc() + config.lock_duration(user.lockouts + 1);
+            request.failed_logins = Some(0);
+            request.lockouts = Some(user.lockouts + 1);
+            request.locked_until = Some(until.unix_timestamp());
+            locked_until = Some(until);
+        }
+        let counts = QueryTypes::named(UserQuery::Failures(UserFailures::new(
+            user.failed_logins,
+            user.lockouts,
+        )));
+        match state.store.update_if::<_, User>(request, vec![counts]) {
+            Ok(Some(user)) => {
+                if let Some(until) = locked_until {
+                    notify(state, SecurityEvent::AccountLocked { user, until });
+                }
+                return;
+            }
+            Ok(None) => match state.store.get::<User>(user.id) {
+                Ok(Some(current)) => user = current,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Could not read user:{}: {:?}", user.id, e);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Could not record failure for user:{}: {:?}", user.id, e);
+                return;
+            }
+        }
+    }
+    error!(
+        "Could not record failure for user:{}, too many concurrent failures",
+        user.id
+    );
+}
+
+// Clears the failure counts after a successful authentication
+pub(crate) fn record_success<S: Store>(state: &AuthState<S>, client: &ClientInfo, user: &User) {
+    if let Some(ip) = &client.ip {
+        match state.failed_ips.lock() {
+            Ok(mut failed_ips) => {
+                failed_ips.remove(ip);
+            }
+            Err(e) => {
+                error!("{:?}", e);
+            }
+        }
+    }
+    if user.failed_logins == 0 && user.lockouts == 0 {
+        return;
+    }
+    let request = RequestUser {
+        id: Some(user.id),
+        failed_logins: Some(0),
+        lockouts: Some(0),
+        ..Default::default()
+    };
+    if let Err(e) = state.store.update::<_, User>(request) {
+        error!("Could not reset failures for user:{}: {:?}", user.id, e);
+    }
+}
+
+// Records an interactive login, flagging devices the user hasn't logged in from before
+pub(crate) fn record_login<S: Store>(state: &AuthState<S>, client: &ClientInfo, user: &User) {
+    record_success(state, client, user);
+
+    let fingerprint = tokens::hash_secret(&format!(
+        "{}\n{}",
+        client.ip.as_deref().unwrap_or_default(),
+        client.user_agent.as_deref().unwrap_or_default()
+    ));
+    let mut known: Vec<&str> = user.known_devices.split_whitespace().collect();
+    let is_new = !known.contains(&fingerprint.as_str());
+    // most recent first, the oldest falls off the end
+    known.retain(|d| *d != fingerprint);
+    known.insert(0, &fingerprint);
+    known.truncate(state.config.lockout.known_devices);
+
+    let request = RequestUser {
+        id: Some(user.id),
+        known_devices: Some(known.join(" ")),
+        ..Default::default()
+    };
+    if let Err(e) = state.store.update::<_, User>(request) {
+        error!("Could not record device for user:{}: {:?}", user.id, e);
+    }
+    // a user's first login isn't news to them
+    if is_new && !user.known_devices.is_empty() {
+        notify(
+            state,
+            SecurityEvent::NewDevice {
+                user: user.clone(),
+                client: client.clone(),
+            },
+        );
+    }
+}
+
+// Admin override, clears the lock & the backoff
+pub(crate) fn unlock<S: Store>(state: &AuthState<S>, user_id: i64) -> Option<User> {
+    let request = RequestUser {
+        id: Some(user_id),
+        failed_logins: Some(0),
+        lockouts: Some(0),
+        locked_until: Some(0),
+        ..Default::default()
+    };
+    match state.store.update::<_, User>(request) {
+        Ok(user) => Some(user),
+        Err(e) => {
+            error!("Could not unlock user:{}: {:?}", user_id, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, header::SET_COOKIE},
+    middleware,
+    response::{AppendHeaders, IntoResponse},
+    routing::{delete, get},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use oauth2::PkceCodeChallenge;
+use serde::Serialize;
+use tracing::{error, info};
+
+use super::{
+    Principal,
+    client_info::ClientInfo,
+    refresh::{refresh_cookie, revoke_family},
+    request_authorizer, tokens,
+};
+use crate::{
+    AuthState, Store,
+    audit::AuditRecord,
+    error::AuthrError,
+    types::{DataType, User},
+};
+
+pub(crate) const SESSION_COOKIE: &str = "session_id";
+
+// Browser session, keyed by the session_id cookie
+#[derive(Debug, Clone)]
+pub struct Session {
+    // opaque id safe to show to the user, unlike the cookie value
+    pub handle: String,
+    pub user: User,
+    pub created: time::OffsetDateTime,
+    pub last_seen: time::OffsetDateTime,
+    pub expires: time::OffsetDateTime,
+    pub client: ClientInfo,
+    pub provider: String,
+    pub impersonator: Option<Impersonator>,
+    // refresh token family the session can be renewed with, revoked with the session
+    pub refresh_family: Option<String>,
+}
+
+// The admin behind an impersonation session & the session to return them to
+#[derive(Debug, Clone)]
+pub struct Impersonator {
+    pub admin: User,
+    pub admin_session: String,
+}
+
+impl Session {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= time::OffsetDateTime::now_utc()
+    }
+}
+
+// What the session management api shows for a session
+#[derive(Debug, Serialize)]
+pub struct SessionView {
+    id: String,
+    user_id: i64,
+    created: i64,
+    last_seen: i64,
+    expires: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    provider: String,
+    current: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impersonated_by: Option<i64>,
+}
+
+impl SessionView {
+    pub(crate) fn new(session: Session, current: bool) -> Self {
+        Self {
+            id: session.handle,
+            user_id: session.user.id,
+            created: session.created.unix_timestamp(),
+            last_seen: session.last_seen.unix_timestamp(),
+            expires: session.expires.unix_timestamp(),
+            ip: session.client.ip,
+            user_agent: session.client.user_agent,
+            provider: session.provider,
+            current,
+            impersonated_by: session.impersonator.map(|i| i.admin.id),
+        }
+    }
+}
+
+// routes for the caller's own sessions
+pub fn routes<S: Store>(state: Arc<AuthState<S>>) -> Router {
+    Router::new()
+        .route("/", get(list))
+        .route("/{id}", delete(revoke))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            request_authorizer,
+        ))
+        .with_state(state)
+}
+
+// Starts a new browser session for `user`, returning the session id
+pub(crate) fn create_session<S: Store>(
+    state: &AuthState<S>,
+    user: User,
+    client: ClientInfo,
+    provider: &str,
+    refresh_family: Option<String>,
+) -> Option<String> {
+    start_session(
+        state,
+        user,
+        state.config.session_duration,
+        client,
+        provider,
+        None,
+        refresh_family,
+    )
+}
+
+pub(crate) fn start_session<S: Store>(
+    state: &AuthState<S>,
+    user: User,
+    duration: time::Duration,
+    client: ClientInfo,
+    provider: &str,
+    impersonator: Option<Impersonator>,
+    refresh_family: Option<String>,
+) -> Option<String> {
+    // Generate a PKCE challenge for a new session_id
+    let (_pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let now = time::OffsetDateTime::now_utc();
+    let expires = match now.checked_add(duration) {
+        Some(expires) => expires,
+        None => {
+            error!("Could not add {:?} and {:?}", now, duration);
+            return None;
+        }
+    };
+    match state.sessions.lock() {
+        Ok(mut sessions) => {
+            sessions.retain(|_, s| !s.is_expired());
+            sessions.insert(
+                pkce_verifier.secret().clone(),
+                Session {
+                    handle: tokens::generate_secret(),
+                    user,
+                    created: now,
+                    last_seen: now,
+                    expires,
+                    client,
+                    provider: provider.to_string(),
+                    impersonator,
+                    refresh_family,
+                },
+            );
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return None;
+        }
+    };
+    Some(pkce_verifier.into_secret())
+}
+
+pub(crate) fn get_session<S: Store>(state: &AuthState<S>, session_id: &str) -> Option<Session> {
+    match state.sessions.lock() {
+        Ok(sessions) => sessions
+            .get(session_id)
+            .filter(|s| !s.is_expired())
+            .cloned(),
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+// Looks up a live session & records that it was just used
+pub(crate) fn touch_session<S: Store>(state: &AuthState<S>, session_id: &str) -> Option<Session> {
+    match state.sessions.lock() {
+        Ok(mut sessions) => {
+            let session = sessions.get_mut(session_id).filter(|s| !s.is_expired())?;
+            session.last_seen = time::OffsetDateTime::now_utc();
+            Some(session.clone())
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+pub(crate) fn remove_session<S: Store>(state: &AuthState<S>, session_id: &str) -> Option<Session> {
+    match state.sessions.lock() {
+        Ok(mut sessions) => sessions.remove(session_id),
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+// Live sessions of `user_id`, flagging the one identified by `current`
+pub(crate) fn list_sessions<S: Store>(
+    state: &AuthState<S>,
+    user_id: i64,
+    current: Option<&str>,
+) -> Vec<SessionView> {
+    match state.sessions.lock() {
+        Ok(sessions) => sessions
+            .iter()
+            .filter(|(_, s)| s.user.id == user_id && !s.is_expired())
+            .map(|(id, s)| SessionView::new(s.clone(), Some(id.as_str()) == current))
+            .collect(),
+        Err(e) => {
+            error!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+pub(crate) fn remove_session_by_handle<S: Store>(
+    state: &AuthState<S>,
+    user_id: i64,
+    handle: &str,
+) -> Option<Session> {
+    match state.sessions.lock() {
+        Ok(mut sessions) => {
+            let session_id = sessions
+                .iter()
+                .find(|(_, s)| s.user.id == user_id && s.handle == handle)
+                .map(|(id, _)| id.clone())?;
+            sessions.remove(&session_id)
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+// Revokes the refresh tokens behind a removed session, along with any other
+// session renewed from them, so the browser can't quietly log back in
+pub(crate) fn end_session_family<S: Store>(state: &AuthState<S>, session: &Session) {
+    if let Some(family) = &session.refresh_family {
+        revoke_family(state, family);
+    }
+}
+
+// Records the revocation of `session` by `principal`
+pub(crate) fn audit_revocation<S: Store>(
+    state: &AuthState<S>,
+    principal: &Principal,
+    session: Session,
+) {
+    info!(target: "audit", "{} revoked session {} of user:{}", principal, session.handle, session.user.id);
+    let user_id = session.user.id;
+    state.audit.record(
+        AuditRecord::new(principal, "session_revoked")
+            .target(DataType::USER, user_id)
+            .before(&SessionView::new(session, false)),
+    );
+}
+
+pub(crate) fn session_cookie<S: Store>(
+    state: &AuthState<S>,
+    session_id: String,
+    max_age: time::Duration,
+) -> Cookie<'static> {
+    state
+        .config
+        .cookies
+        .build(SESSION_COOKIE, session_id, "/", max_age)
+}
+
+// The session id sent by the browser, if any
+pub(crate) fn session_id<'a, S: Store>(
+    state: &AuthState<S>,
+    jar: &'a CookieJar,
+) -> Option<&'a str> {
+    let name = state.config.cookies.name(SESSION_COOKIE, "/");
+    jar.get(&name).map(|c| c.value_trimmed())
+}
+
+pub async fn list<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let user = match principal.user() {
+        Some(user) => user,
+        None => return AuthrError::NotAuthorized.into_response(),
+    };
+    let current = session_id(&state, &jar);
+    Json(list_sessions(&state, user.id, current)).into_response()
+}
+
+pub async fn revoke<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    Extension(principal): Extension<Principal>,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    let user = match principal.user() {
+        Some(user) => user,
+        None => return AuthrError::NotAuthorized.into_response(),
+    };
+    match remove_session_by_handle(&state, user.id, &handle) {
+        Some(session) => {
+            end_session_family(&state, &session);
+            audit_revocation(&state, &principal, session);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => AuthrError::NotFound.into_response(),
+    }
+}
+
+// Ends the browser's session & its refresh token, clearing both cookies
+pub async fn logout<S: Store>(
+    State(state): State<Arc<AuthState<S>>>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    let session = session_id(&state, &jar).and_then(|id| remove_session(&state, id));
+    if let Some(session) = session {
+        end_session_family(&state, &session);
+        let user_id = session.user.id;
+        let principal = Principal::from(session);
+        info!(target: "audit", "{} logged out", principal);
+        state
+            .audit
+            .record(AuditRecord::new(&principal, "logout").target(DataType::USER, user_id));
+    }
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([
+            (
+                SET_COOKIE,
+                session_cookie(&state, String::new(), time::Duration::ZERO).to_string(),
+            ),
+            (
+                SET_COOKIE,
+                refresh_cookie(&state, String::new(), time::Duration::ZERO).to_string(),
+            ),
+        ]),
+    )
+        .into_response()
+}
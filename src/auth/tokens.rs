@@ -0,0 +1,43 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use oauth2::CsrfToken;
+use sha2::{Digest, Sha256};
+
+use super::Principal;
+
+pub const SCOPE_DATA_READ: &str = "data:read";
+pub const SCOPE_DATA_WRITE: &str = "data:write";
+pub const SCOPES: [&str; 2] = [SCOPE_DATA_READ, SCOPE_DATA_WRITE];
+
+// Bearer token issued by the /oauth/token endpoint
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub principal: Principal,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    pub expires: time::OffsetDateTime,
+    // refresh token family this token was issued from
+    pub family: Option<String>,
+}
+
+impl AccessToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= time::OffsetDateTime::now_utc()
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+pub fn generate_secret() -> String {
+    CsrfToken::new_random_len(32).into_secret()
+}
+
+// secrets are only ever persisted as their sha256 digest
+pub fn hash_secret(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+pub fn parse_scopes(scopes: &str) -> Vec<String> {
+    scopes.split_whitespace().map(String::from).collect()
+}
@@ -1,18 +1,90 @@
+use std::env;
+
+use authrs::{
+    SqliteStore,
+    config::SqliteConfig,
+    store::migrations::{self, Migrate, MigrationError},
+};
+
+const USAGE: &str = "usage: bootstrap [migrate | status | rollback [steps]] [--postgres]";
+
+// Manages the schema of the sqlite database configured by the AUTHRS_SQLITE_*
+// variables, or with --postgres the one configured by DB_HOST, DB_USER & DB_PASS.
+// Runs the pending migrations when no command is given.
 fn main() {
-    let connection = sqlite::open("test.db").unwrap();
-    let query = "
-        CREATE TABLE users (
-            id integer primary key autoincrement,
-            guid text not null,
-            name text,
-            email text,
-            picture text);
-
-        CREATE TABLE notes (
-            id integer primary key autoincrement,
-            owner_id integer,
-            contents text,
-            foreign key(owner_id) references users(id));
-    ";
-    connection.execute(query).unwrap();
+    let args: Vec<String> = env::args().skip(1).filter(|a| a != "--postgres").collect();
+
+    #[cfg(feature = "postgres")]
+    if env::args().any(|a| a == "--postgres") {
+        let db = authrs::config::Configuration::from_env().unwrap().db;
+        let store = authrs::PgStore::new(&db).expect("Failed to connect to postgres");
+        return command(&store, &args);
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    if env::args().any(|a| a == "--postgres") {
+        eprintln!("bootstrap was built without the postgres feature");
+        std::process::exit(2);
+    }
+
+    let store = SqliteStore::new(&SqliteConfig::from_env()).expect("Failed to open sqlite");
+    command(&store, &args)
+}
+
+fn command(store: &impl Migrate, args: &[String]) {
+    let res = match args.first().map(|a| a.as_str()) {
+        None | Some("migrate") => migrate(store),
+        Some("status") => status(store),
+        Some("rollback") => match args.get(1).map(|s| s.parse()).unwrap_or(Ok(1)) {
+            Ok(steps) => rollback(store, steps),
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        },
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn migrate(store: &impl Migrate) -> Result<(), MigrationError> {
+    let applied = migrations::migrate(store)?;
+    if applied.is_empty() {
+        println!("schema is up to date");
+    }
+    for version in applied {
+        println!("applied {:04}", version);
+    }
+    Ok(())
+}
+
+fn status(store: &impl Migrate) -> Result<(), MigrationError> {
+    for s in migrations::status(store)? {
+        let applied_at = s
+            .applied_at
+            .and_then(|t| time::OffsetDateTime::from_unix_timestamp(t).ok())
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        println!(
+            "{:04} {:<20} {:<8} {}",
+            s.version,
+            s.name,
+            format!("{:?}", s.state).to_lowercase(),
+            applied_at
+        );
+    }
+    Ok(())
+}
+
+fn rollback(store: &impl Migrate, steps: usize) -> Result<(), MigrationError> {
+    for version in migrations::rollback(store, steps)? {
+        println!("reverted {:04}", version);
+    }
+    Ok(())
 }
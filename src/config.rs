@@ -1,3 +1,5 @@
+use axum_extra::extract::cookie::{Cookie, SameSite};
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -8,6 +10,8 @@ pub struct DbConfig {
     pub host: String,
     pub user: String,
     pub pass: String,
+    // connections kept open by the postgres pool
+    pub pool_size: u32,
 }
 
 impl DbConfig {
@@ -16,6 +20,129 @@ impl DbConfig {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "delete" => Some(Self::Delete),
+            "truncate" => Some(Self::Truncate),
+            "persist" => Some(Self::Persist),
+            "memory" => Some(Self::Memory),
+            "wal" => Some(Self::Wal),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+            Self::Memory => "MEMORY",
+            Self::Wal => "WAL",
+            Self::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "normal" => Some(Self::Normal),
+            "full" => Some(Self::Full),
+            "extra" => Some(Self::Extra),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SqliteConfig {
+    // file path, or `:memory:` for a database that lives as long as the store
+    pub path: String,
+    pub read_only: bool,
+    // left as is for read only databases
+    pub journal_mode: JournalMode,
+    // how long to wait on a locked database before giving up
+    pub busy_timeout: time::Duration,
+    pub foreign_keys: bool,
+    pub synchronous: Synchronous,
+    // read only connections, reads share the writer when 0
+    pub pool_size: u32,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            path: "test.db".to_string(),
+            read_only: false,
+            // lets the readers carry on while a write is in progress
+            journal_mode: JournalMode::Wal,
+            busy_timeout: time::Duration::seconds(5),
+            foreign_keys: true,
+            // durable enough with WAL, a crash can only lose the last commits
+            synchronous: Synchronous::Normal,
+            pool_size: 4,
+        }
+    }
+}
+
+impl SqliteConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            path: std::env::var("AUTHRS_SQLITE_PATH").unwrap_or(default.path),
+            read_only: env_bool("AUTHRS_SQLITE_READ_ONLY").unwrap_or(default.read_only),
+            journal_mode: std::env::var("AUTHRS_SQLITE_JOURNAL_MODE")
+                .ok()
+                .and_then(|s| JournalMode::parse(&s))
+                .unwrap_or(default.journal_mode),
+            busy_timeout: std::env::var("AUTHRS_SQLITE_BUSY_TIMEOUT_MS")
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .map(time::Duration::milliseconds)
+                .unwrap_or(default.busy_timeout),
+            foreign_keys: env_bool("AUTHRS_SQLITE_FOREIGN_KEYS").unwrap_or(default.foreign_keys),
+            synchronous: std::env::var("AUTHRS_SQLITE_SYNCHRONOUS")
+                .ok()
+                .and_then(|s| Synchronous::parse(&s))
+                .unwrap_or(default.synchronous),
+            pool_size: std::env::var("AUTHRS_SQLITE_POOL_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.pool_size),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Configuration {
     pub port: String,
@@ -34,7 +161,428 @@ impl Configuration {
                 host: db_host,
                 user: db_user,
                 pass: db_pass,
+                pool_size: std::env::var("DB_POOL_SIZE")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .filter(|size| *size > 0)
+                    .unwrap_or(10),
             },
         })
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    // guids of users allowed to use the /admin routes
+    pub admins: Vec<String>,
+    pub access_token_duration: time::Duration,
+    pub session_duration: time::Duration,
+    pub refresh_token_duration: time::Duration,
+    pub impersonation_duration: time::Duration,
+    // externally reachable address used to build verification urls
+    pub base_url: String,
+    // public clients allowed to use the device authorization grant
+    pub device_clients: Vec<String>,
+    pub device_code_duration: time::Duration,
+    pub device_poll_interval: time::Duration,
+    pub cookies: CookieConfig,
+    // origins allowed to make cookie authenticated state changing requests
+    pub allowed_origins: Vec<String>,
+    pub rate_limits: RateLimitConfig,
+    pub lockout: LockoutConfig,
+    pub webhooks: WebhookConfig,
+    pub change_feed: ChangeFeedConfig,
+    pub pages: PageConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct PageConfig {
+    // items in a /data list when the request doesn't set a limit
+    pub default_limit: usize,
+    // the most a request can ask for
+    pub max_limit: usize,
+    // the deepest offset a request can start at, the store steps over every row
+    // before it. Kept within what the stores' sql accepts
+    pub max_offset: usize,
+}
+
+impl Default for PageConfig {
+    fn default() -> Self {
+        Self {
+            default_limit: 100,
+            max_limit: 1000,
+            max_offset: 1_000_000,
+        }
+    }
+}
+
+impl PageConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let max_limit = std::env::var("AUTHRS_PAGE_MAX_LIMIT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or(default.max_limit);
+        Self {
+            default_limit: std::env::var("AUTHRS_PAGE_DEFAULT_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|limit| *limit > 0)
+                .unwrap_or(default.default_limit)
+                .min(max_limit),
+            max_limit,
+            max_offset: std::env::var("AUTHRS_PAGE_MAX_OFFSET")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(default.max_offset)
+                .min(i64::MAX as usize),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeFeedConfig {
+    // events kept for clients resuming with a Last-Event-ID
+    pub log_size: usize,
+    // interval between keep alive comments on idle streams
+    pub keep_alive: time::Duration,
+}
+
+impl Default for ChangeFeedConfig {
+    fn default() -> Self {
+        Self {
+            log_size: 1000,
+            keep_alive: time::Duration::seconds(15),
+        }
+    }
+}
+
+impl ChangeFeedConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            log_size: std::env::var("AUTHRS_CHANGE_FEED_LOG_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(default.log_size),
+            keep_alive: env_seconds("AUTHRS_CHANGE_FEED_KEEP_ALIVE_SECONDS")
+                .unwrap_or(default.keep_alive),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    // deliveries are dead lettered after this many failed attempts, at most
+    // MAX_WEBHOOK_ATTEMPTS
+    pub max_attempts: u32,
+    // wait before the first retry, doubling with each further retry
+    pub retry_delay: time::Duration,
+    // the longest the doubling goes
+    pub max_retry_delay: time::Duration,
+    pub timeout: time::Duration,
+}
+
+// a delivery is given up on within a day or so at the default delays
+pub const MAX_WEBHOOK_ATTEMPTS: u32 = 30;
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            retry_delay: time::Duration::seconds(2),
+            max_retry_delay: time::Duration::hours(1),
+            timeout: time::Duration::seconds(10),
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: std::env::var("AUTHRS_WEBHOOK_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .unwrap_or(default.max_attempts)
+                .clamp(1, MAX_WEBHOOK_ATTEMPTS),
+            retry_delay: env_seconds("AUTHRS_WEBHOOK_RETRY_SECONDS").unwrap_or(default.retry_delay),
+            max_retry_delay: env_seconds("AUTHRS_WEBHOOK_MAX_RETRY_SECONDS")
+                .unwrap_or(default.max_retry_delay),
+            timeout: env_seconds("AUTHRS_WEBHOOK_TIMEOUT_SECONDS").unwrap_or(default.timeout),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LockoutConfig {
+    // failed attempts before an account or ip is locked
+    pub max_failures: i64,
+    // first lock lasts this long, doubling with each further lock
+    pub base_duration: time::Duration,
+    pub max_duration: time::Duration,
+    // devices remembered per user for new device detection
+    pub known_devices: usize,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            base_duration: time::Duration::minutes(1),
+            max_duration: time::Duration::hours(1),
+            known_devices: 10,
+        }
+    }
+}
+
+impl LockoutConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_failures: std::env::var("AUTHRS_LOCKOUT_MAX_FAILURES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.max_failures),
+            base_duration: env_seconds("AUTHRS_LOCKOUT_BASE_SECONDS")
+                .unwrap_or(default.base_duration),
+            max_duration: env_seconds("AUTHRS_LOCKOUT_MAX_SECONDS").unwrap_or(default.max_duration),
+            known_devices: std::env::var("AUTHRS_KNOWN_DEVICES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.known_devices),
+        }
+    }
+
+    // how long the `lockouts`th consecutive lock lasts
+    pub fn lock_duration(&self, lockouts: i64) -> time::Duration {
+        let exponent = lockouts.saturating_sub(1).clamp(0, 30) as u32;
+        let factor = 2i32.saturating_pow(exponent);
+        self.base_duration
+            .checked_mul(factor)
+            .unwrap_or(self.max_duration)
+            .min(self.max_duration)
+    }
+}
+
+// Token bucket size & refill rate
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    // "capacity,per_second"
+    fn parse(value: &str) -> Option<Self> {
+        let (capacity, per_second) = value.split_once(',')?;
+        Some(Self {
+            capacity: capacity.trim().parse().ok()?,
+            per_second: per_second.trim().parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    // /auth, /oauth & /device, per ip
+    pub auth: RateLimit,
+    // safe /data requests, per ip & per principal
+    pub data_read: RateLimit,
+    // other /data requests, per ip & per principal
+    pub data_write: RateLimit,
+    // outstanding google login attempts a single ip may have
+    pub max_oauth_states_per_ip: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            auth: RateLimit {
+                capacity: 20.0,
+                per_second: 0.5,
+            },
+            data_read: RateLimit {
+                capacity: 100.0,
+                per_second: 20.0,
+            },
+            data_write: RateLimit {
+                capacity: 30.0,
+                per_second: 5.0,
+            },
+            max_oauth_states_per_ip: 10,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            auth: env_rate_limit("AUTHRS_RATE_LIMIT_AUTH").unwrap_or(default.auth),
+            data_read: env_rate_limit("AUTHRS_RATE_LIMIT_DATA_READ").unwrap_or(default.data_read),
+            data_write: env_rate_limit("AUTHRS_RATE_LIMIT_DATA_WRITE")
+                .unwrap_or(default.data_write),
+            max_oauth_states_per_ip: std::env::var("AUTHRS_MAX_OAUTH_STATES_PER_IP")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.max_oauth_states_per_ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    // prefix cookie names with `__Host-` (or `__Secure-` when not scoped to `/`)
+    pub host_prefix: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: false,
+            same_site: SameSite::Lax,
+            domain: None,
+            host_prefix: false,
+        }
+    }
+}
+
+impl CookieConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let same_site = match std::env::var("AUTHRS_COOKIE_SAMESITE")
+            .map(|s| s.to_lowercase())
+            .as_deref()
+        {
+            Ok("strict") => SameSite::Strict,
+            Ok("lax") => SameSite::Lax,
+            Ok("none") => SameSite::None,
+            _ => default.same_site,
+        };
+        Self {
+            secure: env_bool("AUTHRS_COOKIE_SECURE").unwrap_or(default.secure),
+            same_site,
+            domain: std::env::var("AUTHRS_COOKIE_DOMAIN").ok(),
+            host_prefix: env_bool("AUTHRS_COOKIE_HOST_PREFIX").unwrap_or(default.host_prefix),
+        }
+    }
+
+    pub fn name(&self, name: &str, path: &str) -> String {
+        match (self.host_prefix, path) {
+            (true, "/") => format!("__Host-{}", name),
+            (true, _) => format!("__Secure-{}", name),
+            (false, _) => name.to_string(),
+        }
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        value: String,
+        path: &str,
+        max_age: time::Duration,
+    ) -> Cookie<'static> {
+        // browsers drop prefixed & SameSite=None cookies that aren't Secure
+        let secure = self.secure || self.host_prefix || self.same_site == SameSite::None;
+        let mut cookie = Cookie::build((self.name(name, path), value))
+            .path(path.to_string())
+            .max_age(max_age)
+            .http_only(true)
+            .secure(secure)
+            .same_site(self.same_site);
+        // `__Host-` cookies must not set a Domain
+        if let (Some(domain), false) = (&self.domain, self.host_prefix) {
+            cookie = cookie.domain(domain.clone());
+        }
+        cookie.build()
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            admins: vec![],
+            access_token_duration: time::Duration::hours(1),
+            session_duration: time::Duration::minutes(10),
+            refresh_token_duration: time::Duration::days(30),
+            impersonation_duration: time::Duration::minutes(15),
+            base_url: "http://localhost:8080".to_string(),
+            device_clients: vec!["authrs-cli".to_string()],
+            device_code_duration: time::Duration::minutes(10),
+            device_poll_interval: time::Duration::seconds(5),
+            cookies: CookieConfig::default(),
+            allowed_origins: vec!["http://localhost:8080".to_string()],
+            rate_limits: RateLimitConfig::default(),
+            lockout: LockoutConfig::default(),
+            webhooks: WebhookConfig::default(),
+            change_feed: ChangeFeedConfig::default(),
+            pages: PageConfig::default(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let base_url = std::env::var("AUTHRS_BASE_URL").unwrap_or(default.base_url);
+        let allowed_origins = env_list("AUTHRS_ALLOWED_ORIGINS")
+            .unwrap_or_else(|| vec![base_url.trim_end_matches('/').to_string()]);
+        Self {
+            admins: env_list("AUTHRS_ADMINS").unwrap_or(default.admins),
+            access_token_duration: env_seconds("AUTHRS_ACCESS_TOKEN_SECONDS")
+                .unwrap_or(default.access_token_duration),
+            session_duration: env_seconds("AUTHRS_SESSION_SECONDS")
+                .unwrap_or(default.session_duration),
+            refresh_token_duration: env_seconds("AUTHRS_REFRESH_TOKEN_SECONDS")
+                .unwrap_or(default.refresh_token_duration),
+            impersonation_duration: env_seconds("AUTHRS_IMPERSONATION_SECONDS")
+                .unwrap_or(default.impersonation_duration),
+            base_url,
+            device_clients: env_list("AUTHRS_DEVICE_CLIENTS").unwrap_or(default.device_clients),
+            device_code_duration: env_seconds("AUTHRS_DEVICE_CODE_SECONDS")
+                .unwrap_or(default.device_code_duration),
+            device_poll_interval: env_seconds("AUTHRS_DEVICE_POLL_SECONDS")
+                .unwrap_or(default.device_poll_interval),
+            cookies: CookieConfig::from_env(),
+            allowed_origins,
+            rate_limits: RateLimitConfig::from_env(),
+            lockout: LockoutConfig::from_env(),
+            webhooks: WebhookConfig::from_env(),
+            change_feed: ChangeFeedConfig::from_env(),
+            pages: PageConfig::from_env(),
+        }
+    }
+}
+
+// comma separated list
+fn env_list(key: &str) -> Option<Vec<String>> {
+    std::env::var(key).ok().map(|list| {
+        list.split(',')
+            .map(|a| a.trim().to_string())
+            .filter(|a| !a.is_empty())
+            .collect()
+    })
+}
+
+fn env_bool(key: &str) -> Option<bool> {
+    std::env::var(key)
+        .ok()
+        .map(|s| matches!(s.to_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+fn env_seconds(key: &str) -> Option<time::Duration> {
+    std::env::var(key)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(time::Duration::seconds)
+}
+
+fn env_rate_limit(key: &str) -> Option<RateLimit> {
+    std::env::var(key).ok().and_then(|s| RateLimit::parse(&s))
+}
@@ -1,34 +1,183 @@
-use axum::http::StatusCode;
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::IntoResponse;
+use serde::Serialize;
 use std::error::Error;
 use std::fmt;
+use std::time::Duration;
 
-// Authr error kinds
+use crate::store::error::StoreError;
+use crate::types::ValidationError;
+
+// Authr error kinds, every one is sent as an RFC 7807 problem
 #[derive(Debug)]
 pub enum AuthrError {
-    NotFound,
+    // a body, path or query that couldn't be parsed
+    BadRequest(String),
+    // the fields at fault, empty when the database refused the data
+    Validation(Vec<ValidationError>),
+    // no credentials, or ones that didn't check out
+    Unauthenticated,
+    // credentials that don't allow the request
     NotAuthorized,
+    NotFound,
+    Conflict(String),
+    TooManyRequests(Duration),
+    Unavailable,
+    // the details stay in the logs
+    Internal,
+}
+
+// application/problem+json body
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    // machine readable, stable across releases
+    pub code: &'static str,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldProblem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldProblem {
+    pub field: String,
+    pub code: &'static str,
+    pub detail: String,
+}
+
+impl From<&ValidationError> for FieldProblem {
+    fn from(e: &ValidationError) -> Self {
+        Self {
+            field: e.field().to_string(),
+            code: e.code(),
+            detail: e.to_string(),
+        }
+    }
+}
+
+impl AuthrError {
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            AuthrError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AuthrError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AuthrError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AuthrError::NotAuthorized => StatusCode::FORBIDDEN,
+            AuthrError::NotFound => StatusCode::NOT_FOUND,
+            AuthrError::Conflict(_) => StatusCode::CONFLICT,
+            AuthrError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AuthrError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AuthrError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            AuthrError::BadRequest(_) => "bad_request",
+            AuthrError::Validation(_) => "validation_failed",
+            AuthrError::Unauthenticated => "unauthenticated",
+            AuthrError::NotAuthorized => "not_authorized",
+            AuthrError::NotFound => "not_found",
+            AuthrError::Conflict(_) => "conflict",
+            AuthrError::TooManyRequests(_) => "too_many_requests",
+            AuthrError::Unavailable => "unavailable",
+            AuthrError::Internal => "internal",
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+        let errors = match self {
+            AuthrError::Validation(errors) => errors.iter().map(FieldProblem::from).collect(),
+            _ => vec![],
+        };
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            code: self.code(),
+            detail: self.to_string(),
+            errors,
+        }
+    }
 }
 
 impl IntoResponse for AuthrError {
     fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        let body = match serde_json::to_string(&self.problem()) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                return status.into_response();
+            }
+        };
+        let mut response = (
+            status,
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )],
+            body,
+        )
+            .into_response();
         match self {
-            AuthrError::NotFound => (StatusCode::NOT_FOUND, "Not Found"),
-            AuthrError::NotAuthorized => (StatusCode::FORBIDDEN, "Not Authorized"),
+            AuthrError::Unauthenticated => {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            // Retry-After is in whole seconds, round up so clients don't retry early
+            AuthrError::TooManyRequests(retry_after) => {
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, seconds.max(1).into());
+            }
+            _ => {}
         }
-        .into_response()
+        response
     }
 }
 
 impl fmt::Display for AuthrError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            AuthrError::NotFound => {
-                write!(fmt, "Not Found")
+        match self {
+            AuthrError::BadRequest(e) => {
+                write!(fmt, "{}", e)
+            }
+            AuthrError::Validation(errors) if errors.is_empty() => {
+                write!(fmt, "The data is invalid")
+            }
+            AuthrError::Validation(errors) => {
+                let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(fmt, "The data is invalid: {}", errors.join(", "))
+            }
+            AuthrError::Unauthenticated => {
+                write!(fmt, "Authentication is required")
             }
             AuthrError::NotAuthorized => {
                 write!(fmt, "Not Authorized")
             }
+            AuthrError::NotFound => {
+                write!(fmt, "Not Found")
+            }
+            AuthrError::Conflict(e) => {
+                write!(fmt, "{}", e)
+            }
+            AuthrError::TooManyRequests(_) => {
+                write!(fmt, "Too many requests, try again later")
+            }
+            AuthrError::Unavailable => {
+                write!(fmt, "The service is unavailable, try again later")
+            }
+            AuthrError::Internal => {
+                write!(fmt, "Something went wrong")
+            }
         }
     }
 }
@@ -36,15 +185,55 @@ impl fmt::Display for AuthrError {
 impl Error for AuthrError {
     fn description(&self) -> &str {
         match *self {
+            AuthrError::BadRequest(_) => "Bad Request error",
+            AuthrError::Validation(_) => "Validation error",
+            AuthrError::Unauthenticated => "Unauthenticated error",
             AuthrError::NotFound => "Not Found error",
             AuthrError::NotAuthorized => "Not Authorized error",
+            AuthrError::Conflict(_) => "Conflict error",
+            AuthrError::TooManyRequests(_) => "Too Many Requests error",
+            AuthrError::Unavailable => "Unavailable error",
+            AuthrError::Internal => "Internal error",
         }
     }
 
     fn cause(&self) -> Option<&dyn Error> {
-        match *self {
-            AuthrError::NotFound => None,
-            AuthrError::NotAuthorized => None,
+        None
+    }
+}
+
+impl From<PathRejection> for AuthrError {
+    fn from(e: PathRejection) -> Self {
+        AuthrError::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for AuthrError {
+    fn from(e: QueryRejection) -> Self {
+        AuthrError::BadRequest(e.body_text())
+    }
+}
+
+impl From<ValidationError> for AuthrError {
+    fn from(e: ValidationError) -> Self {
+        AuthrError::Validation(vec![e])
+    }
+}
+
+// database messages name tables & columns, so they aren't passed on
+impl From<StoreError> for AuthrError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::NotFound => AuthrError::NotFound,
+            StoreError::Constraint(_) => AuthrError::Validation(vec![]),
+            StoreError::Conflict(_) => {
+                AuthrError::Conflict("The data conflicts with existing data".to_string())
+            }
+            StoreError::Unavailable(_) => AuthrError::Unavailable,
+            StoreError::NotCreated
+            | StoreError::Decode(_)
+            | StoreError::Poisoned
+            | StoreError::Query(_) => AuthrError::Internal,
         }
     }
 }
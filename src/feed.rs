@@ -0,0 +1,304 @@
+use std::{collections::VecDeque, convert::Infallible, sync::Arc, sync::Mutex};
+
+use axum::{
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, warn};
+
+use crate::{
+    DataState, Store,
+    config::ChangeFeedConfig,
+    error::AuthrError,
+    registry::{DataEntry, ExtractGlonkQueries},
+    store::{Query as _, codec},
+    types::{DataType, QueryTypes},
+};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+// A change to a data object, ids increase by one with every change
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ChangeEvent {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub data_type: DataType,
+    pub action: &'static str,
+    pub data: serde_json::Value,
+}
+
+// What a subscriber is sent. A reset means events were missed, either the
+// client fell too far behind or its Last-Event-ID predates the log, so it
+// should refetch & carry on from the reset's id.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub(crate) enum FeedMessage {
+    Change(ChangeEvent),
+    Reset { id: u64 },
+}
+
+struct EventLog {
+    next_id: u64,
+    events: VecDeque<ChangeEvent>,
+}
+
+// In memory feed of data changes, the most recent `log_size` are kept so
+// clients can resume after a dropped connection.
+pub struct ChangeFeed {
+    log: Mutex<EventLog>,
+    sender: broadcast::Sender<ChangeEvent>,
+    config: ChangeFeedConfig,
+}
+
+impl ChangeFeed {
+    pub fn new(config: ChangeFeedConfig) -> Self {
+        let (sender, _) = broadcast::channel(config.log_size);
+        Self {
+            log: Mutex::new(EventLog {
+                next_id: 1,
+                events: VecDeque::with_capacity(config.log_size),
+            }),
+            sender,
+            config,
+        }
+    }
+
+    pub(crate) fn publish(&self, data_type: DataType, action: &'static str, data: &impl Serialize) {
+        let data = match serde_json::to_value(data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!(
+                    "Could not serialize {}.{} change: {:?}",
+                    data_type, action, e
+                );
+                return;
+            }
+        };
+        match self.log.lock() {
+            Ok(mut log) => {
+                let event = ChangeEvent {
+                    id: log.next_id,
+                    data_type,
+                    action,
+                    data,
+                };
+                log.next_id += 1;
+                if log.events.len() == self.config.log_size {
+                    log.events.pop_front();
+                }
+                log.events.push_back(event.clone());
+                // sent under the lock so subscribers see the log & the channel in the same order,
+                // it only errors when nobody is listening
+                let _ = self.sender.send(event);
+            }
+            Err(e) => {
+                error!("{:?}", e);
+            }
+        }
+    }
+
+    // Starts listening, replaying the logged events after `last_event_id`
+    fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> Option<(VecDeque<FeedMessage>, broadcast::Receiver<ChangeEvent>)> {
+        let log = match self.log.lock() {
+            Ok(log) => log,
+            Err(e) => {
+                error!("{:?}", e);
+                return None;
+            }
+        };
+        let receiver = self.sender.subscribe();
+        let last_event_id = match last_event_id {
+            Some(id) => id,
+            None => return Some((VecDeque::new(), receiver)),
+        };
+        let oldest = log.next_id - log.events.len() as u64;
+        // ids from before a restart can be ahead of the log
+        let backlog = if last_event_id.saturating_add(1) >= oldest && last_event_id < log.next_id {
+            log.events
+                .iter()
+                .filter(|e| e.id > last_event_id)
+                .cloned()
+                .map(FeedMessage::Change)
+                .collect()
+        } else {
+            VecDeque::from([FeedMessage::Reset {
+                id: log.next_id - 1,
+            }])
+        };
+        Some((backlog, receiver))
+    }
+}
+
+// One client's view of the feed, limited to a data type & its queries
+struct Subscription<S: Store> {
+    entry: Arc<dyn DataEntry<S>>,
+    queries: Vec<QueryTypes>,
+    backlog: VecDeque<FeedMessage>,
+    receiver: broadcast::Receiver<ChangeEvent>,
+}
+
+impl<S: Store> Subscription<S> {
+    fn new(
+        state: &DataState<S>,
+        entry: Arc<dyn DataEntry<S>>,
+        queries: Vec<QueryTypes>,
+        last_event_id: Option<u64>,
+    ) -> Option<Self> {
+        let (backlog, receiver) = state.feed.subscribe(last_event_id)?;
+        Some(Self {
+            entry,
+            queries,
+            backlog,
+            receiver,
+        })
+    }
+
+    // checked against the event's snapshot in memory, the stream doesn't wait
+    // on the store for every event
+    fn visible(&self, event: &ChangeEvent) -> bool {
+        if event.data_type != self.entry.data_type() {
+            return false;
+        }
+        match codec::value_row(event.data.clone()) {
+            Some(row) => self.queries.iter().all(|q| q.matches(&row)),
+            // an event that can't be checked isn't sent
+            None => {
+                error!("Could not match {} event: not an object", event.data_type);
+                false
+            }
+        }
+    }
+
+    // None once the stream should end, a client that lagged behind the
+    // channel reconnects & catches up from the log
+    async fn next(&mut self) -> Option<FeedMessage> {
+        while let Some(message) = self.backlog.pop_front() {
+            match &message {
+                FeedMessage::Change(event) if !self.visible(event) => continue,
+                _ => return Some(message),
+            }
+        }
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.visible(&event) => return Some(FeedMessage::Change(event)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("change feed subscriber lagged by {} events", missed);
+                    return None;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+// browsers can't set headers on a WebSocket, so it can come as a query param too
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResumeParams {
+    #[serde(rename = "lastEventId")]
+    last_event_id: Option<u64>,
+}
+
+fn last_event_id(headers: &HeaderMap, params: &ResumeParams) -> Option<u64> {
+    headers
+        .get(LAST_EVENT_ID)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse().ok())
+        .or(params.last_event_id)
+}
+
+fn sse_event(message: FeedMessage) -> Event {
+    let event = match &message {
+        FeedMessage::Change(change) => Event::default().id(change.id.to_string()),
+        FeedMessage::Reset { id } => Event::default().event("reset").id(id.to_string()),
+    };
+    event.json_data(&message).unwrap_or_else(|e| {
+        error!("Could not serialize change feed message: {:?}", e);
+        Event::default().comment("dropped")
+    })
+}
+
+pub(crate) async fn events_sse<S: Store>(
+    Path(data_type): Path<String>,
+    ExtractGlonkQueries(queries): ExtractGlonkQueries,
+    Query(params): Query<ResumeParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<DataState<S>>>,
+) -> impl IntoResponse {
+    let keep_alive = state.feed.config.keep_alive.unsigned_abs();
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    let subscription =
+        match Subscription::new(&state, entry, queries, last_event_id(&headers, &params)) {
+            Some(subscription) => subscription,
+            None => return AuthrError::NotFound.into_response(),
+        };
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let message = subscription.next().await?;
+        Some((Ok::<_, Infallible>(sse_event(message)), subscription))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(keep_alive))
+        .into_response()
+}
+
+pub(crate) async fn events_ws<S: Store>(
+    Path(data_type): Path<String>,
+    ExtractGlonkQueries(queries): ExtractGlonkQueries,
+    Query(params): Query<ResumeParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<DataState<S>>>,
+) -> impl IntoResponse {
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    let subscription =
+        match Subscription::new(&state, entry, queries, last_event_id(&headers, &params)) {
+            Some(subscription) => subscription,
+            None => return AuthrError::NotFound.into_response(),
+        };
+    ws.on_upgrade(move |socket| send_changes(socket, subscription))
+}
+
+async fn send_changes<S: Store>(mut socket: WebSocket, mut subscription: Subscription<S>) {
+    loop {
+        tokio::select! {
+            message = subscription.next() => {
+                let message = match message.map(|m| serde_json::to_string(&m)) {
+                    Some(Ok(message)) => message,
+                    Some(Err(e)) => {
+                        error!("Could not serialize change feed message: {:?}", e);
+                        continue;
+                    }
+                    None => break,
+                };
+                if socket.send(Message::text(message)).await.is_err() {
+                    break;
+                }
+            }
+            // the feed is one way, incoming messages only matter when they close the socket
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+    debug!("change feed socket closed");
+}
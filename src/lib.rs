@@ -1,244 +1,408 @@
+// lets #[derive(DataObject)] name this crate the same way inside & out
+extern crate self as authrs;
+
 // module declarations
+pub mod admin;
+pub mod audit;
 pub mod auth;
 pub mod config;
 pub mod error;
-mod store;
+pub mod feed;
+pub mod rate_limit;
+pub mod registry;
+pub mod store;
 pub mod types;
+pub mod webhooks;
 
 // internal imports
-use crate::auth::google_auth::GoogleAuthClient;
+use crate::audit::{Audit, AuditRecord};
+use crate::auth::Principal;
+use crate::auth::device::DeviceAuthorization;
+use crate::auth::google_auth::{GoogleAuthClient, OAuthState};
+use crate::auth::security::{IpFailures, SecurityHook};
+use crate::auth::sessions::Session;
+use crate::auth::tokens::AccessToken;
+use crate::config::{AuthConfig, PageConfig};
 use crate::error::AuthrError;
-pub use crate::store::SqliteStore;
-use crate::store::{ExtractGlonkQueries, Store};
-use crate::types::{DataObject, DataType, Note, RequestNote, RequestObject, RequestUser, User};
+use crate::feed::ChangeFeed;
+use crate::rate_limit::{MemoryRateLimitStore, RateLimitStore, RateLimiter};
+use crate::registry::{ExtractGlonkQueries, ListParams, Next, Registry};
+#[cfg(feature = "postgres")]
+pub use crate::store::PgStore;
+use crate::store::Query;
+pub use crate::store::{AsyncStore, MemStore, SqliteStore, Store};
+use crate::types::{DataObject, DataType, RequestObject};
+use crate::webhooks::Webhooks;
 
 // imports
-use axum::http::StatusCode;
+use axum::middleware;
 use axum::{
-    Json, Router,
-    extract::{Path, State},
+    Extension, Json, Router,
+    extract::{FromRequestParts, OriginalUri, Path, Query as UrlQuery, State},
     handler::HandlerWithoutStateExt,
+    http::{HeaderMap, HeaderName, HeaderValue, Uri, header::LINK},
     response::IntoResponse,
     routing::{delete, get, post, put},
 };
-use axum::{debug_handler, middleware};
-use serde::Serialize;
+use oauth2::url::form_urlencoded;
+use serde::{Serialize, de::DeserializeOwned};
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     sync::{Arc, Mutex},
 };
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
 use tracing::{debug, error, info};
 
-// state type
-pub struct AuthrState {
-    auth: Arc<AuthState>,
-    data: Arc<DataState>,
+// state type, generic over the store backend
+pub struct AuthrState<S: Store> {
+    auth: Arc<AuthState<S>>,
+    data: Arc<DataState<S>>,
+    limiter: Arc<RateLimiter>,
 }
 
-pub struct AuthState {
-    oauth_sessions: Mutex<HashMap<String, String>>,
-    sessions: Mutex<HashMap<String, (User, time::OffsetDateTime)>>,
+pub struct AuthState<S: Store> {
+    oauth_sessions: Mutex<HashMap<String, OAuthState>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    access_tokens: Mutex<HashMap<String, AccessToken>>,
+    device_codes: Mutex<HashMap<String, DeviceAuthorization>>,
+    failed_ips: Mutex<HashMap<String, IpFailures>>,
+    security_hook: Option<Box<dyn SecurityHook>>,
     google_client: GoogleAuthClient,
-    store: Arc<SqliteStore>,
+    store: Arc<S>,
+    audit: Arc<Audit<S>>,
+    webhooks: Arc<Webhooks<S>>,
+    feed: Arc<ChangeFeed>,
+    registry: Arc<Registry<S>>,
+    config: AuthConfig,
 }
 
-pub struct DataState {
-    store: Arc<SqliteStore>,
+pub struct DataState<S: Store> {
+    store: Arc<S>,
+    audit: Arc<Audit<S>>,
+    webhooks: Arc<Webhooks<S>>,
+    feed: Arc<ChangeFeed>,
+    registry: Arc<Registry<S>>,
+    pages: PageConfig,
 }
 
-impl AuthrState {
-    pub fn new(google_client: GoogleAuthClient, store: SqliteStore) -> Self {
+impl<S: Store + AsyncStore> AuthrState<S> {
+    pub fn new(google_client: GoogleAuthClient, store: S, config: AuthConfig) -> Self {
         let store = Arc::new(store);
+        let audit = Arc::new(Audit::new(store.clone()));
+        let webhooks = Arc::new(Webhooks::new(store.clone(), config.webhooks.clone()));
+        let feed = Arc::new(ChangeFeed::new(config.change_feed.clone()));
+        let limiter = RateLimiter::new(config.rate_limits.clone(), MemoryRateLimitStore::new());
+        let registry = Arc::new(Registry::default());
+        let pages = config.pages.clone();
         Self {
             auth: Arc::new(AuthState {
-                oauth_sessions: Mutex::new(HashMap::<String, String>::new()),
-                sessions: Mutex::new(HashMap::<String, (User, time::OffsetDateTime)>::new()),
+                oauth_sessions: Mutex::new(HashMap::<String, OAuthState>::new()),
+                sessions: Mutex::new(HashMap::<String, Session>::new()),
+                access_tokens: Mutex::new(HashMap::<String, AccessToken>::new()),
+                device_codes: Mutex::new(HashMap::<String, DeviceAuthorization>::new()),
+                failed_ips: Mutex::new(HashMap::<String, IpFailures>::new()),
+                security_hook: None,
                 google_client,
                 store: store.clone(),
+                audit: audit.clone(),
+                webhooks: webhooks.clone(),
+                feed: feed.clone(),
+                registry: registry.clone(),
+                config,
+            }),
+            data: Arc::new(DataState {
+                store,
+                audit,
+                webhooks,
+                feed,
+                registry,
+                pages,
             }),
-            data: Arc::new(DataState { store }),
+            limiter: Arc::new(limiter),
+        }
+    }
+
+    // swaps the in-memory rate limit buckets for a shared backend
+    pub fn with_rate_limit_store(mut self, store: impl RateLimitStore + 'static) -> Self {
+        self.limiter = Arc::new(RateLimiter::new(
+            self.auth.config.rate_limits.clone(),
+            store,
+        ));
+        self
+    }
+
+    // notified of lockouts & logins from new devices
+    pub fn with_security_hook(mut self, hook: impl SecurityHook + 'static) -> Self {
+        match Arc::get_mut(&mut self.auth) {
+            Some(auth) => auth.security_hook = Some(Box::new(hook)),
+            None => error!("Could not set security hook, auth state already shared"),
+        }
+        self
+    }
+
+    // serves `T` under /data/{name} next to the built in user & note, see
+    // `Registry::register`
+    pub fn with_data_type<T, R, Q>(mut self, name: &'static str) -> Self
+    where
+        T: DataObject + Serialize,
+        R: RequestObject + DeserializeOwned,
+        Q: Query + 'static + for<'a> TryFrom<(&'a String, &'a String)>,
+    {
+        let mut registry = (*self.data.registry).clone();
+        registry.register::<T, R, Q>(DataType::new(name));
+        let registry = Arc::new(registry);
+        match (Arc::get_mut(&mut self.auth), Arc::get_mut(&mut self.data)) {
+            (Some(auth), Some(data)) => {
+                auth.registry = registry.clone();
+                data.registry = registry;
+            }
+            _ => error!("Could not register {}, state already shared", name),
         }
+        self
     }
 }
 
-async fn data_get_queries(
-    Path(data_type): Path<DataType>,
+const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+// Path, rejected with a problem rather than axum's plain text
+#[derive(FromRequestParts)]
+#[from_request(via(Path), rejection(AuthrError))]
+struct DataPath<T>(T);
+
+// Query, rejected with a problem rather than axum's plain text
+#[derive(FromRequestParts)]
+#[from_request(via(UrlQuery), rejection(AuthrError))]
+struct DataQuery<T>(T);
+
+// The matching objects, a page at a time. The next page is linked in a Link
+// header & the total, when asked for, sent as X-Total-Count.
+async fn data_get_queries<S: Store + AsyncStore>(
+    DataPath(data_type): DataPath<String>,
     ExtractGlonkQueries(queries): ExtractGlonkQueries,
-    State(state): State<Arc<DataState>>,
+    DataQuery(params): DataQuery<ListParams>,
+    OriginalUri(uri): OriginalUri,
+    State(state): State<Arc<DataState<S>>>,
 ) -> impl IntoResponse {
     debug!("{:?}", queries);
-    match data_type {
-        DataType::User => {
-            let data = state.store.clone().get_queries::<User>(queries);
-            Json(data.clone()).into_response()
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    match entry
+        .list(&state.store, queries, params, &state.pages)
+        .await
+    {
+        Ok(listing) => {
+            let mut headers = HeaderMap::new();
+            if let Some(total) = listing.total {
+                headers.insert(TOTAL_COUNT, total.into());
+            }
+            if let Some(link) = listing.next.and_then(|next| next_link(&uri, next)) {
+                headers.insert(LINK, link);
+            }
+            (headers, Json(listing.items)).into_response()
         }
-        DataType::Note => {
-            let data = state.store.clone().get_queries::<Note>(queries);
-            Json(data.clone()).into_response()
+        Err(e) => {
+            error!("{:?}", e);
+            e.into_response()
         }
     }
 }
 
-async fn data_get(
-    Path((data_type, id)): Path<(DataType, i64)>,
-    State(state): State<Arc<DataState>>,
+async fn data_get<S: Store + AsyncStore>(
+    DataPath((data_type, id)): DataPath<(String, i64)>,
+    State(state): State<Arc<DataState<S>>>,
 ) -> impl IntoResponse {
-    match data_type {
-        DataType::User => {
-            let data: Option<User> = state.store.clone().get(id);
-            match data {
-                Some(data) => Json(data.clone()).into_response(),
-                None => AuthrError::NotFound.into_response(),
-            }
-        }
-        DataType::Note => {
-            let data: Option<Note> = state.store.clone().get(id);
-            match data {
-                Some(data) => Json(data.clone()).into_response(),
-                None => AuthrError::NotFound.into_response(),
-            }
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    match entry.get(&state.store, id).await {
+        Ok(Some(data)) => Json(data).into_response(),
+        Ok(None) => AuthrError::NotFound.into_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            e.into_response()
         }
     }
 }
 
-async fn data_delete(
-    Path((data_type, id)): Path<(DataType, i64)>,
-    State(state): State<Arc<DataState>>,
+async fn data_delete<S: Store + AsyncStore>(
+    DataPath((data_type, id)): DataPath<(String, i64)>,
+    State(state): State<Arc<DataState<S>>>,
+    Extension(principal): Extension<Principal>,
 ) -> impl IntoResponse {
-    match data_type {
-        DataType::User => {
-            let data = state.store.clone().delete::<User>(id);
-            match data {
-                Ok(data) => Json(data.clone()).into_response(),
-                Err(_) => AuthrError::NotFound.into_response(),
-            }
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    let data_type = entry.data_type();
+    match entry.delete(&state.store, id).await {
+        Ok(data) => {
+            state.audit.record(
+                AuditRecord::new(principal, "delete")
+                    .target(data_type, id)
+                    .before(&data),
+            );
+            state.webhooks.emit(data_type, "deleted", &data);
+            state.feed.publish(data_type, "deleted", &data);
+            Json(data).into_response()
         }
-        DataType::Note => {
-            let data = state.store.clone().delete::<Note>(id);
-            match data {
-                Ok(data) => Json(data.clone()).into_response(),
-                Err(_) => AuthrError::NotFound.into_response(),
-            }
+        Err(e) => {
+            error!("{:?}", e);
+            e.into_response()
         }
     }
 }
 
-async fn handle_create<R: RequestObject + Clone, T: DataObject + Serialize>(
-    payload: R,
-    state: Arc<DataState>,
+async fn data_create<S: Store + AsyncStore>(
+    DataPath(data_type): DataPath<String>,
+    State(state): State<Arc<DataState<S>>>,
+    Extension(principal): Extension<Principal>,
+    body: String,
 ) -> impl IntoResponse {
-    if let Err(e) = payload.validate_create() {
-        error!("{:?}", e);
-        return AuthrError::NotFound.into_response();
-    }
-    let data = state.store.clone().create::<_, T>(payload);
-    match data {
-        Ok(data) => Json(data.clone()).into_response(),
-        Err(_) => AuthrError::NotFound.into_response(),
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    let data_type = entry.data_type();
+    match entry.create(&state.store, body.as_str()).await {
+        Ok(data) => {
+            let mut record = AuditRecord::new(principal, "create").after(&data);
+            if let Some(id) = object_id(&data) {
+                record = record.target(data_type, id);
+            }
+            state.audit.record(record);
+            state.webhooks.emit(data_type, "created", &data);
+            state.feed.publish(data_type, "created", &data);
+            Json(data).into_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            e.into_response()
+        }
     }
 }
 
-async fn data_create(
-    Path(data_type): Path<DataType>,
-    State(state): State<Arc<DataState>>,
+async fn data_update<S: Store + AsyncStore>(
+    DataPath(data_type): DataPath<String>,
+    State(state): State<Arc<DataState<S>>>,
+    Extension(principal): Extension<Principal>,
     body: String,
 ) -> impl IntoResponse {
-    match data_type {
-        DataType::User => match serde_json::from_str::<RequestUser>(body.as_str()) {
-            Ok(payload) => handle_create::<_, User>(payload, state)
-                .await
-                .into_response(),
-            Err(e) => {
-                error!("{:?}", e);
-                return (StatusCode::BAD_REQUEST, "Bad Request").into_response();
-            }
-        },
-        DataType::Note => match serde_json::from_str::<RequestNote>(body.as_str()) {
-            Ok(payload) => handle_create::<_, Note>(payload, state)
-                .await
-                .into_response(),
-            Err(e) => {
-                error!("{:?}", e);
-                return (StatusCode::BAD_REQUEST, "Bad Request").into_response();
+    let entry = match state.registry.entry(&data_type) {
+        Ok(entry) => entry,
+        Err(e) => return e.into_response(),
+    };
+    let data_type = entry.data_type();
+    match entry.update(&state.store, body.as_str()).await {
+        Ok(updated) => {
+            let mut record = AuditRecord::new(principal, "update")
+                .target(data_type, updated.id)
+                .after(&updated.after);
+            if let Some(before) = &updated.before {
+                record = record.before(before);
             }
-        },
+            state.audit.record(record);
+            state.webhooks.emit(data_type, "updated", &updated.after);
+            state.feed.publish(data_type, "updated", &updated.after);
+            Json(updated.after).into_response()
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            e.into_response()
+        }
     }
 }
 
-async fn handle_update<R: RequestObject + Clone, T: DataObject + Serialize>(
-    payload: R,
-    state: Arc<DataState>,
-) -> impl IntoResponse {
-    if let Err(e) = payload.validate_update() {
-        error!("{:?}", e);
-        return AuthrError::NotFound.into_response();
-    }
-    let data = state.store.clone().update::<_, T>(payload);
-    match data {
-        Ok(data) => Json(data.clone()).into_response(),
-        Err(_) => AuthrError::NotFound.into_response(),
-    }
+// helper functions
+// the id of a serialized data object, for audit records
+fn object_id(data: &serde_json::Value) -> Option<i64> {
+    data.get("id")?.as_i64()
 }
 
-async fn data_update(
-    Path(data_type): Path<DataType>,
-    State(state): State<Arc<DataState>>,
-    body: String,
-) -> impl IntoResponse {
-    match data_type {
-        DataType::User => match serde_json::from_str::<RequestUser>(body.as_str()) {
-            Ok(payload) => handle_update::<_, User>(payload, state)
-                .await
-                .into_response(),
-            Err(e) => {
-                error!("{:?}", e);
-                return (StatusCode::BAD_REQUEST, "Bad Request").into_response();
-            }
-        },
-        DataType::Note => match serde_json::from_str::<RequestNote>(body.as_str()) {
-            Ok(payload) => handle_update::<_, Note>(payload, state)
-                .await
-                .into_response(),
-            Err(e) => {
-                error!("{:?}", e);
-                return (StatusCode::BAD_REQUEST, "Bad Request").into_response();
-            }
-        },
+// the list's next page, the same url with the cursor or offset moved on
+fn next_link(uri: &Uri, next: Next) -> Option<HeaderValue> {
+    let (key, val) = match next {
+        Next::Cursor(cursor) => ("cursor", cursor),
+        Next::Offset(offset) => ("offset", offset.to_string()),
+    };
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    for (k, v) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        if k != key {
+            query.append_pair(&k, &v);
+        }
     }
+    query.append_pair(key, &val);
+    let link = format!("<{}?{}>; rel=\"next\"", uri.path(), query.finish());
+    HeaderValue::from_str(&link).ok()
 }
 
-// helper functions
 async fn handle_not_found() -> impl IntoResponse {
     AuthrError::NotFound.into_response()
 }
 
-fn data_routes(state: Arc<DataState>) -> Router {
+fn data_routes<S: Store + AsyncStore>(
+    state: Arc<DataState<S>>,
+    limiter: Arc<RateLimiter>,
+) -> Router {
     Router::new()
         .route("/{type}/{id}", get(data_get))
         .route("/{type}", get(data_get_queries))
+        .route("/{type}/events", get(feed::events_sse))
+        .route("/{type}/events/ws", get(feed::events_ws))
         .route("/{type}/{id}", delete(data_delete))
         .route("/{type}", post(data_create))
         .route("/{type}", put(data_update))
+        // runs after the authorizer so requests are limited per principal
+        .route_layer(middleware::from_fn_with_state(
+            limiter,
+            rate_limit::limit_by_principal,
+        ))
         .with_state(state)
 }
 
-pub async fn run(listener: TcpListener, state: AuthrState) {
+pub async fn run<S: Store + AsyncStore>(listener: TcpListener, state: AuthrState<S>) {
     let state = Arc::new(state);
     let app = Router::new()
-        // data routes should only get the store in state
-        .nest_service("/data/", data_routes(state.data.clone()))
+        // data routes should only get the store, the audit log, webhooks & the change feed in state
+        .nest_service(
+            "/data/",
+            data_routes(state.data.clone(), state.limiter.clone()),
+        )
+        // admin routes check the authorized principal against the configured admins
+        .nest_service("/admin/", admin::routes(state.auth.clone()))
         .route_layer(middleware::from_fn_with_state(
             state.auth.clone(),
             auth::request_authorizer,
         ))
         // auth routes should get the store & the sessions
         .nest_service("/auth/", auth::routes(state.auth.clone()))
+        // oauth routes authenticate clients themselves
+        .nest_service("/oauth/", auth::oauth::routes(state.auth.clone()))
+        // device verification page checks the browser session itself
+        .merge(auth::device::routes(state.auth.clone()))
         .fallback_service(
             ServeDir::new("static").not_found_service(handle_not_found.into_service()),
-        );
+        )
+        // applies to every route that accepts our cookies
+        .layer(middleware::from_fn_with_state(
+            state.auth.clone(),
+            auth::csrf::csrf_guard,
+        ))
+        // outermost so floods are turned away before any other work
+        .layer(middleware::from_fn_with_state(
+            state.limiter.clone(),
+            rate_limit::limit_by_ip,
+        ));
 
     info!("Listening on {:?}", listener.local_addr());
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -2,15 +2,19 @@ use std::env;
 use tokio::net::TcpListener;
 use tracing_subscriber::prelude::*;
 
-use authrs::{AuthrState, SqliteStore, auth::google_auth::GoogleAuthClient, run};
+use authrs::{
+    AuthrState, MemStore, SqliteStore,
+    auth::google_auth::GoogleAuthClient,
+    config::{AuthConfig, SqliteConfig},
+    run,
+    store::migrations::{self, Migrate},
+};
 use tracing::info;
 
 #[tokio::main]
 async fn main() {
     let client = GoogleAuthClient::from_env();
-    // let mem_store = MemStore::new();
-    let store = SqliteStore::new();
-    let state = AuthrState::new(client, store);
+    let config = AuthConfig::from_env();
 
     if env::var("RUST_LOG").is_err() {
         panic!("RUST_LOG not set!");
@@ -32,5 +36,37 @@ async fn main() {
         .await
         .expect("Failed to bind address");
 
-    run(listener, state).await
+    // AUTHRS_STORE=memory keeps everything in memory, nothing survives a restart
+    match env::var("AUTHRS_STORE").as_deref() {
+        Ok("memory") => {
+            let store = MemStore::new().expect("Failed to start the memory store");
+            run(listener, AuthrState::new(client, store, config)).await
+        }
+        // connects with DB_HOST, DB_USER & DB_PASS
+        #[cfg(feature = "postgres")]
+        Ok("postgres") => {
+            let db = authrs::config::Configuration::from_env().unwrap().db;
+            let store = authrs::PgStore::new(&db).expect("Failed to connect to postgres");
+            migrate(&store);
+            run(listener, AuthrState::new(client, store, config)).await
+        }
+        _ => {
+            // configured with the AUTHRS_SQLITE_* variables
+            let store = SqliteStore::new(&SqliteConfig::from_env()).expect("Failed to open sqlite");
+            migrate(&store);
+            run(listener, AuthrState::new(client, store, config)).await
+        }
+    }
+}
+
+// AUTHRS_AUTO_MIGRATE=false leaves the schema to `bootstrap migrate`
+fn migrate(store: &impl Migrate) {
+    if env::var("AUTHRS_AUTO_MIGRATE").is_ok_and(|v| v == "false") {
+        return;
+    }
+    match migrations::migrate(store) {
+        Ok(applied) if applied.is_empty() => info!("schema is up to date"),
+        Ok(applied) => info!("applied migrations {:?}", applied),
+        Err(e) => panic!("{}", e),
+    }
 }
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::{debug, error};
+
+use crate::{
+    auth::{Principal, client_info},
+    config::{RateLimit, RateLimitConfig},
+    error::AuthrError,
+};
+
+// buckets are pruned once a store holds this many keys
+const MAX_BUCKETS: usize = 10_000;
+// & when the full ones aren't enough, the least recently used go until this
+// many are left, so it doesn't run again on the next call
+const PRUNE_TO: usize = MAX_BUCKETS * 9 / 10;
+
+// Token bucket storage. The in-memory store only limits a single instance,
+// a shared backend can implement this to limit across instances.
+pub trait RateLimitStore: Send + Sync {
+    // Takes a token from the bucket for `key`, or returns how long until one is available
+    fn acquire(&self, key: &str, limit: &RateLimit) -> Result<(), Duration>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RouteGroup {
+    Auth,
+    DataRead,
+    DataWrite,
+}
+
+impl RouteGroup {
+    fn classify(method: &Method, path: &str) -> Option<Self> {
+        if path.starts_with("/auth/") || path.starts_with("/oauth/") || path == "/device" {
+            Some(RouteGroup::Auth)
+        } else if path.starts_with("/data/") && method.is_safe() {
+            Some(RouteGroup::DataRead)
+        } else if path.starts_with("/data/") {
+            Some(RouteGroup::DataWrite)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "auth",
+            RouteGroup::DataRead => "data_read",
+            RouteGroup::DataWrite => "data_write",
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Box<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: impl RateLimitStore + 'static) -> Self {
+        Self {
+            config,
+            store: Box::new(store),
+        }
+    }
+
+    fn limit(&self, group: RouteGroup) -> &RateLimit {
+        match group {
+            RouteGroup::Auth => &self.config.auth,
+            RouteGroup::DataRead => &self.config.data_read,
+            RouteGroup::DataWrite => &self.config.data_write,
+        }
+    }
+
+    fn acquire(&self, group: RouteGroup, key: &str) -> Result<(), Duration> {
+        let key = format!("{}:{}", group.name(), key);
+        self.store
+            .acquire(&key, self.limit(group))
+            .inspect_err(|retry_after| debug!("rate limited {} for {:?}", key, retry_after))
+    }
+}
+
+pub(crate) fn too_many_requests(retry_after: Duration) -> Response {
+    AuthrError::TooManyRequests(retry_after).into_response()
+}
+
+// per ip middleware, runs before authentication
+pub async fn limit_by_ip(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let group = match RouteGroup::classify(req.method(), req.uri().path()) {
+        Some(group) => group,
+        None => return next.run(req).await,
+    };
+    let ip = client_info::client_ip(req.extensions()).unwrap_or_default();
+    if let Err(retry_after) = limiter.acquire(group, &format!("ip:{}", ip)) {
+        return too_many_requests(retry_after);
+    }
+    next.run(req).await
+}
+
+// per principal middleware, must run after auth::request_authorizer
+pub async fn limit_by_principal(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let group = if req.method().is_safe() {
+        RouteGroup::DataRead
+    } else {
+        RouteGroup::DataWrite
+    };
+    let key = match req.extensions().get::<Principal>() {
+        // impersonating admins share the user's budget
+        Some(Principal::Impersonated { user, .. }) => format!("user:{}", user.id),
+        Some(principal) => principal.to_string(),
+        None => return next.run(req).await,
+    };
+    if let Err(retry_after) = limiter.acquire(group, &key) {
+        return too_many_requests(retry_after);
+    }
+    next.run(req).await
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // when it's back to capacity under its own limit, None when it never refills
+    full_at: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn acquire(&self, key: &str, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(e) => {
+                // fail open, a broken limiter shouldn't take the service down
+                error!("{:?}", e);
+                return Ok(());
+            }
+        };
+        if buckets.len() >= MAX_BUCKETS {
+            prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.capacity,
+            updated: now,
+            full_at: Some(now),
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.full_at = (limit.per_second > 0.0).then(|| {
+                now + Duration::from_secs_f64((limit.capacity - bucket.tokens) / limit.per_second)
+            });
+            return Ok(());
+        }
+        if limit.per_second <= 0.0 {
+            return Err(Duration::from_secs(60));
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / limit.per_second,
+        ))
+    }
+}
+
+fn prune(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    // full buckets carry no state worth keeping
+    buckets.retain(|_, b| b.full_at.is_none_or(|full_at| full_at > now));
+    if buckets.len() < MAX_BUCKETS {
+        return;
+    }
+    let mut updated = buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+    let (_, cutoff, _) = updated.select_nth_unstable(buckets.len() - PRUNE_TO);
+    let cutoff = *cutoff;
+    buckets.retain(|_, b| b.updated > cutoff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow() -> RateLimit {
+        RateLimit {
+            capacity: 2.0,
+            per_second: 0.001,
+        }
+    }
+
+    fn len(store: &MemoryRateLimitStore) -> usize {
+        store.buckets.lock().unwrap().len()
+    }
+
+    #[test]
+    fn buckets_stay_under_the_cap() {
+        let store = MemoryRateLimitStore::new();
+        // one token per key leaves every bucket partly drained
+        for i in 0..MAX_BUCKETS * 3 {
+            store.acquire(&format!("ip:{}", i), &slow()).unwrap();
+            assert!(len(&store) <= MAX_BUCKETS);
+        }
+        // the most recent keys are kept
+        let last = format!("ip:{}", MAX_BUCKETS * 3 - 1);
+        store.acquire(&last, &slow()).unwrap();
+        assert!(store.acquire(&last, &slow()).is_err());
+    }
+
+    #[test]
+    fn buckets_refill_under_their_own_limit() {
+        let store = MemoryRateLimitStore::new();
+        let fast = RateLimit {
+            capacity: 2.0,
+            per_second: 1_000_000.0,
+        };
+        for i in 0..MAX_BUCKETS - 1 {
+            store.acquire(&format!("fast:{}", i), &fast).unwrap();
+        }
+        store.acquire("slow:1", &slow()).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        // the fast buckets are full again, whatever the caller's limit
+        store.acquire("slow:2", &slow()).unwrap();
+        assert_eq!(len(&store), 2);
+    }
+
+    #[test]
+    fn empty_bucket_waits_for_a_token() {
+        let store = MemoryRateLimitStore::new();
+        store.acquire("ip:1", &slow()).unwrap();
+        store.acquire("ip:1", &slow()).unwrap();
+        let retry_after = store.acquire("ip:1", &slow()).unwrap_err();
+        assert!(retry_after > Duration::from_secs(990));
+        // other keys have their own
+        store.acquire("ip:2", &slow()).unwrap();
+    }
+}
@@ -0,0 +1,627 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use axum::{
+    extract::{FromRequestParts, Path, Query as UrlQuery},
+    http::request::Parts,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tracing::{debug, error};
+
+use crate::{
+    DataState,
+    config::PageConfig,
+    error::AuthrError,
+    store::{
+        AsyncStore, Query, Store,
+        error::{StoreError, StoreResult},
+        filter::{Filter, Operator},
+        page::{Page, Sort},
+    },
+    types::{
+        ColumnType, DataObject, DataType, Note, NoteQuery, QueryTypes, RequestNote, RequestObject,
+        RequestUser, User, UserQuery, ValidationError,
+    },
+};
+
+pub const ACTIONS: &[&str] = &["created", "updated", "deleted"];
+
+// A data type served under /data/{name}, with its object, request & query
+// types erased so the handlers & the change feed work on json
+pub trait DataEntry<S>: Send + Sync {
+    fn data_type(&self) -> DataType;
+    // one of the type's named queries, e.g. byGuid, a `field[op]` filter, or a
+    // `filter` expression
+    fn query(&self, name: &str, val: &str) -> Result<QueryTypes, AuthrError>;
+    fn get<'a>(&'a self, store: &'a S, id: i64) -> BoxFuture<'a, StoreResult<Option<Value>>>;
+    fn list<'a>(
+        &'a self,
+        store: &'a S,
+        queries: Vec<QueryTypes>,
+        params: ListParams,
+        config: &PageConfig,
+    ) -> BoxFuture<'a, Result<Listing, AuthrError>>;
+    fn create<'a>(&'a self, store: &'a S, body: &str) -> BoxFuture<'a, Result<Value, AuthrError>>;
+    fn update<'a>(&'a self, store: &'a S, body: &str)
+    -> BoxFuture<'a, Result<Updated, AuthrError>>;
+    fn delete<'a>(&'a self, store: &'a S, id: i64) -> BoxFuture<'a, StoreResult<Value>>;
+}
+
+// How a /data list is sorted & paged, from its query string
+#[derive(Debug, Default, Deserialize)]
+pub struct ListParams {
+    // comma separated columns, descending when prefixed with -
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    // skips rows, for clients that can't follow a cursor
+    pub offset: Option<usize>,
+    // from the previous page's next link
+    pub cursor: Option<String>,
+    // include a count of every row the queries match
+    #[serde(default)]
+    pub count: bool,
+}
+
+// One page of a list
+pub struct Listing {
+    pub items: Vec<Value>,
+    pub total: Option<i64>,
+    // None on the last page
+    pub next: Option<Next>,
+}
+
+// Where the page after a listing starts, in whichever form the request paged by
+pub enum Next {
+    Cursor(String),
+    Offset(usize),
+}
+
+// sort keys a list can be ordered by, besides the id
+const MAX_SORT: usize = 4;
+
+// The page a list request asks for. Lists are ordered by the id after any sort
+// keys, it's unique so the last row of a page says exactly where the next starts.
+fn page<T: DataObject>(params: &ListParams, config: &PageConfig) -> Result<Page, AuthrError> {
+    let mut sort = vec![];
+    for key in params.sort.iter().flat_map(|sort| sort.split(',')) {
+        let (col, descending) = match key.trim().strip_prefix('-') {
+            Some(col) => (col, true),
+            None => (key.trim(), false),
+        };
+        if col.is_empty() {
+            continue;
+        }
+        if !T::filter_cols().iter().any(|(c, _)| *c == col) {
+            return Err(AuthrError::BadRequest(format!("Can't sort on `{}`", col)));
+        }
+        sort.push(Sort {
+            col: col.to_string(),
+            descending,
+        });
+    }
+    if sort.len() > MAX_SORT {
+        return Err(AuthrError::BadRequest(format!(
+            "Can't sort on more than {} columns",
+            MAX_SORT
+        )));
+    }
+    if !sort.iter().any(|s| s.col == T::id_col()) {
+        sort.push(Sort {
+            col: T::id_col(),
+            descending: false,
+        });
+    }
+    let limit = params
+        .limit
+        .unwrap_or(config.default_limit)
+        .min(config.max_limit);
+    if limit == 0 {
+        return Err(AuthrError::BadRequest(
+            "The limit must be at least 1".to_string(),
+        ));
+    }
+    let offset = params.offset.unwrap_or_default();
+    if offset > config.max_offset {
+        return Err(AuthrError::BadRequest(format!(
+            "The offset can't be more than {}",
+            config.max_offset
+        )));
+    }
+    let after = match (&params.cursor, params.offset) {
+        (Some(_), Some(_)) => {
+            return Err(AuthrError::BadRequest(
+                "A cursor and an offset can't be used together".to_string(),
+            ));
+        }
+        (Some(cursor), None) => {
+            let bad_cursor = || AuthrError::BadRequest("Bad cursor".to_string());
+            let key = decode_cursor::<T>(&sort, cursor).ok_or_else(bad_cursor)?;
+            Some(Page::after(&sort, &key).ok_or_else(bad_cursor)?)
+        }
+        _ => None,
+    };
+    Ok(Page {
+        sort,
+        after,
+        limit: Some(limit),
+        offset,
+        count: params.count,
+    })
+}
+
+// A cursor is the sort keys of a page's last row, as a base64 json array
+fn encode_cursor(sort: &[Sort], last: &Value) -> Option<String> {
+    let key = sort
+        .iter()
+        .map(|s| last.get(&s.col).cloned().unwrap_or(Value::Null))
+        .collect::<Vec<_>>();
+    let key = serde_json::to_vec(&key).ok()?;
+    Some(URL_SAFE_NO_PAD.encode(key))
+}
+
+fn decode_cursor<T: DataObject>(sort: &[Sort], cursor: &str) -> Option<Vec<sqlite::Value>> {
+    let key = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    let key = serde_json::from_slice::<Vec<Value>>(&key).ok()?;
+    if key.len() != sort.len() {
+        return None;
+    }
+    sort.iter()
+        .zip(key)
+        .map(|(s, val)| {
+            let (_, col_type) = T::filter_cols().iter().find(|(col, _)| *col == s.col)?;
+            match (val, col_type) {
+                // ids are never null
+                (Value::Null, _) if s.col != T::id_col() => Some(sqlite::Value::Null),
+                (Value::Number(n), ColumnType::Integer) => n.as_i64().map(sqlite::Value::Integer),
+                (Value::Bool(b), ColumnType::Bool) => Some(sqlite::Value::Integer(b as i64)),
+                (Value::String(s), ColumnType::Text) => Some(sqlite::Value::String(s)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// An updated object, as it was & as it is
+pub struct Updated {
+    pub id: i64,
+    // None when it couldn't be read before the update
+    pub before: Option<Value>,
+    pub after: Value,
+}
+
+// a fn pointer so the entry is Send + Sync whatever the types are
+type Types<T, R, Q> = fn() -> (T, R, Q);
+
+struct Entry<T, R, Q> {
+    data_type: DataType,
+    types: PhantomData<Types<T, R, Q>>,
+}
+
+fn json(data: &impl Serialize) -> StoreResult<Value> {
+    serde_json::to_value(data).map_err(|e| StoreError::Decode(e.to_string()))
+}
+
+impl<S, T, R, Q> DataEntry<S> for Entry<T, R, Q>
+where
+    S: Store + AsyncStore,
+    T: DataObject + Serialize,
+    R: RequestObject + DeserializeOwned,
+    Q: Query + 'static + for<'a> TryFrom<(&'a String, &'a String)>,
+{
+    fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    fn query(&self, name: &str, val: &str) -> Result<QueryTypes, AuthrError> {
+        if let Ok(query) = Q::try_from((&name.to_string(), &val.to_string())) {
+            return Ok(QueryTypes::named(query));
+        }
+        if name == "filter" {
+            let filter = Filter::parse(val, T::filter_cols())
+                .map_err(|e| AuthrError::BadRequest(format!("Bad filter: {}", e)))?;
+            return Ok(QueryTypes::Filter(filter));
+        }
+        let (field, op) = name
+            .strip_suffix(']')
+            .and_then(|name| name.split_once('['))
+            .ok_or_else(|| AuthrError::BadRequest(format!("Unknown query `{}`", name)))?;
+        let (field, col_type) = T::filter_cols()
+            .iter()
+            .find(|(col, _)| *col == field)
+            .ok_or_else(|| AuthrError::BadRequest(format!("Can't filter on `{}`", field)))?;
+        let op = op
+            .parse::<Operator>()
+            .map_err(|_| AuthrError::BadRequest(format!("Unknown operator `{}`", op)))?;
+        let filter = Filter::new(field, op, *col_type, val)
+            .map_err(|e| AuthrError::BadRequest(format!("Bad value for `{}`: {}", name, e)))?;
+        Ok(QueryTypes::Filter(filter))
+    }
+
+    fn get<'a>(&'a self, store: &'a S, id: i64) -> BoxFuture<'a, StoreResult<Option<Value>>> {
+        Box::pin(async move {
+            let data: Option<T> = AsyncStore::get(store, id).await?;
+            data.as_ref().map(json).transpose()
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        store: &'a S,
+        queries: Vec<QueryTypes>,
+        params: ListParams,
+        config: &PageConfig,
+    ) -> BoxFuture<'a, Result<Listing, AuthrError>> {
+        let page = page::<T>(&params, config);
+        Box::pin(async move {
+            let page = page?;
+            let (sort, limit) = (page.sort.clone(), page.limit.unwrap_or_default());
+            let paged = AsyncStore::get_page::<T>(store, queries, page).await?;
+            let items = paged
+                .items
+                .iter()
+                .map(json)
+                .collect::<StoreResult<Vec<_>>>()?;
+            // a full page may have more after it
+            let next = match (items.last(), params.offset) {
+                (Some(_), Some(offset)) if items.len() == limit => {
+                    Some(Next::Offset(offset + limit))
+                }
+                (Some(last), None) if items.len() == limit => {
+                    encode_cursor(&sort, last).map(Next::Cursor)
+                }
+                _ => None,
+            };
+            Ok(Listing {
+                items,
+                total: paged.total,
+                next,
+            })
+        })
+    }
+
+    fn create<'a>(&'a self, store: &'a S, body: &str) -> BoxFuture<'a, Result<Value, AuthrError>> {
+        let payload = serde_json::from_str::<R>(body);
+        Box::pin(async move {
+            let payload = payload.map_err(|e| AuthrError::BadRequest(e.to_string()))?;
+            payload.validate_create()?;
+            let data = AsyncStore::create::<_, T>(store, payload).await?;
+            Ok(json(&data)?)
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        store: &'a S,
+        body: &str,
+    ) -> BoxFuture<'a, Result<Updated, AuthrError>> {
+        let payload = serde_json::from_str::<R>(body);
+        Box::pin(async move {
+            let payload = payload.map_err(|e| AuthrError::BadRequest(e.to_string()))?;
+            payload.validate_update()?;
+            let id = payload.id().ok_or(ValidationError::MissingIdOnUpdate)?;
+            // the audit record goes without a before when it can't be read
+            let before = self.get(store, id).await.unwrap_or_else(|e| {
+                error!("{:?}", e);
+                None
+            });
+            let data = AsyncStore::update::<_, T>(store, payload).await?;
+            Ok(Updated {
+                id,
+                before,
+                after: json(&data)?,
+            })
+        })
+    }
+
+    fn delete<'a>(&'a self, store: &'a S, id: i64) -> BoxFuture<'a, StoreResult<Value>> {
+        Box::pin(async move {
+            let data = AsyncStore::delete::<T>(store, id).await?;
+            json(&data)
+        })
+    }
+}
+
+// The data types served under /data, user & note to begin with
+pub struct Registry<S> {
+    entries: HashMap<&'static str, Arc<dyn DataEntry<S>>>,
+}
+
+impl<S> Clone for Registry<S> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<S: Store + AsyncStore> Default for Registry<S> {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<User, RequestUser, UserQuery>(DataType::USER);
+        registry.register::<Note, RequestNote, NoteQuery>(DataType::NOTE);
+        registry
+    }
+}
+
+impl<S> Registry<S> {
+    pub fn empty() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    // None for a type that was never registered
+    pub fn get(&self, name: &str) -> Option<Arc<dyn DataEntry<S>>> {
+        self.entries.get(name).cloned()
+    }
+
+    // the type behind a /data/{type} path, unknown ones aren't found
+    pub fn entry(&self, name: &str) -> Result<Arc<dyn DataEntry<S>>, AuthrError> {
+        self.get(name).ok_or(AuthrError::NotFound)
+    }
+
+    // every webhook event the registered types emit, sorted
+    pub fn events(&self) -> Vec<String> {
+        let mut events = self
+            .entries
+            .keys()
+            .flat_map(|name| {
+                ACTIONS
+                    .iter()
+                    .map(move |action| format!("{}.{}", name, action))
+            })
+            .collect::<Vec<_>>();
+        events.sort();
+        events
+    }
+}
+
+impl<S: Store + AsyncStore> Registry<S> {
+    // serves `T` under /data/{name}, replacing any type registered there before.
+    // `R` is what create & update accept, `Q` parses the url's query parameters
+    pub fn register<T, R, Q>(&mut self, data_type: DataType) -> &mut Self
+    where
+        T: DataObject + Serialize,
+        R: RequestObject + DeserializeOwned,
+        Q: Query + 'static + for<'a> TryFrom<(&'a String, &'a String)>,
+    {
+        let entry: Entry<T, R, Q> = Entry {
+            data_type,
+            types: PhantomData,
+        };
+        self.entries.insert(data_type.name(), Arc::new(entry));
+        self
+    }
+}
+
+// parameters the handlers read themselves
+const NOT_QUERIES: &[&str] = &["lastEventId", "sort", "limit", "offset", "cursor", "count"];
+
+// The queries in a /data/{type} url, a parameter that isn't one of the type's
+// queries is rejected
+pub struct ExtractGlonkQueries(pub Vec<QueryTypes>);
+
+impl<S: Store> FromRequestParts<Arc<DataState<S>>> for ExtractGlonkQueries {
+    type Rejection = AuthrError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<DataState<S>>,
+    ) -> Result<Self, Self::Rejection> {
+        let queries = UrlQuery::<HashMap<String, String>>::from_request_parts(parts, state).await?;
+        let Path(data_type) = Path::<String>::from_request_parts(parts, state).await?;
+        let entry = state.registry.entry(&data_type)?;
+        let glonk_queries = queries
+            .iter()
+            .filter(|(k, _)| !NOT_QUERIES.contains(&k.as_str()))
+            .map(|(k, v)| entry.query(k, v))
+            .collect::<Result<Vec<QueryTypes>, AuthrError>>()?;
+        debug!("{:?}", queries);
+        Ok(Self(glonk_queries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::{SqliteStore, config::SqliteConfig, store::migrations};
+
+    // names with ties & nulls, ids 1 to 7
+    const NAMES: &[Option<&str>] = &[
+        Some("b"),
+        None,
+        Some("a"),
+        Some("b"),
+        None,
+        Some("a"),
+        Some("c"),
+    ];
+
+    fn users() -> SqliteStore {
+        let store = SqliteStore::new(&SqliteConfig {
+            path: ":memory:".to_string(),
+            ..SqliteConfig::default()
+        })
+        .unwrap();
+        migrations::migrate(&store).unwrap();
+        for (i, name) in NAMES.iter().enumerate() {
+            let user = RequestUser {
+                guid: Some(format!("google/{}", i + 1)),
+                name: name.map(str::to_string),
+                email: Some(format!("{}@b.c", i + 1)),
+                ..Default::default()
+            };
+            Store::create::<_, User>(&store, user).unwrap();
+        }
+        store
+    }
+
+    fn params(sort: &str, limit: usize, cursor: Option<String>) -> ListParams {
+        ListParams {
+            sort: Some(sort.to_string()),
+            limit: Some(limit),
+            cursor,
+            ..Default::default()
+        }
+    }
+
+    // the ids of every page, following the cursors
+    async fn walk(store: &SqliteStore, sort: &str, limit: usize) -> Vec<i64> {
+        let entry = Registry::<SqliteStore>::default().entry("user").unwrap();
+        let mut ids = vec![];
+        let mut cursor = None;
+        loop {
+            let listing = entry
+                .list(
+                    store,
+                    vec![],
+                    params(sort, limit, cursor),
+                    &PageConfig::default(),
+                )
+                .await
+                .unwrap();
+            assert!(listing.items.len() <= limit);
+            ids.extend(
+                listing
+                    .items
+                    .iter()
+                    .map(|item| item["id"].as_i64().unwrap()),
+            );
+            match listing.next {
+                Some(Next::Cursor(next)) => cursor = Some(next),
+                Some(Next::Offset(_)) => panic!("paged by offset"),
+                None => return ids,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursors_walk_every_row_once() {
+        let store = users();
+        let sorts = [
+            ("name", vec![2, 5, 3, 6, 1, 4, 7]),
+            ("-name", vec![7, 1, 4, 3, 6, 2, 5]),
+            ("-name,-id", vec![7, 4, 1, 6, 3, 5, 2]),
+            ("name,-email", vec![5, 2, 6, 3, 4, 1, 7]),
+            ("", (1..=7).collect()),
+        ];
+        for (sort, expected) in sorts {
+            for limit in 1..=NAMES.len() + 1 {
+                assert_eq!(
+                    walk(&store, sort, limit).await,
+                    expected,
+                    "sort={} limit={}",
+                    sort,
+                    limit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let sort = page::<User>(&params("-name,email", 1, None), &PageConfig::default())
+            .unwrap()
+            .sort;
+        for name in [Value::Null, Value::from("o'brien \"%_")] {
+            let last = serde_json::json!({ "id": 9, "name": name, "email": "e" });
+            let cursor = encode_cursor(&sort, &last).unwrap();
+            let expected = vec![
+                match &name {
+                    Value::String(s) => sqlite::Value::String(s.clone()),
+                    _ => sqlite::Value::Null,
+                },
+                sqlite::Value::String("e".to_string()),
+                sqlite::Value::Integer(9),
+            ];
+            assert_eq!(decode_cursor::<User>(&sort, &cursor), Some(expected));
+        }
+    }
+
+    fn status(params: ListParams) -> StatusCode {
+        match page::<User>(&params, &PageConfig::default()) {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    #[test]
+    fn tampered_cursors_are_bad_requests() {
+        let cursor = |key: &str| URL_SAFE_NO_PAD.encode(key);
+        let tampered = [
+            "not base64!".to_string(),
+            cursor("not json"),
+            cursor("{\"name\": \"a\", \"id\": 1}"),
+            // a key too short or too long for the sort
+            cursor("[1]"),
+            cursor("[\"a\", 1, 2]"),
+            // the wrong type for the column
+            cursor("[\"a\", \"1\"]"),
+            cursor("[1, 1]"),
+            cursor("[\"a\", 1.5]"),
+            // the id can't be null, or nothing would follow it
+            cursor("[\"a\", null]"),
+        ];
+        assert_eq!(
+            status(params("name", 10, Some(cursor("[\"a\", 1]")))),
+            StatusCode::OK
+        );
+        for cursor in tampered {
+            assert_eq!(
+                status(params("name", 10, Some(cursor.clone()))),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                cursor
+            );
+        }
+    }
+
+    #[test]
+    fn bad_list_params_are_bad_requests() {
+        let config = PageConfig::default();
+        let offset = |offset| ListParams {
+            offset: Some(offset),
+            ..Default::default()
+        };
+        assert_eq!(status(offset(config.max_offset)), StatusCode::OK);
+        assert_eq!(
+            status(offset(config.max_offset + 1)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(offset(usize::MAX)), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(ListParams {
+                offset: Some(1),
+                ..params("name", 10, Some(URL_SAFE_NO_PAD.encode("[\"a\", 1]")))
+            }),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(params("name", 0, None)), StatusCode::BAD_REQUEST);
+        assert_eq!(status(params("nope", 10, None)), StatusCode::BAD_REQUEST);
+        // internal columns can't be sorted on either
+        assert_eq!(
+            status(params("known_devices", 10, None)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(params("name,email,guid,picture", 10, None)),
+            StatusCode::OK
+        );
+        match page::<User>(
+            &params("id,name,email,guid,picture", 10, None),
+            &PageConfig::default(),
+        ) {
+            Err(AuthrError::BadRequest(message)) => {
+                assert_eq!(
+                    message,
+                    format!("Can't sort on more than {} columns", MAX_SORT)
+                )
+            }
+            other => panic!("expected too many sort keys, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use sqlite::{Bindable, Connection, Value};
+
+use crate::types::{DataObject, RequestObject};
+
+use super::{
+    Row,
+    error::{StoreError, StoreResult},
+};
+
+// Data objects are read & written through sqlite statements, so stores that
+// don't run on sqlite pass their rows through a connection without any tables
+// to convert them to & from values.
+pub(crate) struct Codec {
+    conn: Connection,
+}
+
+impl Codec {
+    pub(crate) fn new() -> StoreResult<Self> {
+        Ok(Self {
+            conn: sqlite::open(":memory:")?,
+        })
+    }
+
+    // the values a request sets, by column
+    pub(crate) fn request_row<R: RequestObject>(&self, data: R) -> StoreResult<Row> {
+        let cols = data.sql_cols();
+        if cols.is_empty() {
+            return Ok(Row::new());
+        }
+        let placeholders = data.sql_placeholders();
+        self.bound_row(&cols, &placeholders, data)
+    }
+
+    // every column of an object, as it reads them back
+    pub(crate) fn row<T: DataObject>(&self, data: T) -> StoreResult<Row> {
+        let cols = T::sql_cols();
+        let placeholders = vec!["?"; cols.split(',').count()].join(",");
+        self.bound_row(&cols, &placeholders, data)
+    }
+
+    fn bound_row(&self, cols: &str, placeholders: &str, data: impl Bindable) -> StoreResult<Row> {
+        let mut row = Row::new();
+        let mut statement = self.conn.prepare(format!("SELECT {}", placeholders))?;
+        statement.bind(data)?;
+        statement.next()?;
+        for (i, col) in cols.split(',').enumerate() {
+            row.insert(
+                col.to_string(),
+                statement.read::<Value, _>(i).map_err(StoreError::decode)?,
+            );
+        }
+        Ok(row)
+    }
+
+    pub(crate) fn object<T: DataObject>(&self, row: &Row) -> StoreResult<T> {
+        let cols = T::sql_cols();
+        let select = cols
+            .split(',')
+            .map(|col| format!("? AS {}", col))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let values = cols
+            .split(',')
+            .enumerate()
+            .map(|(i, col)| (i + 1, row.get(col).cloned().unwrap_or(Value::Null)))
+            .collect::<Vec<(usize, Value)>>();
+        let mut statement = self.conn.prepare(format!("SELECT {}", select))?;
+        statement.bind::<&[(_, Value)]>(values.as_slice())?;
+        T::from_rows(&mut statement)?
+            .pop()
+            .ok_or(StoreError::Decode(format!("no {} row", T::table_name())))
+    }
+}
+
+// a json snapshot of a data object as a row
+pub(crate) fn json_row(snapshot: &str) -> Option<Row> {
+    value_row(serde_json::from_str(snapshot).ok()?)
+}
+
+// & one that's already parsed
+pub(crate) fn value_row(snapshot: serde_json::Value) -> Option<Row> {
+    let object = match snapshot {
+        serde_json::Value::Object(object) => object,
+        _ => return None,
+    };
+    let row = object
+        .into_iter()
+        .map(|(col, val)| {
+            let val = match val {
+                serde_json::Value::Null => Value::Null,
+                serde_json::Value::Bool(b) => Value::Integer(b as i64),
+                serde_json::Value::Number(n) => match n.as_i64() {
+                    Some(i) => Value::Integer(i),
+                    None => Value::Float(n.as_f64().unwrap_or_default()),
+                },
+                serde_json::Value::String(s) => Value::String(s),
+                val => Value::String(val.to_string()),
+            };
+            (col, val)
+        })
+        .collect();
+    Some(row)
+}
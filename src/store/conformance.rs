@@ -0,0 +1,696 @@
+// The behaviour every `Store` has to share, run against each backend. Rows are
+// tagged per run & every query is scoped to the tag, so a shared postgres
+// database with data of its own can be used. Postgres runs only when
+// AUTHRS_TEST_POSTGRES is set, connecting with DB_HOST, DB_USER & DB_PASS.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlite::Value;
+
+use super::{
+    Store,
+    error::StoreError,
+    filter::Filter,
+    page::{Page, Sort},
+};
+use crate::{
+    MemStore, SqliteStore,
+    config::SqliteConfig,
+    store::migrations,
+    types::{
+        DataObject, Note, NoteQuery, QueryTypes, RequestNote, RequestObject, RequestServiceAccount,
+        RequestUser, ServiceAccount, User, UserByGuid, UserFailures, UserQuery, ValidationError,
+    },
+};
+
+// names whose bytewise order isn't their dictionary order
+const CASED_NAMES: &[Option<&str>] = &[Some("b"), Some("B"), Some("a"), Some("A"), Some("_")];
+
+// names with ties
+const NAMES: &[Option<&str>] = &[
+    Some("b"),
+    Some("d"),
+    Some("a"),
+    Some("b"),
+    Some("d"),
+    Some("a"),
+    Some("c"),
+];
+
+// & with nulls, which the memory store can't hold in a required column
+const NULL_NAMES: &[Option<&str>] = &[
+    Some("b"),
+    None,
+    Some("a"),
+    Some("b"),
+    None,
+    Some("a"),
+    Some("c"),
+];
+
+fn tag() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("conformance/{}", nanos)
+}
+
+fn user(tag: &str) -> RequestUser {
+    RequestUser {
+        guid: Some(format!("{}/1", tag)),
+        name: Some("a".to_string()),
+        email: Some("a@a.c".to_string()),
+        picture: Some(String::new()),
+        ..Default::default()
+    }
+}
+
+fn users<S: Store>(store: &S, tag: &str, names: &[Option<&str>]) -> Vec<User> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let user = RequestUser {
+                guid: Some(format!("{}/{}", tag, i)),
+                name: name.map(str::to_string),
+                email: Some(format!("{}@b.c", i)),
+                picture: Some(String::new()),
+                ..Default::default()
+            };
+            store.create::<_, User>(user).unwrap()
+        })
+        .collect()
+}
+
+fn clean_up<S: Store, T: DataObject>(store: &S, ids: impl IntoIterator<Item = i64>) {
+    for id in ids {
+        let _ = store.delete::<T>(id);
+    }
+}
+
+fn filter<T: DataObject>(expression: &str) -> QueryTypes {
+    QueryTypes::Filter(Filter::parse(expression, T::filter_cols()).unwrap())
+}
+
+fn tagged(tag: &str) -> QueryTypes {
+    filter::<User>(&format!("guid startsWith \"{}/\"", tag))
+}
+
+fn ids(users: &[User]) -> Vec<i64> {
+    users.iter().map(|u| u.id).collect()
+}
+
+fn notes(notes: &[Note]) -> Vec<i64> {
+    notes.iter().map(|n| n.id).collect()
+}
+
+fn crud<S: Store>(store: &S) {
+    let tag = tag();
+    let user = store.create::<_, User>(user(&tag)).unwrap();
+    assert_eq!(user.guid, format!("{}/1", tag));
+    assert_eq!(
+        (user.name.as_deref(), user.email.as_deref()),
+        (Some("a"), Some("a@a.c"))
+    );
+    // columns the request leaves out take their defaults
+    assert_eq!((user.failed_logins, user.known_devices.as_str()), (0, ""));
+
+    let got = store.get::<User>(user.id).unwrap().unwrap();
+    assert_eq!((got.id, got.guid), (user.id, user.guid.clone()));
+
+    let updated = store
+        .update::<_, User>(RequestUser {
+            id: Some(user.id),
+            email: Some("a@b.c".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+    // only the fields set change
+    assert_eq!(updated.name.as_deref(), Some("a"));
+    assert_eq!(updated.email.as_deref(), Some("a@b.c"));
+
+    let deleted = store.delete::<User>(user.id).unwrap();
+    assert_eq!(deleted.email.as_deref(), Some("a@b.c"));
+    assert!(store.get::<User>(user.id).unwrap().is_none());
+    assert!(matches!(
+        store.delete::<User>(user.id),
+        Err(StoreError::NotFound)
+    ));
+    let missing = RequestUser {
+        id: Some(user.id),
+        name: Some("b".to_string()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        store.update::<_, User>(missing),
+        Err(StoreError::NotFound)
+    ));
+
+    // the deleted row had the highest id, it isn't handed out again
+    let next = store.create::<_, User>(self::user(&tag)).unwrap();
+    assert!(next.id > user.id);
+    clean_up::<_, User>(store, [next.id]);
+}
+
+// the sql stores leave the request's checks to the caller & go by the schema,
+// a missing NOT NULL column is refused by every store
+fn validation<S: Store>(store: &S) {
+    let no_guid = RequestUser {
+        name: Some("a".to_string()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        store.create::<_, User>(no_guid),
+        Err(StoreError::Constraint(_))
+    ));
+}
+
+fn queries<S: Store>(store: &S) {
+    let tag = tag();
+    let users = users(store, &tag, NAMES);
+    let found = |queries: Vec<QueryTypes>| found(store, queries);
+    let named = |indexes: &[usize]| named(&users, indexes);
+
+    assert_eq!(found(vec![tagged(&tag)]), ids(&users));
+    let guid = QueryTypes::named(UserQuery::ByGuid(UserByGuid::new(format!("{}/3", tag))));
+    assert_eq!(found(vec![guid]), named(&[3]));
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("name eq b")]),
+        named(&[0, 3])
+    );
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("name isNull true")]),
+        named(&[])
+    );
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("not name lt c")]),
+        named(&[1, 4, 6])
+    );
+    assert_eq!(
+        found(vec![
+            tagged(&tag),
+            filter::<User>("name in (a, c) or email like \"1@%\""),
+        ]),
+        named(&[1, 2, 5, 6])
+    );
+    assert_eq!(
+        found(vec![
+            tagged(&tag),
+            filter::<User>(&format!("id gte {} and id lt {}", users[2].id, users[4].id)),
+        ]),
+        named(&[2, 3])
+    );
+    // LIKE wildcards in a contains are taken literally
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("email contains \"%\"")]),
+        named(&[])
+    );
+
+    let note = store
+        .create::<_, Note>(RequestNote {
+            owner_id: Some(users[0].id),
+            contents: Some(format!("{} 100% Done", tag)),
+            ..Default::default()
+        })
+        .unwrap();
+    let contains = |val: &str| {
+        let query =
+            NoteQuery::try_from((&"byContentsContains".to_string(), &val.to_string())).unwrap();
+        notes(
+            &store
+                .get_queries::<Note>(vec![QueryTypes::named(query)])
+                .unwrap(),
+        )
+    };
+    // case insensitive
+    assert_eq!(contains(&format!("{} 100% done", tag)), vec![note.id]);
+    assert_eq!(contains(&format!("{} 1000", tag)), Vec::<i64>::new());
+
+    let snapshot = serde_json::to_string(&users[0]).unwrap();
+    assert!(
+        store
+            .matches::<User>(&snapshot, &[filter::<User>("name eq b")])
+            .unwrap()
+    );
+    assert!(
+        !store
+            .matches::<User>(&snapshot, &[filter::<User>("name isNull true")])
+            .unwrap()
+    );
+
+    clean_up::<_, Note>(store, [note.id]);
+    clean_up::<_, User>(store, ids(&users));
+}
+
+fn found<S: Store>(store: &S, queries: Vec<QueryTypes>) -> Vec<i64> {
+    let mut found = ids(&store.get_queries::<User>(queries).unwrap());
+    found.sort();
+    found
+}
+
+fn named(users: &[User], indexes: &[usize]) -> Vec<i64> {
+    indexes.iter().map(|i| users[*i].id).collect()
+}
+
+// the memory store can't hold them, see `NULL_NAMES`
+fn nulls<S: Store>(store: &S) {
+    let tag = tag();
+    let users = users(store, &tag, NULL_NAMES);
+    let found = |queries: Vec<QueryTypes>| found(store, queries);
+    let named = |indexes: &[usize]| named(&users, indexes);
+
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("name isNull true")]),
+        named(&[1, 4])
+    );
+    // a comparison with null is false, & its negation true
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("name gte b")]),
+        named(&[0, 3, 6])
+    );
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("not name lt b")]),
+        named(&[0, 1, 3, 4, 6])
+    );
+    assert_eq!(
+        found(vec![tagged(&tag), filter::<User>("name ne b")]),
+        named(&[2, 5, 6])
+    );
+    let snapshot = serde_json::to_string(&users[1]).unwrap();
+    assert!(
+        store
+            .matches::<User>(&snapshot, &[filter::<User>("name isNull true")])
+            .unwrap()
+    );
+
+    walk(store, &tag, &users);
+
+    // a null bound to a column that isn't text
+    let note = store
+        .create::<_, Note>(RequestNote {
+            owner_id: Some(users[0].id),
+            contents: Some(tag.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+    store.update::<_, Note>(Disowned(note.id)).unwrap();
+    let disowned = store
+        .get_queries::<Note>(vec![
+            filter::<Note>(&format!("contents eq \"{}\"", tag)),
+            filter::<Note>("owner_id isNull true"),
+        ])
+        .unwrap();
+    assert_eq!(notes(&disowned), vec![note.id]);
+
+    clean_up::<_, Note>(store, [note.id]);
+    clean_up::<_, User>(store, ids(&users));
+}
+
+// a request that clears a note's owner
+#[derive(Debug, Clone)]
+struct Disowned(i64);
+
+impl sqlite::Bindable for Disowned {
+    fn bind(self, statement: &mut sqlite::Statement) -> sqlite::Result<()> {
+        statement.bind((1, Value::Null))
+    }
+}
+
+impl RequestObject for Disowned {
+    fn validate_create(&self) -> Result<(), ValidationError> {
+        Err(ValidationError::IdProvidedOnCreate)
+    }
+
+    fn validate_update(&self) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    fn sql_cols(&self) -> String {
+        "owner_id".to_string()
+    }
+
+    fn sql_placeholders(&self) -> String {
+        "?".to_string()
+    }
+
+    fn id(&self) -> Option<i64> {
+        Some(self.0)
+    }
+}
+
+// text sorts & compares bytewise in every store, whatever the database's
+// collation, so cursors page the same everywhere
+fn collation<S: Store>(store: &S) {
+    let tag = tag();
+    let users = users(store, &tag, CASED_NAMES);
+    walk(store, &tag, &users);
+    assert_eq!(
+        found(store, vec![tagged(&tag), filter::<User>("name lt a")]),
+        named(&users, &[1, 3, 4])
+    );
+    clean_up::<_, User>(store, ids(&users));
+}
+
+// a request that sets no columns
+#[derive(Debug, Clone)]
+struct Untouched(i64);
+
+impl sqlite::Bindable for Untouched {
+    fn bind(self, _: &mut sqlite::Statement) -> sqlite::Result<()> {
+        Ok(())
+    }
+}
+
+impl RequestObject for Untouched {
+    fn validate_create(&self) -> Result<(), ValidationError> {
+        Err(ValidationError::IdProvidedOnCreate)
+    }
+
+    fn validate_update(&self) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    fn sql_cols(&self) -> String {
+        String::new()
+    }
+
+    fn sql_placeholders(&self) -> String {
+        String::new()
+    }
+
+    fn id(&self) -> Option<i64> {
+        Some(self.0)
+    }
+}
+
+fn update_if<S: Store>(store: &S) {
+    let tag = tag();
+    let user = store.create::<_, User>(user(&tag)).unwrap();
+    let failure = |failed_logins: i64| RequestUser {
+        id: Some(user.id),
+        failed_logins: Some(failed_logins + 1),
+        ..Default::default()
+    };
+    let unchanged = |failed_logins: i64| {
+        QueryTypes::named(UserQuery::Failures(UserFailures::new(failed_logins, 0)))
+    };
+
+    let updated = store
+        .update_if::<_, User>(failure(0), vec![unchanged(0)])
+        .unwrap()
+        .unwrap();
+    assert_eq!(updated.failed_logins, 1);
+    // a second writer that read the same row loses
+    assert!(
+        store
+            .update_if::<_, User>(failure(0), vec![unchanged(0)])
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(
+        store.get::<User>(user.id).unwrap().unwrap().failed_logins,
+        1
+    );
+    // one whose checks still hold goes through, even with nothing to set
+    let touched = RequestUser {
+        id: Some(user.id),
+        ..Default::default()
+    };
+    assert_eq!(
+        store
+            .update_if::<_, User>(touched, vec![unchanged(1)])
+            .unwrap()
+            .map(|u| u.failed_logins),
+        Some(1)
+    );
+
+    // & with nothing at all, not even the id
+    assert_eq!(
+        store
+            .update_if::<_, User>(Untouched(user.id), vec![unchanged(1)])
+            .unwrap()
+            .map(|u| u.id),
+        Some(user.id)
+    );
+    assert!(
+        store
+            .update_if::<_, User>(Untouched(user.id), vec![unchanged(0)])
+            .unwrap()
+            .is_none()
+    );
+
+    store.delete::<User>(user.id).unwrap();
+    assert!(
+        store
+            .update_if::<_, User>(failure(1), vec![])
+            .unwrap()
+            .is_none()
+    );
+}
+
+// the keys `Page::after` continues from
+fn key(sort: &[Sort], user: &User) -> Vec<Value> {
+    sort.iter()
+        .map(|s| match s.col.as_str() {
+            "name" => user.name.clone().map(Value::String).unwrap_or(Value::Null),
+            "id" => Value::Integer(user.id),
+            col => panic!("no key for {}", col),
+        })
+        .collect()
+}
+
+fn sort(descending: bool) -> Vec<Sort> {
+    vec![
+        Sort {
+            col: "name".to_string(),
+            descending,
+        },
+        Sort {
+            col: "id".to_string(),
+            descending: false,
+        },
+    ]
+}
+
+// `users` read by name both ways, whole & with cursors
+fn walk<S: Store>(store: &S, tag: &str, users: &[User]) {
+    // nulls first ascending & last descending, ties by id
+    let order = |descending: bool| {
+        let mut sorted = users.to_vec();
+        sorted.sort_by(|l, r| {
+            let by_name = match (&l.name, &r.name) {
+                (None, None) => std::cmp::Ordering::Equal,
+                (None, Some(_)) => std::cmp::Ordering::Less,
+                (Some(_), None) => std::cmp::Ordering::Greater,
+                (Some(l), Some(r)) => l.cmp(r),
+            };
+            match descending {
+                false => by_name,
+                true => by_name.reverse(),
+            }
+            .then(l.id.cmp(&r.id))
+        });
+        ids(&sorted)
+    };
+
+    let all = store
+        .get_page::<User>(vec![tagged(tag)], Page::default())
+        .unwrap();
+    assert_eq!(ids(&all.items), ids(users));
+    assert_eq!(all.total, None);
+
+    for descending in [false, true] {
+        let sort = sort(descending);
+        let page = store
+            .get_page::<User>(
+                vec![tagged(tag)],
+                Page {
+                    sort: sort.clone(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(ids(&page.items), order(descending));
+
+        // walked with cursors, every row comes once
+        let mut walked = vec![];
+        let mut after = None;
+        loop {
+            let page = store
+                .get_page::<User>(
+                    vec![tagged(tag)],
+                    Page {
+                        sort: sort.clone(),
+                        after,
+                        limit: Some(2),
+                        count: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            // counted whatever the cursor
+            assert_eq!(page.total, Some(users.len() as i64));
+            let last = match page.items.last() {
+                Some(last) => last,
+                None => break,
+            };
+            after = Page::after(&sort, &key(&sort, last));
+            walked.extend(ids(&page.items));
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(walked, order(descending));
+    }
+}
+
+fn paging<S: Store>(store: &S) {
+    let tag = tag();
+    let users = users(store, &tag, NAMES);
+    walk(store, &tag, &users);
+
+    let page = store
+        .get_page::<User>(
+            vec![tagged(&tag), filter::<User>("name ne d")],
+            Page {
+                sort: sort(false),
+                limit: Some(2),
+                offset: 3,
+                count: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        ids(&page.items),
+        vec![users[3].id, users[6].id],
+        "a, a, b, then b & c"
+    );
+    assert_eq!(page.total, Some(5));
+
+    let empty = store
+        .get_page::<User>(
+            vec![tagged(&tag)],
+            Page {
+                offset: users.len(),
+                count: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert!(empty.items.is_empty());
+    assert_eq!(empty.total, Some(users.len() as i64));
+
+    clean_up::<_, User>(store, ids(&users));
+}
+
+// the memory store doesn't enforce unique constraints
+fn unique<S: Store>(store: &S) {
+    let client_id = tag();
+    let account = || RequestServiceAccount {
+        client_id: Some(client_id.clone()),
+        secret_hash: Some("hash".to_string()),
+        name: Some("sa".to_string()),
+        scopes: Some("notes:read".to_string()),
+        ..Default::default()
+    };
+    let first = store.create::<_, ServiceAccount>(account()).unwrap();
+    assert!(matches!(
+        store.create::<_, ServiceAccount>(account()),
+        Err(StoreError::Conflict(_))
+    ));
+    let other = store
+        .create::<_, ServiceAccount>(RequestServiceAccount {
+            client_id: Some(format!("{}/other", client_id)),
+            ..account()
+        })
+        .unwrap();
+    let taken = RequestServiceAccount {
+        id: Some(other.id),
+        client_id: Some(client_id.clone()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        store.update::<_, ServiceAccount>(taken),
+        Err(StoreError::Conflict(_))
+    ));
+    clean_up::<_, ServiceAccount>(store, [first.id, other.id]);
+}
+
+macro_rules! conformance {
+    ($name:ident, $store:expr $(, $test:ident)*) => {
+        mod $name {
+            use super::*;
+
+            $(
+                #[test]
+                fn $test() {
+                    match $store {
+                        Some(store) => super::$test(&store),
+                        None => eprintln!("AUTHRS_TEST_POSTGRES isn't set, skipped"),
+                    }
+                }
+            )*
+        }
+    };
+}
+
+fn sqlite() -> Option<SqliteStore> {
+    let store = SqliteStore::new(&SqliteConfig {
+        path: ":memory:".to_string(),
+        ..SqliteConfig::default()
+    })
+    .unwrap();
+    migrations::migrate(&store).unwrap();
+    Some(store)
+}
+
+#[cfg(feature = "postgres")]
+fn postgres() -> Option<super::PgStore> {
+    std::env::var("AUTHRS_TEST_POSTGRES").ok()?;
+    let db = crate::config::DbConfig {
+        host: std::env::var("DB_HOST").unwrap_or("127.0.0.1".to_string()),
+        user: std::env::var("DB_USER").unwrap_or("postgres".to_string()),
+        pass: std::env::var("DB_PASS").unwrap_or_default(),
+        pool_size: 2,
+    };
+    let store = super::PgStore::new(&db).unwrap();
+    migrations::migrate(&store).unwrap();
+    Some(store)
+}
+
+conformance!(
+    memstore,
+    Some(MemStore::new().unwrap()),
+    crud,
+    validation,
+    queries,
+    update_if,
+    paging,
+    collation
+);
+conformance!(
+    sqlitestore,
+    sqlite(),
+    crud,
+    validation,
+    queries,
+    update_if,
+    paging,
+    collation,
+    nulls,
+    unique
+);
+#[cfg(feature = "postgres")]
+conformance!(
+    pgstore,
+    postgres(),
+    crud,
+    validation,
+    queries,
+    update_if,
+    paging,
+    collation,
+    nulls,
+    unique
+);
@@ -1,22 +1,70 @@
+use axum::response::IntoResponse;
 use std::error::Error;
 use std::fmt;
+use std::sync::PoisonError;
+
+use crate::error::AuthrError;
 
 // Store error kinds
 #[derive(Debug)]
 pub enum StoreError {
+    // the statement ran but handed back no row
     NotCreated,
     NotFound,
+    // a NOT NULL, CHECK or foreign key violation, or a missing required field
+    Constraint(String),
+    // a UNIQUE violation
+    Conflict(String),
+    // a row that couldn't be read into its data object
+    Decode(String),
+    // the database couldn't be reached, stayed busy or the caller went away
+    Unavailable(String),
+    // a panic while a connection was held
+    Poisoned,
+    // any other database error
+    Query(String),
+}
+
+impl StoreError {
+    // for failed column reads, the statement itself ran fine
+    pub fn decode(e: sqlite::Error) -> Self {
+        StoreError::Decode(e.message.unwrap_or_default())
+    }
+}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> axum::response::Response {
+        AuthrError::from(self).into_response()
+    }
 }
 
 impl fmt::Display for StoreError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
+        match self {
             StoreError::NotCreated => {
                 write!(fmt, "The data could not be created",)
             }
             StoreError::NotFound => {
                 write!(fmt, "The data could not be found",)
             }
+            StoreError::Constraint(e) => {
+                write!(fmt, "The data violates a constraint: {}", e)
+            }
+            StoreError::Conflict(e) => {
+                write!(fmt, "The data conflicts with existing data: {}", e)
+            }
+            StoreError::Decode(e) => {
+                write!(fmt, "The data could not be read: {}", e)
+            }
+            StoreError::Unavailable(e) => {
+                write!(fmt, "The database is unavailable: {}", e)
+            }
+            StoreError::Poisoned => {
+                write!(fmt, "The database connection was poisoned",)
+            }
+            StoreError::Query(e) => {
+                write!(fmt, "The query failed: {}", e)
+            }
         }
     }
 }
@@ -26,13 +74,72 @@ impl Error for StoreError {
         match *self {
             StoreError::NotCreated => "NotCreated error",
             StoreError::NotFound => "NotFound error",
+            StoreError::Constraint(_) => "Constraint error",
+            StoreError::Conflict(_) => "Conflict error",
+            StoreError::Decode(_) => "Decode error",
+            StoreError::Unavailable(_) => "Unavailable error",
+            StoreError::Poisoned => "Poisoned error",
+            StoreError::Query(_) => "Query error",
         }
     }
 
     fn cause(&self) -> Option<&dyn Error> {
-        match *self {
-            StoreError::NotCreated => None,
-            StoreError::NotFound => None,
+        None
+    }
+}
+
+// sqlite only reports primary result codes
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+const SQLITE_CANTOPEN: isize = 14;
+const SQLITE_CONSTRAINT: isize = 19;
+
+impl From<sqlite::Error> for StoreError {
+    fn from(e: sqlite::Error) -> Self {
+        let message = e.message.unwrap_or_default();
+        match e.code {
+            Some(SQLITE_BUSY | SQLITE_LOCKED | SQLITE_CANTOPEN) => StoreError::Unavailable(message),
+            // primary keys report as UNIQUE too
+            Some(SQLITE_CONSTRAINT) if message.starts_with("UNIQUE") => {
+                StoreError::Conflict(message)
+            }
+            Some(SQLITE_CONSTRAINT) => StoreError::Constraint(message),
+            _ => StoreError::Query(message),
+        }
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(e: r2d2::Error) -> Self {
+        StoreError::Unavailable(e.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for StoreError {
+    fn from(_: PoisonError<T>) -> Self {
+        StoreError::Poisoned
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for StoreError {
+    fn from(e: postgres::Error) -> Self {
+        use postgres::error::SqlState;
+
+        let message = e.to_string();
+        match e.code() {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION => StoreError::Conflict(message),
+            // RAISE EXCEPTION is how the audit log refuses changes
+            Some(code)
+                if *code == SqlState::NOT_NULL_VIOLATION
+                    || *code == SqlState::FOREIGN_KEY_VIOLATION
+                    || *code == SqlState::CHECK_VIOLATION
+                    || *code == SqlState::RAISE_EXCEPTION =>
+            {
+                StoreError::Constraint(message)
+            }
+            None if e.is_closed() => StoreError::Unavailable(message),
+            _ => StoreError::Query(message),
         }
     }
 }
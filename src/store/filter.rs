@@ -0,0 +1,608 @@
+use std::{iter::Peekable, str::FromStr, vec::IntoIter};
+
+use sqlite::Value;
+
+use crate::types::ColumnType;
+
+use super::{
+    AndCriteria, Comparison, ComparisonCriteria, ContainsCriteria, Criteria, EqualsCriteria,
+    InCriteria, LikeCriteria, NotCriteria, NullCriteria, OrCriteria, Query, Row,
+};
+
+// limits on a filter expression, it's parsed & run for anyone who can query
+const MAX_LENGTH: usize = 2048;
+// how deep parentheses & nots can nest
+const MAX_DEPTH: usize = 8;
+// conditions in the whole expression
+const MAX_CONDITIONS: usize = 32;
+
+// The operator in a `field[op]=value` query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    // comma separated values
+    In,
+    // a LIKE pattern, % & _ are wildcards
+    Like,
+    Contains,
+    StartsWith,
+    // true or false
+    IsNull,
+}
+
+impl FromStr for Operator {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eq" => Ok(Operator::Eq),
+            "ne" => Ok(Operator::Ne),
+            "lt" => Ok(Operator::Lt),
+            "lte" => Ok(Operator::Lte),
+            "gt" => Ok(Operator::Gt),
+            "gte" => Ok(Operator::Gte),
+            "in" => Ok(Operator::In),
+            "like" => Ok(Operator::Like),
+            "contains" => Ok(Operator::Contains),
+            "startsWith" => Ok(Operator::StartsWith),
+            "isNull" => Ok(Operator::IsNull),
+            _ => Err(()),
+        }
+    }
+}
+
+// Conditions on a data type's columns, from the query string of /data/{type}
+#[derive(Debug)]
+pub struct Filter {
+    inner: Box<dyn Criteria>,
+}
+
+impl Filter {
+    // `field` must be one of the type's filter columns, it's put into the sql as is
+    pub fn new(
+        field: &'static str,
+        op: Operator,
+        col_type: ColumnType,
+        val: &str,
+    ) -> Result<Self, String> {
+        let vals = match op {
+            Operator::In => val.split(',').collect(),
+            _ => vec![val],
+        };
+        Ok(Self {
+            inner: criteria(field, op, col_type, &vals)?,
+        })
+    }
+
+    pub(crate) fn from_criteria(inner: Box<dyn Criteria>) -> Self {
+        Self { inner }
+    }
+
+    // A filter expression over `cols`, conditions combined with and, or, not &
+    // parentheses, e.g. `(email eq "a@b.c" or name contains bob) and not id in (1, 2)`.
+    // and binds tighter than or, values can be left unquoted when they're a single word.
+    pub fn parse(
+        expression: &str,
+        cols: &'static [(&'static str, ColumnType)],
+    ) -> Result<Self, String> {
+        if expression.len() > MAX_LENGTH {
+            return Err(format!("longer than {} characters", MAX_LENGTH));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(expression)?.into_iter().peekable(),
+            cols,
+            depth: 0,
+            conditions: 0,
+        };
+        let inner = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(Self { inner }),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+}
+
+// every operator but `in` takes a single value
+fn criteria(
+    field: &'static str,
+    op: Operator,
+    col_type: ColumnType,
+    vals: &[&str],
+) -> Result<Box<dyn Criteria>, String> {
+    let field = field.to_string();
+    let val = vals.first().copied().unwrap_or_default();
+    let comparison = |comparison| -> Result<Box<dyn Criteria>, String> {
+        Ok(Box::new(ComparisonCriteria {
+            field: field.clone(),
+            comparison,
+            val: col_type.parse(val)?,
+        }))
+    };
+    let criteria: Box<dyn Criteria> = match op {
+        Operator::Eq => Box::new(EqualsCriteria {
+            field,
+            val: col_type.parse(val)?,
+        }),
+        Operator::Ne => comparison(Comparison::NotEqual)?,
+        Operator::Lt => comparison(Comparison::Less)?,
+        Operator::Lte => comparison(Comparison::AtMost)?,
+        Operator::Gt => comparison(Comparison::Greater)?,
+        Operator::Gte => comparison(Comparison::AtLeast)?,
+        Operator::In => Box::new(InCriteria {
+            field,
+            vals: vals
+                .iter()
+                .map(|v| col_type.parse(v))
+                .collect::<Result<Vec<Value>, String>>()?,
+        }),
+        Operator::Like => Box::new(LikeCriteria {
+            field,
+            pattern: val.to_string(),
+        }),
+        Operator::Contains => Box::new(ContainsCriteria {
+            field,
+            val: val.to_string(),
+        }),
+        Operator::StartsWith => Box::new(LikeCriteria::starts_with(field, val)),
+        Operator::IsNull => Box::new(NullCriteria {
+            field,
+            null: match val {
+                "true" => true,
+                "false" => false,
+                _ => return Err(format!("`{}` is not true or false", val)),
+            },
+        }),
+    };
+    Ok(criteria)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Comma,
+    // a field, operator, keyword or unquoted value
+    Word(String),
+    Quoted(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Token::Open => write!(fmt, "`(`"),
+            Token::Close => write!(fmt, "`)`"),
+            Token::Comma => write!(fmt, "`,`"),
+            Token::Word(word) => write!(fmt, "`{}`", word),
+            Token::Quoted(val) => write!(fmt, "\"{}\"", val),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => continue,
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            // `\` escapes a quote or itself
+            '"' => {
+                let mut val = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => val.push(c),
+                            None => return Err("unterminated string".to_string()),
+                        },
+                        Some(c) => val.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(val));
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"(),\"".contains(*c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+    cols: &'static [(&'static str, ColumnType)],
+    depth: usize,
+    conditions: usize,
+}
+
+impl Parser {
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.tokens
+            .next_if(|t| matches!(t, Token::Word(w) if w == keyword))
+            .is_some()
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.tokens.next() {
+            Some(next) if next == token => Ok(()),
+            Some(next) => Err(format!("expected {}, found {}", token, next)),
+            None => Err(format!("expected {}", token)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Box<dyn Criteria>, String> {
+        let mut left = self.and()?;
+        while self.keyword("or") {
+            let right = self.and()?;
+            left = Box::new(OrCriteria { left, right });
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Box<dyn Criteria>, String> {
+        let mut left = self.unary()?;
+        while self.keyword("and") {
+            let right = self.unary()?;
+            left = Box::new(AndCriteria { left, right });
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Box<dyn Criteria>, String> {
+        if self.keyword("not") {
+            let inner = self.nested(Self::unary)?;
+            return Ok(Box::new(NotCriteria { inner }));
+        }
+        if self.tokens.next_if_eq(&Token::Open).is_some() {
+            let inner = self.nested(Self::or)?;
+            self.expect(Token::Close)?;
+            return Ok(inner);
+        }
+        self.condition()
+    }
+
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Box<dyn Criteria>, String>,
+    ) -> Result<Box<dyn Criteria>, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {}", MAX_DEPTH));
+        }
+        let inner = parse(self)?;
+        self.depth -= 1;
+        Ok(inner)
+    }
+
+    fn condition(&mut self) -> Result<Box<dyn Criteria>, String> {
+        self.conditions += 1;
+        if self.conditions > MAX_CONDITIONS {
+            return Err(format!("more than {} conditions", MAX_CONDITIONS));
+        }
+        let field = match self.tokens.next() {
+            Some(Token::Word(field)) => field,
+            Some(token) => return Err(format!("expected a field, found {}", token)),
+            None => return Err("expected a field".to_string()),
+        };
+        let (field, col_type) = self
+            .cols
+            .iter()
+            .find(|(col, _)| *col == field)
+            .ok_or_else(|| format!("can't filter on `{}`", field))?;
+        let op = match self.tokens.next() {
+            Some(Token::Word(op)) => op
+                .parse::<Operator>()
+                .map_err(|_| format!("unknown operator `{}`", op))?,
+            Some(token) => return Err(format!("expected an operator, found {}", token)),
+            None => return Err(format!("expected an operator after `{}`", field)),
+        };
+        let vals = match op {
+            Operator::In => {
+                self.expect(Token::Open)?;
+                let mut vals = vec![self.value()?];
+                while self.tokens.next_if_eq(&Token::Comma).is_some() {
+                    vals.push(self.value()?);
+                }
+                self.expect(Token::Close)?;
+                vals
+            }
+            _ => vec![self.value()?],
+        };
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
+        criteria(field, op, *col_type, &vals).map_err(|e| format!("`{}`: {}", field, e))
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.tokens.next() {
+            Some(Token::Word(val) | Token::Quoted(val)) => Ok(val),
+            Some(token) => Err(format!("expected a value, found {}", token)),
+            None => Err("expected a value".to_string()),
+        }
+    }
+}
+
+impl Query for Filter {
+    fn build(&self) -> (String, Vec<Value>) {
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.inner.matches(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cols: &[(&str, Value)]) -> Row {
+        cols.iter()
+            .map(|(col, val)| (col.to_string(), val.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn operators_parse() {
+        let ops = [
+            ("eq", Operator::Eq),
+            ("ne", Operator::Ne),
+            ("lt", Operator::Lt),
+            ("lte", Operator::Lte),
+            ("gt", Operator::Gt),
+            ("gte", Operator::Gte),
+            ("in", Operator::In),
+            ("like", Operator::Like),
+            ("contains", Operator::Contains),
+            ("startsWith", Operator::StartsWith),
+            ("isNull", Operator::IsNull),
+        ];
+        for (name, op) in ops {
+            assert_eq!(name.parse::<Operator>(), Ok(op));
+        }
+        for name in ["", "EQ", "startswith", "between"] {
+            assert_eq!(name.parse::<Operator>(), Err(()));
+        }
+    }
+
+    #[test]
+    fn new_parses_values_by_column_type() {
+        let filter = Filter::new("id", Operator::Gte, ColumnType::Integer, "7").unwrap();
+        assert_eq!(
+            filter.build(),
+            ("id >= ?".to_string(), vec![Value::Integer(7)])
+        );
+        let filter = Filter::new("done", Operator::Eq, ColumnType::Bool, "true").unwrap();
+        assert_eq!(
+            filter.build(),
+            ("done = ?".to_string(), vec![Value::Integer(1)])
+        );
+        assert!(Filter::new("id", Operator::Eq, ColumnType::Integer, "seven").is_err());
+        assert!(Filter::new("done", Operator::Eq, ColumnType::Bool, "yes").is_err());
+    }
+
+    #[test]
+    fn new_splits_in_values() {
+        let filter = Filter::new("id", Operator::In, ColumnType::Integer, "1,2,3").unwrap();
+        assert_eq!(
+            filter.build(),
+            (
+                "id IN (?,?,?)".to_string(),
+                vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]
+            )
+        );
+        assert!(filter.matches(&row(&[("id", Value::Integer(2))])));
+        assert!(!filter.matches(&row(&[("id", Value::Integer(4))])));
+        assert!(Filter::new("id", Operator::In, ColumnType::Integer, "1,x").is_err());
+    }
+
+    #[test]
+    fn new_starts_with_escapes_wildcards() {
+        let filter = Filter::new("name", Operator::StartsWith, ColumnType::Text, "50%_").unwrap();
+        assert_eq!(
+            filter.build().1,
+            vec![Value::String("50\\%\\_%".to_string())]
+        );
+        assert!(filter.matches(&row(&[("name", Value::String("50%_off".to_string()))])));
+        assert!(!filter.matches(&row(&[("name", Value::String("500 off".to_string()))])));
+    }
+
+    #[test]
+    fn new_like_and_contains_ignore_case() {
+        let name = row(&[("name", Value::String("Bobby Tables".to_string()))]);
+        let like = Filter::new("name", Operator::Like, ColumnType::Text, "b_b%s").unwrap();
+        assert!(like.matches(&name));
+        let contains = Filter::new("name", Operator::Contains, ColumnType::Text, "TABLE").unwrap();
+        assert!(contains.matches(&name));
+    }
+
+    #[test]
+    fn new_is_null_takes_true_or_false() {
+        let null = Filter::new("name", Operator::IsNull, ColumnType::Text, "true").unwrap();
+        assert_eq!(null.build(), ("name IS NULL".to_string(), vec![]));
+        assert!(null.matches(&row(&[("name", Value::Null)])));
+        assert!(null.matches(&row(&[])));
+        let not_null = Filter::new("name", Operator::IsNull, ColumnType::Text, "false").unwrap();
+        assert!(not_null.matches(&row(&[("name", Value::String("a".to_string()))])));
+        assert!(Filter::new("name", Operator::IsNull, ColumnType::Text, "maybe").is_err());
+    }
+
+    const COLS: &[(&str, ColumnType)] = &[
+        ("id", ColumnType::Integer),
+        ("name", ColumnType::Text),
+        ("done", ColumnType::Bool),
+    ];
+
+    fn parse(expression: &str) -> Result<Filter, String> {
+        Filter::parse(expression, COLS)
+    }
+
+    fn note(id: i64, name: Option<&str>) -> Row {
+        row(&[
+            ("id", Value::Integer(id)),
+            (
+                "name",
+                name.map(|n| Value::String(n.to_string()))
+                    .unwrap_or(Value::Null),
+            ),
+        ])
+    }
+
+    #[test]
+    fn tokenize_splits_words_quotes_and_punctuation() {
+        assert_eq!(
+            tokenize(r#"(id in (1,2)) and name eq "a \"b\" \\c""#).unwrap(),
+            vec![
+                Token::Open,
+                Token::Word("id".to_string()),
+                Token::Word("in".to_string()),
+                Token::Open,
+                Token::Word("1".to_string()),
+                Token::Comma,
+                Token::Word("2".to_string()),
+                Token::Close,
+                Token::Close,
+                Token::Word("and".to_string()),
+                Token::Word("name".to_string()),
+                Token::Word("eq".to_string()),
+                Token::Quoted(r#"a "b" \c"#.to_string()),
+            ]
+        );
+        assert!(tokenize(r#"name eq "open"#).is_err());
+        assert!(tokenize(r#"name eq "open\"#).is_err());
+    }
+
+    #[test]
+    fn parse_and_binds_tighter_than_or() {
+        let filter = parse("id eq 1 or id eq 2 and name eq x").unwrap();
+        assert_eq!(
+            filter.build().0,
+            "((id = ?) or (((id = ?) and (name = ?))))"
+        );
+        assert!(filter.matches(&note(1, Some("y"))));
+        assert!(!filter.matches(&note(2, Some("y"))));
+        assert!(filter.matches(&note(2, Some("x"))));
+
+        let grouped = parse("(id eq 1 or id eq 2) and name eq x").unwrap();
+        assert!(!grouped.matches(&note(1, Some("y"))));
+        assert!(grouped.matches(&note(1, Some("x"))));
+    }
+
+    #[test]
+    fn parse_not() {
+        let filter = parse("not id eq 1").unwrap();
+        assert!(!filter.matches(&note(1, None)));
+        assert!(filter.matches(&note(2, None)));
+
+        let filter = parse("not (id eq 1 or id eq 2) and not not name eq x").unwrap();
+        assert!(filter.matches(&note(3, Some("x"))));
+        assert!(!filter.matches(&note(2, Some("x"))));
+        assert!(!filter.matches(&note(3, Some("y"))));
+
+        // a null never compares equal, so its negation holds, in sql too
+        let filter = parse("not name eq x").unwrap();
+        assert_eq!(filter.build().0, "(not coalesce((name = ?), false))");
+        assert!(filter.matches(&note(1, None)));
+    }
+
+    #[test]
+    fn parse_quoted_values() {
+        let filter = parse(r#"name eq "bob \"the\" (builder), \\ and or""#).unwrap();
+        assert_eq!(
+            filter.build().1,
+            vec![Value::String(
+                r#"bob "the" (builder), \ and or"#.to_string()
+            )]
+        );
+        assert!(parse(r#"name eq "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn parse_in_lists() {
+        let filter = parse(r#"id in (1, 2,3) and name in ("a b", c)"#).unwrap();
+        assert_eq!(
+            filter.build(),
+            (
+                "((id IN (?,?,?)) and (name IN (?,?)))".to_string(),
+                vec![
+                    Value::Integer(1),
+                    Value::Integer(2),
+                    Value::Integer(3),
+                    Value::String("a b".to_string()),
+                    Value::String("c".to_string()),
+                ]
+            )
+        );
+        assert!(parse("id in ()").is_err());
+        assert!(parse("id in (1, 2").is_err());
+        assert_eq!(parse("id in 1").unwrap_err(), "expected `(`, found `1`");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("nope eq 1").unwrap_err(), "can't filter on `nope`");
+        assert_eq!(
+            parse("id between 1").unwrap_err(),
+            "unknown operator `between`"
+        );
+        assert_eq!(
+            parse("id eq one").unwrap_err(),
+            "`id`: `one` is not an integer"
+        );
+        assert_eq!(parse("id eq 1 id").unwrap_err(), "unexpected `id`");
+        assert_eq!(parse("id eq").unwrap_err(), "expected a value");
+        assert_eq!(parse("(id eq 1").unwrap_err(), "expected `)`");
+        assert!(parse("").is_err());
+        assert!(parse("id eq 1 and").is_err());
+        assert!(parse("and id eq 1").is_err());
+    }
+
+    #[test]
+    fn parse_limits_length() {
+        let padded = format!("id eq 1{}", " ".repeat(MAX_LENGTH - 7));
+        assert!(parse(&padded).is_ok());
+        assert_eq!(
+            parse(&format!("{} ", padded)).unwrap_err(),
+            format!("longer than {} characters", MAX_LENGTH)
+        );
+    }
+
+    #[test]
+    fn parse_limits_depth() {
+        let nots = |n| format!("{}id eq 1", "not ".repeat(n));
+        assert!(parse(&nots(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse(&nots(MAX_DEPTH + 1)).unwrap_err(),
+            format!("nested deeper than {}", MAX_DEPTH)
+        );
+        let parens = |n| format!("{}id eq 1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(&parens(MAX_DEPTH)).is_ok());
+        assert!(parse(&parens(MAX_DEPTH + 1)).is_err());
+        // depth is how deep, not how many
+        let siblings = ["(id eq 1)"; MAX_DEPTH + 1].join(" or ");
+        assert!(parse(&siblings).is_ok());
+    }
+
+    #[test]
+    fn parse_limits_conditions() {
+        let conditions = |n| vec!["id eq 1"; n].join(" or ");
+        assert!(parse(&conditions(MAX_CONDITIONS)).is_ok());
+        assert_eq!(
+            parse(&conditions(MAX_CONDITIONS + 1)).unwrap_err(),
+            format!("more than {} conditions", MAX_CONDITIONS)
+        );
+        let in_list = format!("id in ({})", vec!["1"; MAX_CONDITIONS + 1].join(","));
+        assert!(parse(&in_list).is_ok());
+    }
+}
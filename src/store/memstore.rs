@@ -0,0 +1,202 @@
+use sqlite::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use tracing::debug;
+
+use crate::{RequestObject, types::DataObject};
+
+use super::{
+    AsyncStore, Query, QueryTypes, Row, Store,
+    codec::{self, Codec},
+    error::{StoreError, StoreResult},
+    page::{Page, Paged},
+};
+
+struct Tables {
+    rows: HashMap<String, BTreeMap<i64, Row>>,
+    // the last id handed out per table, ids aren't reused after a delete
+    last_ids: HashMap<String, i64>,
+    codec: Codec,
+}
+
+// Keeps every table in memory, e.g. for tests. Queries are evaluated with
+// `Query::matches`, required fields are checked on create but unique
+// constraints & column defaults other than 0 aren't enforced.
+pub struct MemStore {
+    tables: Mutex<Tables>,
+}
+
+impl MemStore {
+    pub fn new() -> StoreResult<Self> {
+        Ok(Self {
+            tables: Mutex::new(Tables {
+                rows: HashMap::new(),
+                last_ids: HashMap::new(),
+                codec: Codec::new()?,
+            }),
+        })
+    }
+}
+
+impl Store for MemStore {
+    fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        if let Err(e) = data.validate_create() {
+            debug!("{}", e);
+            return Err(StoreError::Constraint(e.to_string()));
+        }
+        let mut tables = self.tables.lock()?;
+        let mut row = tables.codec.request_row(data)?;
+        let last_id = tables.last_ids.entry(T::table_name()).or_default();
+        *last_id += 1;
+        let id = *last_id;
+        row.insert(T::id_col(), Value::Integer(id));
+        let data = tables.codec.object::<T>(&row)?;
+        // the columns left out are kept as they read, so queries see the defaults
+        let row = tables.codec.row(data.clone())?;
+        tables
+            .rows
+            .entry(T::table_name())
+            .or_default()
+            .insert(id, row);
+        Ok(data)
+    }
+
+    fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        Store::update_if(self, data, vec![])?.ok_or(StoreError::NotFound)
+    }
+
+    // checked & written under the one lock
+    fn update_if<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+        queries: Vec<QueryTypes>,
+    ) -> StoreResult<Option<T>> {
+        let id = data
+            .id()
+            .ok_or(StoreError::Constraint("id is required".to_string()))?;
+        let mut tables = self.tables.lock()?;
+        let changes = tables.codec.request_row(data)?;
+        let mut row = match tables
+            .rows
+            .get(&T::table_name())
+            .and_then(|table| table.get(&id))
+            .filter(|row| queries.iter().all(|q| q.matches(row)))
+        {
+            Some(row) => row.clone(),
+            None => return Ok(None),
+        };
+        row.extend(changes);
+        let data = tables.codec.object::<T>(&row)?;
+        tables
+            .rows
+            .entry(T::table_name())
+            .or_default()
+            .insert(id, row);
+        Ok(Some(data))
+    }
+
+    fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
+        let tables = self.tables.lock()?;
+        match tables
+            .rows
+            .get(&T::table_name())
+            .and_then(|table| table.get(&id))
+        {
+            Some(row) => tables.codec.object(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        let tables = self.tables.lock()?;
+        let table = match tables.rows.get(&T::table_name()) {
+            Some(table) => table,
+            None => return Ok(vec![]),
+        };
+        table
+            .values()
+            .filter(|row| queries.iter().all(|q| q.matches(row)))
+            .map(|row| tables.codec.object(row))
+            .collect()
+    }
+
+    fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        let tables = self.tables.lock()?;
+        let table = match tables.rows.get(&T::table_name()) {
+            Some(table) => table,
+            None => {
+                return Ok(Paged {
+                    items: vec![],
+                    total: page.count.then_some(0),
+                });
+            }
+        };
+        let mut rows = table
+            .values()
+            .filter(|row| queries.iter().all(|q| q.matches(row)))
+            .collect::<Vec<_>>();
+        let total = page.count.then_some(rows.len() as i64);
+        let id_col = T::id_col();
+        rows.sort_by(|left, right| page.cmp(left, right, &id_col));
+        let items = rows
+            .into_iter()
+            .filter(|row| page.after.as_ref().is_none_or(|after| after.matches(row)))
+            .skip(page.offset)
+            .take(page.limit.unwrap_or(usize::MAX))
+            .map(|row| tables.codec.object(row))
+            .collect::<StoreResult<Vec<T>>>()?;
+        Ok(Paged { items, total })
+    }
+
+    fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        let mut tables = self.tables.lock()?;
+        let row = tables
+            .rows
+            .get_mut(&T::table_name())
+            .and_then(|table| table.remove(&id))
+            .ok_or(StoreError::NotFound)?;
+        tables.codec.object(&row)
+    }
+
+    fn matches<T: DataObject>(&self, snapshot: &str, queries: &[QueryTypes]) -> StoreResult<bool> {
+        match codec::json_row(snapshot) {
+            Some(row) => Ok(queries.iter().all(|q| q.matches(&row))),
+            None => Err(StoreError::Decode("snapshot is not an object".to_string())),
+        }
+    }
+}
+
+// nothing to wait on, the sync calls are answered in place
+impl AsyncStore for MemStore {
+    async fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        Store::create(self, data)
+    }
+
+    async fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        Store::update(self, data)
+    }
+
+    async fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
+        Store::get(self, id)
+    }
+
+    async fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        Store::get_queries(self, queries)
+    }
+
+    async fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        Store::get_page(self, queries, page)
+    }
+
+    async fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        Store::delete(self, id)
+    }
+}
@@ -0,0 +1,382 @@
+use std::fmt;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+// An embedded schema change, applied in version order
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    // None when the change can't be undone
+    pub down: Option<&'static str>,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_tables",
+        up: include_str!("../../migrations/sqlite/0001_create_tables.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/sqlite/0001_create_tables.down.sql"
+        )),
+    },
+    Migration {
+        version: 2,
+        name: "create_audit_log",
+        up: include_str!("../../migrations/sqlite/0002_create_audit_log.up.sql"),
+        down: None,
+    },
+    Migration {
+        version: 3,
+        name: "add_user_lockout",
+        up: include_str!("../../migrations/sqlite/0003_add_user_lockout.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/sqlite/0003_add_user_lockout.down.sql"
+        )),
+    },
+    Migration {
+        version: 4,
+        name: "create_service_accounts",
+        up: include_str!("../../migrations/sqlite/0004_create_service_accounts.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/sqlite/0004_create_service_accounts.down.sql"
+        )),
+    },
+    Migration {
+        version: 5,
+        name: "create_refresh_tokens",
+        up: include_str!("../../migrations/sqlite/0005_create_refresh_tokens.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/sqlite/0005_create_refresh_tokens.down.sql"
+        )),
+    },
+    Migration {
+        version: 6,
+        name: "create_webhooks",
+        up: include_str!("../../migrations/sqlite/0006_create_webhooks.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/sqlite/0006_create_webhooks.down.sql"
+        )),
+    },
+];
+
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_tables",
+        up: include_str!("../../migrations/postgres/0001_create_tables.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/postgres/0001_create_tables.down.sql"
+        )),
+    },
+    Migration {
+        version: 2,
+        name: "create_audit_log",
+        up: include_str!("../../migrations/postgres/0002_create_audit_log.up.sql"),
+        down: None,
+    },
+    Migration {
+        version: 3,
+        name: "add_user_lockout",
+        up: include_str!("../../migrations/postgres/0003_add_user_lockout.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/postgres/0003_add_user_lockout.down.sql"
+        )),
+    },
+    Migration {
+        version: 4,
+        name: "create_service_accounts",
+        up: include_str!("../../migrations/postgres/0004_create_service_accounts.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/postgres/0004_create_service_accounts.down.sql"
+        )),
+    },
+    Migration {
+        version: 5,
+        name: "create_refresh_tokens",
+        up: include_str!("../../migrations/postgres/0005_create_refresh_tokens.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/postgres/0005_create_refresh_tokens.down.sql"
+        )),
+    },
+    Migration {
+        version: 6,
+        name: "create_webhooks",
+        up: include_str!("../../migrations/postgres/0006_create_webhooks.up.sql"),
+        down: Some(include_str!(
+            "../../migrations/postgres/0006_create_webhooks.down.sql"
+        )),
+    },
+];
+
+// A row of the schema_migrations table
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    // unix timestamp
+    pub applied_at: i64,
+}
+
+// A store whose schema is managed by migrations
+pub trait Migrate {
+    fn migrations(&self) -> &'static [Migration];
+    // creates schema_migrations when it doesn't exist yet
+    fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError>;
+    // runs the up sql & records the version in one transaction
+    fn apply(&self, migration: &Migration) -> Result<(), MigrationError>;
+    // runs the down sql & forgets the version in one transaction
+    fn revert(&self, migration: &Migration, down: &str) -> Result<(), MigrationError>;
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sql(String),
+    // an applied migration was edited afterwards
+    ChecksumMismatch(i64),
+    // the database has a version this build doesn't know about
+    Unknown(i64),
+    Irreversible(i64),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            MigrationError::Sql(e) => write!(fmt, "migration failed: {}", e),
+            MigrationError::ChecksumMismatch(v) => {
+                write!(fmt, "migration {} was changed after it was applied", v)
+            }
+            MigrationError::Unknown(v) => {
+                write!(
+                    fmt,
+                    "migration {} is applied but not known to this build",
+                    v
+                )
+            }
+            MigrationError::Irreversible(v) => write!(fmt, "migration {} can't be undone", v),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // applied, but the embedded sql no longer matches its checksum
+    Modified,
+    // applied, but not embedded in this build
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub state: MigrationState,
+    pub applied_at: Option<i64>,
+}
+
+pub fn status(store: &impl Migrate) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let applied = store.applied()?;
+    let mut res: Vec<MigrationStatus> = store
+        .migrations()
+        .iter()
+        .map(|m| {
+            let row = applied.iter().find(|a| a.version == m.version);
+            let state = match row {
+                Some(a) if a.checksum == m.checksum() => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: m.version,
+                name: m.name.to_string(),
+                state,
+                applied_at: row.map(|a| a.applied_at),
+            }
+        })
+        .collect();
+    for a in applied.iter() {
+        if !store.migrations().iter().any(|m| m.version == a.version) {
+            res.push(MigrationStatus {
+                version: a.version,
+                name: a.name.clone(),
+                state: MigrationState::Unknown,
+                applied_at: Some(a.applied_at),
+            });
+        }
+    }
+    res.sort_by_key(|s| s.version);
+    Ok(res)
+}
+
+// Applies every pending migration, refusing to run when an applied one was
+// edited or is unknown. Returns the versions applied.
+pub fn migrate(store: &impl Migrate) -> Result<Vec<i64>, MigrationError> {
+    let statuses = status(store)?;
+    for s in statuses.iter() {
+        match s.state {
+            MigrationState::Modified => return Err(MigrationError::ChecksumMismatch(s.version)),
+            MigrationState::Unknown => return Err(MigrationError::Unknown(s.version)),
+            MigrationState::Applied | MigrationState::Pending => {}
+        }
+    }
+    let mut applied = vec![];
+    for migration in store.migrations() {
+        let pending = statuses
+            .iter()
+            .any(|s| s.version == migration.version && s.state == MigrationState::Pending);
+        if pending {
+            info!(
+                "applying migration {} {}",
+                migration.version, migration.name
+            );
+            store.apply(migration)?;
+            applied.push(migration.version);
+        }
+    }
+    Ok(applied)
+}
+
+// Reverts the `steps` most recently applied migrations, newest first. Stops
+// before doing anything if one of them has no down migration.
+pub fn rollback(store: &impl Migrate, steps: usize) -> Result<Vec<i64>, MigrationError> {
+    let mut applied = store.applied()?;
+    applied.sort_by_key(|a| std::cmp::Reverse(a.version));
+    let mut plan = vec![];
+    for a in applied.iter().take(steps) {
+        let migration = store
+            .migrations()
+            .iter()
+            .find(|m| m.version == a.version)
+            .ok_or(MigrationError::Unknown(a.version))?;
+        let down = migration
+            .down
+            .ok_or(MigrationError::Irreversible(a.version))?;
+        plan.push((migration, down));
+    }
+    let mut reverted = vec![];
+    for (migration, down) in plan {
+        info!(
+            "reverting migration {} {}",
+            migration.version, migration.name
+        );
+        store.revert(migration, down)?;
+        reverted.push(migration.version);
+    }
+    Ok(reverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SqliteStore, config::SqliteConfig};
+
+    fn memory_store() -> SqliteStore {
+        SqliteStore::new(&SqliteConfig {
+            path: ":memory:".to_string(),
+            ..SqliteConfig::default()
+        })
+        .unwrap()
+    }
+
+    // a sqlite store with its own list of migrations
+    struct Versions(SqliteStore, &'static [Migration]);
+
+    impl Migrate for Versions {
+        fn migrations(&self) -> &'static [Migration] {
+            self.1
+        }
+
+        fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError> {
+            self.0.applied()
+        }
+
+        fn apply(&self, migration: &Migration) -> Result<(), MigrationError> {
+            self.0.apply(migration)
+        }
+
+        fn revert(&self, migration: &Migration, down: &str) -> Result<(), MigrationError> {
+            self.0.revert(migration, down)
+        }
+    }
+
+    const THINGS: &[Migration] = &[Migration {
+        version: 1,
+        name: "create_things",
+        up: "CREATE TABLE things (id integer primary key)",
+        down: Some("DROP TABLE things"),
+    }];
+
+    const EDITED: &[Migration] = &[Migration {
+        version: 1,
+        name: "create_things",
+        up: "CREATE TABLE things (id integer primary key, name text)",
+        down: Some("DROP TABLE things"),
+    }];
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let store = memory_store();
+        let versions = SQLITE.iter().map(|m| m.version).collect::<Vec<_>>();
+        assert_eq!(migrate(&store).unwrap(), versions);
+        assert_eq!(migrate(&store).unwrap(), Vec::<i64>::new());
+        assert!(
+            status(&store)
+                .unwrap()
+                .iter()
+                .all(|s| s.state == MigrationState::Applied)
+        );
+    }
+
+    #[test]
+    fn migrate_rejects_edited_migration() {
+        let store = memory_store();
+        migrate(&Versions(store.clone(), THINGS)).unwrap();
+
+        let edited = Versions(store, EDITED);
+        assert_eq!(status(&edited).unwrap()[0].state, MigrationState::Modified);
+        assert!(matches!(
+            migrate(&edited),
+            Err(MigrationError::ChecksumMismatch(1))
+        ));
+    }
+
+    #[test]
+    fn migrate_rejects_unknown_version() {
+        let store = memory_store();
+        migrate(&Versions(store.clone(), THINGS)).unwrap();
+        assert!(matches!(
+            migrate(&Versions(store, &[])),
+            Err(MigrationError::Unknown(1))
+        ));
+    }
+
+    #[test]
+    fn rollback_stops_at_irreversible() {
+        let store = memory_store();
+        migrate(&store).unwrap();
+        // 0003 to 0006 can be undone, 0002 can't
+        assert!(matches!(
+            rollback(&store, 5),
+            Err(MigrationError::Irreversible(2))
+        ));
+        // nothing was reverted
+        assert_eq!(store.applied().unwrap().len(), SQLITE.len());
+        assert_eq!(rollback(&store, 4).unwrap(), vec![6, 5, 4, 3]);
+        assert_eq!(migrate(&store).unwrap(), vec![3, 4, 5, 6]);
+    }
+}
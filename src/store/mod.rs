@@ -1,82 +1,176 @@
-pub(crate) mod error;
+pub(crate) mod codec;
+#[cfg(test)]
+mod conformance;
+pub mod error;
+pub mod filter;
+pub(crate) mod memstore;
+pub mod migrations;
+pub mod page;
+#[cfg(feature = "postgres")]
+pub(crate) mod pgstore;
 pub(crate) mod sqlitestore;
-use std::collections::HashMap;
-
-use axum::{
-    extract::{
-        FromRequestParts, Path, Query as UrlQuery,
-        rejection::{PathRejection, QueryRejection},
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool},
     },
-    http::request::Parts,
-    response::IntoResponse,
 };
+
+pub use memstore::MemStore;
+#[cfg(feature = "postgres")]
+pub use pgstore::PgStore;
 pub use sqlitestore::SqliteStore;
 
-use error::StoreResult;
+use error::{StoreError, StoreResult};
+use page::{Page, Paged};
 use sqlite::Value;
-use tracing::debug;
+use tokio::runtime::RuntimeFlavor;
+use tracing::error;
 
 use crate::types::{DataObject, QueryTypes, RequestObject};
 
-pub(crate) trait Store {
+// Backend the data objects are kept in. `SqliteStore` & `MemStore` are
+// provided, implement this to plug in another.
+pub trait Store: Send + Sync + 'static {
     fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T>;
     fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T>;
-    fn get<T: DataObject>(&self, id: i64) -> Option<T>;
-    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> Vec<T>;
+    // a compare-and-set, updates the object only while it still matches every
+    // query. None when it doesn't, or is gone
+    fn update_if<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+        queries: Vec<QueryTypes>,
+    ) -> StoreResult<Option<T>>;
+    fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>>;
+    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>>;
+    // the part of what the queries match that `page` asks for
+    fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>>;
     fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T>;
+    // whether a json snapshot of `T` satisfies every query, used where the
+    // object can't be looked up again, e.g. after it's deleted
+    fn matches<T: DataObject>(&self, snapshot: &str, queries: &[QueryTypes]) -> StoreResult<bool>;
 }
 
-pub struct ExtractGlonkQueries(pub Vec<QueryTypes>);
+// Database calls block, tokio has to be told first so it can move other tasks
+// off the worker. Only the multi threaded runtime can, block_in_place panics on
+// a current_thread one, where the call just blocks it. Async callers don't
+// block at all, they go through `AsyncStore`.
+pub(crate) fn blocking<F: FnOnce() -> R, R>(f: F) -> R {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
 
-pub enum QueriesRejection {
-    Query(QueryRejection),
-    Path(PathRejection),
+// Async counterpart of `Store` used by the /data handlers. `SqliteStore` &
+// `PgStore` run their queries on tokio's blocking pool, & skip the ones whose
+// caller went away while they waited for a connection. `MemStore` answers in
+// place through its `Store` methods.
+pub trait AsyncStore: Send + Sync + 'static {
+    fn create<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+    ) -> impl Future<Output = StoreResult<T>> + Send;
+    fn update<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+    ) -> impl Future<Output = StoreResult<T>> + Send;
+    fn get<T: DataObject>(&self, id: i64) -> impl Future<Output = StoreResult<Option<T>>> + Send;
+    fn get_queries<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+    ) -> impl Future<Output = StoreResult<Vec<T>>> + Send;
+    fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> impl Future<Output = StoreResult<Paged<T>>> + Send;
+    fn delete<T: DataObject>(&self, id: i64) -> impl Future<Output = StoreResult<T>> + Send;
 }
 
-impl IntoResponse for QueriesRejection {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            Self::Query(i) => i.into_response(),
-            Self::Path(i) => i.into_response(),
-        }
+// Set once the caller of an async store call stops waiting for it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
     }
 }
 
-impl From<PathRejection> for QueriesRejection {
-    fn from(value: PathRejection) -> QueriesRejection {
-        QueriesRejection::Path(value)
+struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        (self.0).0.store(true, atomic::Ordering::Relaxed);
     }
 }
-impl From<QueryRejection> for QueriesRejection {
-    fn from(value: QueryRejection) -> Self {
-        QueriesRejection::Query(value)
+
+// Runs `f` on tokio's blocking pool, cancelling it when the returned future is
+// dropped
+pub(crate) async fn spawn_blocking<R: Send + 'static>(
+    f: impl FnOnce(Cancellation) -> StoreResult<R> + Send + 'static,
+) -> StoreResult<R> {
+    let cancellation = Cancellation::default();
+    let _guard = CancelOnDrop(cancellation.clone());
+    match tokio::task::spawn_blocking(move || f(cancellation)).await {
+        Ok(res) => res,
+        // the connection it held is poisoned too
+        Err(e) => {
+            error!("{:?}", e);
+            Err(StoreError::Poisoned)
+        }
     }
 }
 
-impl<S> FromRequestParts<S> for ExtractGlonkQueries
-where
-    S: Send + Sync,
-{
-    type Rejection = QueriesRejection;
+// A stored object's columns, for stores that evaluate queries themselves
+pub type Row = HashMap<String, Value>;
+
+pub(crate) trait Criteria: Send + Sync + std::fmt::Debug {
+    fn build(&self) -> (String, Vec<Value>);
+    fn matches(&self, row: &Row) -> bool;
+}
+
+impl Criteria for Box<dyn Criteria> {
+    fn build(&self) -> (String, Vec<Value>) {
+        (**self).build()
+    }
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let queries = UrlQuery::<HashMap<String, String>>::from_request_parts(parts, state).await?;
-        let Path(data_type) = Path::from_request_parts(parts, state).await?;
-        let glonk_queries = queries
-            .iter()
-            .filter_map(|(k, v)| QueryTypes::try_from((&data_type, (k, v))).ok())
-            .collect::<Vec<QueryTypes>>();
-        debug!("{:?}", queries);
-        Ok(Self(glonk_queries))
+    fn matches(&self, row: &Row) -> bool {
+        (**self).matches(row)
     }
 }
 
-pub(crate) trait Criteria: Send + Sync + std::fmt::Debug {
+// `build` gives the sql where clause & its values, `matches` evaluates the same
+// condition against a row
+pub trait Query: Send + Sync + std::fmt::Debug {
     fn build(&self) -> (String, Vec<Value>);
+    fn matches(&self, row: &Row) -> bool;
 }
 
-pub(crate) trait Query: Send + Sync + std::fmt::Debug {
-    fn build(&self) -> (String, Vec<Value>);
+// orders values the way sqlite compares them, None when either is null
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Integer(l), Value::Integer(r)) => Some(l.cmp(r)),
+        (Value::Integer(l), Value::Float(r)) => (*l as f64).partial_cmp(r),
+        (Value::Float(l), Value::Integer(r)) => l.partial_cmp(&(*r as f64)),
+        (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Binary(l), Value::Binary(r)) => Some(l.cmp(r)),
+        (Value::Null, _) | (_, Value::Null) => None,
+        // numbers sort before text, text before blobs
+        (Value::Integer(_) | Value::Float(_), _) => Some(Ordering::Less),
+        (Value::String(_), Value::Binary(_)) => Some(Ordering::Less),
+        _ => Some(Ordering::Greater),
+    }
 }
 
 #[derive(Debug)]
@@ -86,12 +180,23 @@ pub(crate) struct ContainsCriteria {
 }
 
 impl Criteria for ContainsCriteria {
+    // the value is taken literally, as `matches` does
     fn build(&self) -> (String, Vec<Value>) {
         (
-            format!("{} LIKE ?", self.field),
-            vec![Value::String(format!("%{}%", self.val))],
+            format!("{} LIKE ? ESCAPE '\\'", self.field),
+            vec![Value::String(format!("%{}%", escape_like(&self.val)))],
         )
     }
+
+    // LIKE is case insensitive for ascii
+    fn matches(&self, row: &Row) -> bool {
+        match row.get(&self.field) {
+            Some(Value::String(s)) => s
+                .to_ascii_lowercase()
+                .contains(&self.val.to_ascii_lowercase()),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -104,6 +209,203 @@ impl Criteria for EqualsCriteria {
     fn build(&self) -> (String, Vec<Value>) {
         (format!("{} = ?", self.field), vec![self.val.clone()])
     }
+
+    fn matches(&self, row: &Row) -> bool {
+        row.get(&self.field)
+            .and_then(|v| compare(v, &self.val))
+            .is_some_and(|o| o == Ordering::Equal)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Comparison {
+    AtLeast,
+    AtMost,
+    Less,
+    Greater,
+    NotEqual,
+}
+
+#[derive(Debug)]
+pub(crate) struct ComparisonCriteria {
+    pub field: String,
+    pub comparison: Comparison,
+    pub val: Value,
+}
+
+impl Criteria for ComparisonCriteria {
+    fn build(&self) -> (String, Vec<Value>) {
+        let op = match self.comparison {
+            Comparison::AtLeast => ">=",
+            Comparison::AtMost => "<=",
+            Comparison::Less => "<",
+            Comparison::Greater => ">",
+            Comparison::NotEqual => "!=",
+        };
+        // text compares bytewise, as the memory store & sqlite do by default
+        let collate = match self.val {
+            Value::String(_) => " COLLATE BINARY",
+            _ => "",
+        };
+        (
+            format!("{} {} ?{}", self.field, op, collate),
+            vec![self.val.clone()],
+        )
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        let ordering = row.get(&self.field).and_then(|v| compare(v, &self.val));
+        match self.comparison {
+            Comparison::AtLeast => ordering.is_some_and(|o| o != Ordering::Less),
+            Comparison::AtMost => ordering.is_some_and(|o| o != Ordering::Greater),
+            Comparison::Less => ordering.is_some_and(|o| o == Ordering::Less),
+            Comparison::Greater => ordering.is_some_and(|o| o == Ordering::Greater),
+            Comparison::NotEqual => ordering.is_some_and(|o| o != Ordering::Equal),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InCriteria {
+    pub field: String,
+    pub vals: Vec<Value>,
+}
+
+impl Criteria for InCriteria {
+    fn build(&self) -> (String, Vec<Value>) {
+        (
+            format!(
+                "{} IN ({})",
+                self.field,
+                vec!["?"; self.vals.len()].join(",")
+            ),
+            self.vals.clone(),
+        )
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        row.get(&self.field).is_some_and(|v| {
+            self.vals
+                .iter()
+                .any(|val| compare(v, val) == Some(Ordering::Equal))
+        })
+    }
+}
+
+// `%` matches any run of characters & `_` any one, `\` escapes either
+#[derive(Debug)]
+pub(crate) struct LikeCriteria {
+    pub field: String,
+    pub pattern: String,
+}
+
+impl LikeCriteria {
+    // matches values that begin with `prefix`, taken literally
+    pub fn starts_with(field: String, prefix: &str) -> Self {
+        Self {
+            field,
+            pattern: format!("{}%", escape_like(prefix)),
+        }
+    }
+}
+
+// `val` as a LIKE pattern that matches it literally
+fn escape_like(val: &str) -> String {
+    let mut pattern = String::with_capacity(val.len());
+    for c in val.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+impl Criteria for LikeCriteria {
+    fn build(&self) -> (String, Vec<Value>) {
+        (
+            format!("{} LIKE ? ESCAPE '\\'", self.field),
+            vec![Value::String(self.pattern.clone())],
+        )
+    }
+
+    // LIKE is case insensitive for ascii
+    fn matches(&self, row: &Row) -> bool {
+        match row.get(&self.field) {
+            Some(Value::String(s)) => {
+                like(&self.pattern.to_ascii_lowercase(), &s.to_ascii_lowercase())
+            }
+            _ => false,
+        }
+    }
+}
+
+// wildcard matching that backtracks to the last `%` only, so it stays linear
+// in the pattern's length however many `%` it has
+fn like(pattern: &str, text: &str) -> bool {
+    #[derive(PartialEq)]
+    enum Token {
+        Any,
+        One,
+        Char(char),
+    }
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '%' => Token::Any,
+            '_' => Token::One,
+            '\\' => Token::Char(chars.next().unwrap_or('\\')),
+            c => Token::Char(c),
+        });
+    }
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // where the last `%` is & the text it has taken up to
+    let mut any = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::Any) => {
+                any = Some((p, t));
+                p += 1;
+            }
+            Some(Token::One) => {
+                p += 1;
+                t += 1;
+            }
+            Some(Token::Char(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match any {
+                Some((any_p, any_t)) => {
+                    any = Some((any_p, any_t + 1));
+                    p = any_p + 1;
+                    t = any_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(|token| *token == Token::Any)
+}
+
+#[derive(Debug)]
+pub(crate) struct NullCriteria {
+    pub field: String,
+    pub null: bool,
+}
+
+impl Criteria for NullCriteria {
+    fn build(&self) -> (String, Vec<Value>) {
+        let op = if self.null { "IS NULL" } else { "IS NOT NULL" };
+        (format!("{} {}", self.field, op), vec![])
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        let null = matches!(row.get(&self.field), None | Some(Value::Null));
+        null == self.null
+    }
 }
 
 #[derive(Debug)]
@@ -129,6 +431,10 @@ where
             [&lv[..], &rv[..]].concat(),
         )
     }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.left.matches(row) && self.right.matches(row)
+    }
 }
 
 #[derive(Debug)]
@@ -154,4 +460,52 @@ where
             [&lv[..], &rv[..]].concat(),
         )
     }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.left.matches(row) || self.right.matches(row)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct NotCriteria<C>
+where
+    C: Criteria,
+{
+    pub inner: C,
+}
+
+impl<C> Criteria for NotCriteria<C>
+where
+    C: Criteria,
+{
+    // a comparison with null is neither true nor false in sql, `matches` takes
+    // it as false so the negation has to as well
+    fn build(&self) -> (String, Vec<Value>) {
+        let (q, v) = self.inner.build();
+        (format!("(not coalesce(({}), false))", q), v)
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        !self.inner.matches(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::blocking;
+
+    #[test]
+    fn blocking_outside_runtime() {
+        assert_eq!(blocking(|| 1), 1);
+    }
+
+    #[tokio::test]
+    async fn blocking_on_current_thread_runtime() {
+        assert_eq!(blocking(|| 1), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_on_multi_thread_runtime() {
+        assert_eq!(blocking(|| 1), 1);
+    }
 }
@@ -0,0 +1,311 @@
+use std::cmp::Ordering;
+
+use sqlite::Value;
+
+use crate::types::ColumnType;
+
+use super::{
+    AndCriteria, Comparison, ComparisonCriteria, Criteria, EqualsCriteria, NullCriteria,
+    OrCriteria, Row, compare, filter::Filter,
+};
+
+// One of the keys a list is ordered by
+#[derive(Debug, Clone)]
+pub struct Sort {
+    // put into the sql as is, so one of the type's columns
+    pub col: String,
+    pub descending: bool,
+}
+
+// Which part of a list to read & in what order. Nulls come first ascending &
+// last descending, whatever the store.
+#[derive(Debug, Default)]
+pub struct Page {
+    // the last key should be unique & never null, usually the id, so every row
+    // has one place. Ordered by id when empty
+    pub sort: Vec<Sort>,
+    // rows past a previous page's last, see `Page::after`
+    pub after: Option<Filter>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    // also count every row the queries match, wherever the page starts
+    pub count: bool,
+}
+
+#[derive(Debug)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    // only when the page asked for a count
+    pub total: Option<i64>,
+}
+
+impl Page {
+    // the rows that come after the one whose `sort` keys are `key`, None when
+    // none can, i.e. every key is a null sorted descending
+    pub fn after(sort: &[Sort], key: &[Value]) -> Option<Filter> {
+        let mut after: Option<Box<dyn Criteria>> = None;
+        for (i, (s, val)) in sort.iter().zip(key).enumerate() {
+            // equal on the keys before this one & past it on this one
+            let mut past = match past(s, val) {
+                Some(past) => past,
+                None => continue,
+            };
+            for (s, val) in sort[..i].iter().zip(key) {
+                past = Box::new(AndCriteria {
+                    left: equal(s, val),
+                    right: past,
+                });
+            }
+            after = Some(match after {
+                Some(left) => Box::new(OrCriteria { left, right: past }),
+                None => past,
+            });
+        }
+        after.map(Filter::from_criteria)
+    }
+
+    // sql ORDER BY clause, `id_col` when there's nothing to sort by. text
+    // columns in `cols` sort bytewise, like their cursor comparisons
+    pub(crate) fn order_by(&self, id_col: &str, cols: &[(&str, ColumnType)]) -> String {
+        if self.sort.is_empty() {
+            return format!(" ORDER BY {}", id_col);
+        }
+        let keys = self
+            .sort
+            .iter()
+            .map(|s| {
+                let collate = match cols
+                    .iter()
+                    .any(|(c, t)| *c == s.col && *t == ColumnType::Text)
+                {
+                    true => " COLLATE BINARY",
+                    false => "",
+                };
+                match s.descending {
+                    false => format!("{}{} ASC NULLS FIRST", s.col, collate),
+                    true => format!("{}{} DESC NULLS LAST", s.col, collate),
+                }
+            })
+            .collect::<Vec<_>>();
+        format!(" ORDER BY {}", keys.join(", "))
+    }
+
+    // the order of two rows, for stores that sort themselves
+    pub(crate) fn cmp(&self, left: &Row, right: &Row, id_col: &str) -> Ordering {
+        if self.sort.is_empty() {
+            return cmp_col(left, right, id_col);
+        }
+        self.sort
+            .iter()
+            .map(|s| match s.descending {
+                false => cmp_col(left, right, &s.col),
+                true => cmp_col(left, right, &s.col).reverse(),
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+// nulls first
+fn cmp_col(left: &Row, right: &Row, col: &str) -> Ordering {
+    match (left.get(col), right.get(col)) {
+        (None | Some(Value::Null), None | Some(Value::Null)) => Ordering::Equal,
+        (None | Some(Value::Null), _) => Ordering::Less,
+        (_, None | Some(Value::Null)) => Ordering::Greater,
+        (Some(l), Some(r)) => compare(l, r).unwrap_or(Ordering::Equal),
+    }
+}
+
+fn equal(s: &Sort, val: &Value) -> Box<dyn Criteria> {
+    match val {
+        Value::Null => Box::new(NullCriteria {
+            field: s.col.clone(),
+            null: true,
+        }),
+        val => Box::new(EqualsCriteria {
+            field: s.col.clone(),
+            val: val.clone(),
+        }),
+    }
+}
+
+// None when nothing comes after `val`, a null sorted descending
+fn past(s: &Sort, val: &Value) -> Option<Box<dyn Criteria>> {
+    let field = s.col.clone();
+    match (s.descending, val) {
+        (false, Value::Null) => Some(Box::new(NullCriteria { field, null: false })),
+        (false, val) => Some(Box::new(ComparisonCriteria {
+            field,
+            comparison: Comparison::Greater,
+            val: val.clone(),
+        })),
+        (true, Value::Null) => None,
+        (true, val) => Some(Box::new(OrCriteria {
+            left: ComparisonCriteria {
+                field: field.clone(),
+                comparison: Comparison::Less,
+                val: val.clone(),
+            },
+            right: NullCriteria { field, null: true },
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Query;
+
+    // ties & nulls on both name & rank
+    fn rows() -> Vec<Row> {
+        let names = [
+            Some("b"),
+            None,
+            Some("a"),
+            Some("b"),
+            None,
+            Some("a"),
+            Some("c"),
+            Some("b"),
+        ];
+        let ranks = [
+            Some(2),
+            Some(1),
+            None,
+            Some(1),
+            Some(1),
+            Some(2),
+            None,
+            None,
+        ];
+        names
+            .iter()
+            .zip(ranks)
+            .enumerate()
+            .map(|(i, (name, rank))| {
+                Row::from([
+                    ("id".to_string(), Value::Integer(i as i64 + 1)),
+                    (
+                        "name".to_string(),
+                        name.map(|n| Value::String(n.to_string()))
+                            .unwrap_or(Value::Null),
+                    ),
+                    (
+                        "rank".to_string(),
+                        rank.map(Value::Integer).unwrap_or(Value::Null),
+                    ),
+                ])
+            })
+            .collect()
+    }
+
+    fn id(row: &Row) -> i64 {
+        match row.get("id") {
+            Some(Value::Integer(id)) => *id,
+            _ => panic!("no id"),
+        }
+    }
+
+    // the keys, then the id when they don't include it
+    fn page(keys: &[(&str, bool)]) -> Page {
+        let mut sort = keys
+            .iter()
+            .map(|(col, descending)| Sort {
+                col: col.to_string(),
+                descending: *descending,
+            })
+            .collect::<Vec<_>>();
+        if !keys.iter().any(|(col, _)| *col == "id") {
+            sort.push(Sort {
+                col: "id".to_string(),
+                descending: false,
+            });
+        }
+        Page {
+            sort,
+            ..Page::default()
+        }
+    }
+
+    fn sorted(page: &Page) -> Vec<Row> {
+        let mut rows = rows();
+        rows.sort_by(|left, right| page.cmp(left, right, "id"));
+        rows
+    }
+
+    #[test]
+    fn cmp_puts_nulls_first_ascending_and_last_descending() {
+        let ids = |keys| sorted(&page(keys)).iter().map(id).collect::<Vec<_>>();
+        assert_eq!(ids(&[("name", false)]), vec![2, 5, 3, 6, 1, 4, 8, 7]);
+        assert_eq!(ids(&[("name", true)]), vec![7, 1, 4, 8, 3, 6, 2, 5]);
+        assert_eq!(
+            ids(&[("name", true), ("id", true)]),
+            vec![7, 8, 4, 1, 6, 3, 5, 2]
+        );
+        assert_eq!(ids(&[]), (1..=8).collect::<Vec<_>>());
+    }
+
+    // every row's key picks out exactly the rows sorted after it
+    #[test]
+    fn after_continues_where_the_key_left_off() {
+        let sorts: &[&[(&str, bool)]] = &[
+            &[("name", false)],
+            &[("name", true)],
+            &[("rank", false)],
+            &[("rank", true)],
+            &[("name", false), ("rank", true)],
+            &[("rank", true), ("name", false)],
+            &[("name", true), ("rank", false)],
+            &[("name", true), ("id", true)],
+            &[("rank", true), ("name", true), ("id", true)],
+        ];
+        for keys in sorts {
+            let page = page(keys);
+            let rows = sorted(&page);
+            for (i, row) in rows.iter().enumerate() {
+                let key = page
+                    .sort
+                    .iter()
+                    .map(|s| row.get(&s.col).cloned().unwrap())
+                    .collect::<Vec<_>>();
+                let after = match Page::after(&page.sort, &key) {
+                    Some(after) => rows
+                        .iter()
+                        .filter(|r| after.matches(r))
+                        .map(id)
+                        .collect::<Vec<_>>(),
+                    None => vec![],
+                };
+                let expected = rows[i + 1..].iter().map(id).collect::<Vec<_>>();
+                assert_eq!(after, expected, "sorted by {:?} after {:?}", keys, key);
+            }
+        }
+    }
+
+    #[test]
+    fn after_nothing_past_a_descending_null() {
+        let sort = [Sort {
+            col: "name".to_string(),
+            descending: true,
+        }];
+        assert!(Page::after(&sort, &[Value::Null]).is_none());
+    }
+
+    #[test]
+    fn order_by_clause() {
+        let cols = [
+            ("id", ColumnType::Integer),
+            ("name", ColumnType::Text),
+            ("rank", ColumnType::Integer),
+        ];
+        assert_eq!(
+            page(&[]).order_by("id", &cols),
+            " ORDER BY id ASC NULLS FIRST"
+        );
+        assert_eq!(Page::default().order_by("id", &cols), " ORDER BY id");
+        assert_eq!(
+            page(&[("name", true), ("rank", false)]).order_by("id", &cols),
+            " ORDER BY name COLLATE BINARY DESC NULLS LAST, rank ASC NULLS FIRST, id ASC NULLS FIRST"
+        );
+    }
+}
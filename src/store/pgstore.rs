@@ -0,0 +1,464 @@
+use bytes::BytesMut;
+use postgres::{
+    NoTls,
+    types::{IsNull, ToSql, Type, to_sql_checked},
+};
+use r2d2_postgres::{PostgresConnectionManager, r2d2};
+use sqlite::Value;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
+
+use crate::{RequestObject, config::DbConfig, types::DataObject};
+
+use super::{
+    AsyncStore, Cancellation, Query, QueryTypes, Row, Store, blocking,
+    codec::{self, Codec},
+    error::{StoreError, StoreResult},
+    migrations::{self, AppliedMigration, Migrate, Migration, MigrationError},
+    page::{Page, Paged},
+    spawn_blocking,
+};
+
+type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+/// Postgres backend with a connection pool.
+///
+/// The postgres client blocks on a runtime of its own. Its `AsyncStore` calls
+/// run on tokio's blocking pool, but `PgStore::new` & the `Store` calls made
+/// from async code need tokio's multi threaded runtime (the default for
+/// `#[tokio::main]`), a current thread runtime panics on them.
+#[derive(Clone)]
+pub struct PgStore {
+    pool: Pool,
+    codec: Arc<Mutex<Codec>>,
+    // checked once a connection is available, set for async calls only
+    cancellation: Cancellation,
+}
+
+impl PgStore {
+    pub fn new(config: &DbConfig) -> StoreResult<Self> {
+        let pg_config = config.get_connection_string().parse().map_err(|e| {
+            error!("Invalid postgres connection string: {:?}", e);
+            StoreError::from(e)
+        })?;
+        let manager = PostgresConnectionManager::new(pg_config, NoTls);
+        let pool = blocking(|| {
+            r2d2::Pool::builder()
+                .max_size(config.pool_size)
+                .build(manager)
+        })
+        .map_err(|e| {
+            error!("Could not connect to postgres: {:?}", e);
+            StoreError::from(e)
+        })?;
+        Ok(Self {
+            pool,
+            codec: Arc::new(Mutex::new(Codec::new()?)),
+            cancellation: Cancellation::default(),
+        })
+    }
+
+    // a handle for one async call, running `f` on the blocking pool
+    async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&PgStore) -> StoreResult<R> + Send + 'static,
+    ) -> StoreResult<R> {
+        let store = self.clone();
+        spawn_blocking(move |cancellation| {
+            f(&PgStore {
+                cancellation,
+                ..store
+            })
+        })
+        .await
+    }
+
+    fn query(&self, query: &str, vals: &[Value]) -> StoreResult<Vec<Row>> {
+        debug!("{}", query);
+        blocking(|| {
+            let mut client = self.pool.get()?;
+            if self.cancellation.is_cancelled() {
+                debug!("caller went away, skipping query");
+                return Err(StoreError::Unavailable("cancelled".to_string()));
+            }
+            let params = vals.iter().map(param).collect::<Vec<_>>();
+            let params = params.iter().map(|p| p.as_ref()).collect::<Vec<_>>();
+            let rows = client.query(query, &params)?;
+            Ok(rows.iter().map(row).collect())
+        })
+    }
+
+    fn objects<T: DataObject>(&self, rows: Vec<Row>) -> StoreResult<Vec<T>> {
+        let codec = self.codec.lock()?;
+        rows.iter().map(|row| codec.object(row)).collect()
+    }
+
+    fn first<T: DataObject>(&self, rows: Vec<Row>) -> StoreResult<Option<T>> {
+        Ok(self.objects(rows)?.into_iter().next())
+    }
+
+    fn request_row<R: RequestObject>(&self, data: R) -> StoreResult<Row> {
+        self.codec.lock()?.request_row(data)
+    }
+}
+
+// The criteria are written for sqlite, postgres numbers its placeholders, needs
+// ILIKE for the same case insensitive matching & "C" for bytewise collation
+fn clause(clause: &str, next: &mut usize) -> String {
+    let mut res = String::with_capacity(clause.len());
+    for c in dialect(clause).chars() {
+        if c == '?' {
+            *next += 1;
+            res.push_str(&format!("${}", next));
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn dialect(sql: &str) -> String {
+    sql.replace(" LIKE ", " ILIKE ")
+        .replace(" COLLATE BINARY", " COLLATE \"C\"")
+}
+
+// the where clause the queries make & the values it binds, numbering its
+// placeholders after the `bound` values already in the statement
+fn where_clause<'a>(
+    queries: impl Iterator<Item = &'a QueryTypes>,
+    bound: usize,
+) -> (String, Vec<Value>) {
+    let mut clauses = vec![];
+    let mut vals = vec![];
+    let mut next = bound;
+    for q in queries {
+        let (sql, q_vals) = q.build();
+        clauses.push(clause(&sql, &mut next));
+        vals.extend(q_vals);
+    }
+    if clauses.is_empty() {
+        return (String::new(), vals);
+    }
+    (format!(" where {}", clauses.join(" and ")), vals)
+}
+
+fn param(val: &Value) -> Box<dyn ToSql + Sync> {
+    match val {
+        Value::Integer(i) => Box::new(*i),
+        Value::Float(f) => Box::new(*f),
+        Value::String(s) => Box::new(s.clone()),
+        Value::Binary(b) => Box::new(b.clone()),
+        Value::Null => Box::new(Null),
+    }
+}
+
+// a null of whatever type postgres expects, it types the parameter from the
+// column so a typed None would be rejected by every other column type
+#[derive(Debug)]
+struct Null;
+
+impl ToSql for Null {
+    fn to_sql(
+        &self,
+        _: &Type,
+        _: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+fn row(pg_row: &postgres::Row) -> Row {
+    pg_row
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(i, col)| {
+            let val = match *col.type_() {
+                Type::INT8 => pg_row.get::<_, Option<i64>>(i).map(Value::Integer),
+                Type::INT4 => pg_row
+                    .get::<_, Option<i32>>(i)
+                    .map(|v| Value::Integer(v as i64)),
+                Type::INT2 => pg_row
+                    .get::<_, Option<i16>>(i)
+                    .map(|v| Value::Integer(v as i64)),
+                Type::BOOL => pg_row
+                    .get::<_, Option<bool>>(i)
+                    .map(|v| Value::Integer(v as i64)),
+                Type::FLOAT8 => pg_row.get::<_, Option<f64>>(i).map(Value::Float),
+                Type::BYTEA => pg_row.get::<_, Option<Vec<u8>>>(i).map(Value::Binary),
+                _ => pg_row.get::<_, Option<String>>(i).map(Value::String),
+            };
+            (col.name().to_string(), val.unwrap_or(Value::Null))
+        })
+        .collect()
+}
+
+impl Store for PgStore {
+    fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        let (cols, vals): (Vec<String>, Vec<Value>) = self.request_row(data)?.into_iter().unzip();
+        let query = if cols.is_empty() {
+            format!(
+                "INSERT INTO {} DEFAULT VALUES returning {}",
+                T::table_name(),
+                T::sql_cols()
+            )
+        } else {
+            let placeholders = (1..=cols.len())
+                .map(|i| format!("${}", i))
+                .collect::<Vec<String>>();
+            format!(
+                "INSERT INTO {}({}) VALUES ({}) returning {}",
+                T::table_name(),
+                cols.join(","),
+                placeholders.join(","),
+                T::sql_cols()
+            )
+        };
+        self.first(self.query(&query, &vals)?)?
+            .ok_or(StoreError::NotCreated)
+    }
+
+    fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        Store::update_if(self, data, vec![])?.ok_or(StoreError::NotFound)
+    }
+
+    fn update_if<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+        queries: Vec<QueryTypes>,
+    ) -> StoreResult<Option<T>> {
+        let id = data
+            .id()
+            .ok_or(StoreError::Constraint("id is required".to_string()))?;
+        let mut changes = self.request_row(data)?;
+        changes.remove(&T::id_col());
+        let (cols, mut vals): (Vec<String>, Vec<Value>) = changes.into_iter().unzip();
+        let sets = cols
+            .iter()
+            .enumerate()
+            .map(|(i, col)| format!("{} = ${}", col, i + 1))
+            .collect::<Vec<String>>();
+        vals.push(Value::Integer(id));
+        let (clauses, q_vals) = where_clause(queries.iter(), vals.len());
+        let condition = format!(
+            "{}{} {} = ${}",
+            clauses,
+            if clauses.is_empty() { " where" } else { " and" },
+            T::id_col(),
+            cols.len() + 1
+        );
+        vals.extend(q_vals);
+        // nothing to set, but the object still has to match
+        let query = if sets.is_empty() {
+            format!(
+                "SELECT {} FROM {}{}",
+                T::sql_cols(),
+                T::table_name(),
+                condition
+            )
+        } else {
+            format!(
+                "UPDATE {} SET {}{} returning {}",
+                T::table_name(),
+                sets.join(", "),
+                condition,
+                T::sql_cols()
+            )
+        };
+        self.first(self.query(&query, &vals)?)
+    }
+
+    fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
+        let query = format!(
+            "SELECT {} FROM {} where {} = $1",
+            T::sql_cols(),
+            T::table_name(),
+            T::id_col()
+        );
+        self.first(self.query(&query, &[Value::Integer(id)])?)
+    }
+
+    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        let (clauses, vals) = where_clause(queries.iter(), 0);
+        // sqlite hands rows back in insertion order without being asked
+        let query = format!(
+            "SELECT {} FROM {}{} ORDER BY {}",
+            T::sql_cols(),
+            T::table_name(),
+            clauses,
+            T::id_col()
+        );
+        self.objects(self.query(&query, &vals)?)
+    }
+
+    fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        let total = match page.count {
+            true => {
+                let (clauses, vals) = where_clause(queries.iter(), 0);
+                let query = format!(
+                    "SELECT COUNT(*) AS total FROM {}{}",
+                    T::table_name(),
+                    clauses
+                );
+                match self
+                    .query(&query, &vals)?
+                    .pop()
+                    .and_then(|row| row.get("total").cloned())
+                {
+                    Some(Value::Integer(total)) => Some(total),
+                    _ => return Err(StoreError::Decode("count is not an integer".to_string())),
+                }
+            }
+            false => None,
+        };
+        let order_by = dialect(&page.order_by(&T::id_col(), T::filter_cols()));
+        let after = page.after.map(QueryTypes::Filter);
+        let (clauses, vals) = where_clause(queries.iter().chain(after.iter()), 0);
+        let query = format!(
+            "SELECT {} FROM {}{}{} LIMIT {} OFFSET {}",
+            T::sql_cols(),
+            T::table_name(),
+            clauses,
+            order_by,
+            page.limit
+                .map(|l| l.to_string())
+                .unwrap_or_else(|| "ALL".to_string()),
+            page.offset
+        );
+        let items = self.objects(self.query(&query, &vals)?)?;
+        Ok(Paged { items, total })
+    }
+
+    fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        let query = format!(
+            "DELETE FROM {} where {} = $1 returning {}",
+            T::table_name(),
+            T::id_col(),
+            T::sql_cols()
+        );
+        self.first(self.query(&query, &[Value::Integer(id)])?)?
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn matches<T: DataObject>(&self, snapshot: &str, queries: &[QueryTypes]) -> StoreResult<bool> {
+        match codec::json_row(snapshot) {
+            Some(row) => Ok(queries.iter().all(|q| q.matches(&row))),
+            None => Err(StoreError::Decode("snapshot is not an object".to_string())),
+        }
+    }
+}
+
+impl AsyncStore for PgStore {
+    async fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        self.run(|store| Store::create(store, data)).await
+    }
+
+    async fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        self.run(|store| Store::update(store, data)).await
+    }
+
+    async fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
+        self.run(move |store| Store::get(store, id)).await
+    }
+
+    async fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        self.run(|store| Store::get_queries(store, queries)).await
+    }
+
+    async fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        self.run(|store| Store::get_page(store, queries, page))
+            .await
+    }
+
+    async fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        self.run(move |store| Store::delete(store, id)).await
+    }
+}
+
+fn migration_error(e: impl std::fmt::Display) -> MigrationError {
+    MigrationError::Sql(e.to_string())
+}
+
+impl Migrate for PgStore {
+    fn migrations(&self) -> &'static [Migration] {
+        migrations::POSTGRES
+    }
+
+    fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError> {
+        blocking(|| {
+            let mut client = self.pool.get().map_err(migration_error)?;
+            client
+                .batch_execute(
+                    "CREATE TABLE IF NOT EXISTS schema_migrations (
+                        version bigint primary key,
+                        name text not null,
+                        checksum text not null,
+                        applied_at bigint not null)",
+                )
+                .map_err(migration_error)?;
+            let rows = client
+                .query("SELECT * FROM schema_migrations ORDER BY version", &[])
+                .map_err(migration_error)?;
+            Ok(rows
+                .iter()
+                .map(|row| AppliedMigration {
+                    version: row.get("version"),
+                    name: row.get("name"),
+                    checksum: row.get("checksum"),
+                    applied_at: row.get("applied_at"),
+                })
+                .collect())
+        })
+    }
+
+    fn apply(&self, migration: &Migration) -> Result<(), MigrationError> {
+        blocking(|| {
+            let mut client = self.pool.get().map_err(migration_error)?;
+            let mut transaction = client.transaction().map_err(migration_error)?;
+            transaction
+                .batch_execute(migration.up)
+                .map_err(migration_error)?;
+            transaction
+                .execute(
+                    "INSERT INTO schema_migrations(version, name, checksum, applied_at) VALUES ($1, $2, $3, $4)",
+                    &[
+                        &migration.version,
+                        &migration.name,
+                        &migration.checksum(),
+                        &time::OffsetDateTime::now_utc().unix_timestamp(),
+                    ],
+                )
+                .map_err(migration_error)?;
+            transaction.commit().map_err(migration_error)
+        })
+    }
+
+    fn revert(&self, migration: &Migration, down: &str) -> Result<(), MigrationError> {
+        blocking(|| {
+            let mut client = self.pool.get().map_err(migration_error)?;
+            let mut transaction = client.transaction().map_err(migration_error)?;
+            transaction.batch_execute(down).map_err(migration_error)?;
+            transaction
+                .execute(
+                    "DELETE FROM schema_migrations where version = $1",
+                    &[&migration.version],
+                )
+                .map_err(migration_error)?;
+            transaction.commit().map_err(migration_error)
+        })
+    }
+}
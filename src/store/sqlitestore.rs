@@ -1,21 +1,121 @@
-use sqlite::{Connection, Value};
-use std::sync::Mutex;
-use tracing::debug;
+use r2d2::ManageConnection;
+use sqlite::{Connection, OpenFlags, Value};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
 
-use crate::{RequestObject, types::DataObject};
+use crate::{RequestObject, config::SqliteConfig, types::DataObject};
 
-use super::{Query, QueryTypes, Store, error::StoreResult};
+use super::{
+    AsyncStore, Cancellation, Query, QueryTypes, Store, blocking,
+    error::{StoreError, StoreResult},
+    migrations::{self, AppliedMigration, Migrate, Migration, MigrationError},
+    page::{Page, Paged},
+    spawn_blocking,
+};
 
+// Writes go through a single connection, reads through a pool of read only
+// connections so they don't wait on each other, or on the writer in WAL mode.
+#[derive(Clone)]
 pub struct SqliteStore {
-    conn: Mutex<Connection>,
+    writer: Arc<Mutex<Connection>>,
+    // None for `:memory:`, every connection would get its own database
+    readers: Option<r2d2::Pool<SqliteConnectionManager>>,
+    // checked once a connection is available, set for async calls only
+    cancellation: Cancellation,
 }
 
 impl SqliteStore {
-    pub fn new() -> Self {
-        let connection = sqlite::open("test.db").unwrap();
-        Self {
-            conn: Mutex::new(connection),
+    pub fn new(config: &SqliteConfig) -> StoreResult<Self> {
+        // opened first, it creates the file the readers open
+        let writer = Self::open(config).map_err(|e| {
+            error!("Could not open {}: {:?}", config.path, e);
+            StoreError::from(e)
+        })?;
+        let readers = if config.path == ":memory:" || config.pool_size == 0 {
+            None
+        } else {
+            let manager = SqliteConnectionManager {
+                config: SqliteConfig {
+                    read_only: true,
+                    ..config.clone()
+                },
+            };
+            let pool = r2d2::Pool::builder()
+                .max_size(config.pool_size)
+                .build(manager)
+                .map_err(|e| {
+                    error!("Could not open {}: {:?}", config.path, e);
+                    StoreError::from(e)
+                })?;
+            Some(pool)
+        };
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            readers,
+            cancellation: Cancellation::default(),
+        })
+    }
+
+    // a handle for one async call, running `f` on the blocking pool
+    async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&SqliteStore) -> StoreResult<R> + Send + 'static,
+    ) -> StoreResult<R> {
+        let store = self.clone();
+        spawn_blocking(move |cancellation| {
+            f(&SqliteStore {
+                cancellation,
+                ..store
+            })
+        })
+        .await
+    }
+
+    fn connected<R>(
+        &self,
+        conn: &Connection,
+        f: impl FnOnce(&Connection) -> StoreResult<R>,
+    ) -> StoreResult<R> {
+        if self.cancellation.is_cancelled() {
+            debug!("caller went away, skipping query");
+            return Err(StoreError::Unavailable("cancelled".to_string()));
         }
+        f(conn)
+    }
+
+    fn write<R>(&self, f: impl FnOnce(&Connection) -> StoreResult<R>) -> StoreResult<R> {
+        blocking(|| self.connected(&*self.writer.lock()?, f))
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&Connection) -> StoreResult<R>) -> StoreResult<R> {
+        let pool = match &self.readers {
+            Some(pool) => pool,
+            None => return self.write(f),
+        };
+        blocking(|| self.connected(&*pool.get()?, f))
+    }
+
+    fn open(config: &SqliteConfig) -> sqlite::Result<Connection> {
+        let flags = if config.read_only {
+            OpenFlags::new().with_read_only()
+        } else {
+            OpenFlags::new().with_create().with_read_write()
+        };
+        let mut connection = Connection::open_with_flags(&config.path, flags)?;
+        connection.set_busy_timeout(config.busy_timeout.whole_milliseconds().max(0) as usize)?;
+        connection.execute(format!(
+            "PRAGMA foreign_keys = {}; PRAGMA synchronous = {};",
+            if config.foreign_keys { "ON" } else { "OFF" },
+            config.synchronous.as_str()
+        ))?;
+        // changing the journal mode writes to the database
+        if !config.read_only {
+            connection.execute(format!(
+                "PRAGMA journal_mode = {}",
+                config.journal_mode.as_str()
+            ))?;
+        }
+        Ok(connection)
     }
 }
 
@@ -29,114 +129,386 @@ impl Store for SqliteStore {
             T::sql_cols()
         );
         debug!("{}", query);
-        if let Ok(conn) = self.conn.lock() {
-            let mut statement = conn.prepare(query).unwrap();
-            statement.bind(data).unwrap();
-            let data: Vec<T> = T::from_rows(&mut statement);
-            if data.len() >= 1 {
-                Ok(data[0].clone())
-            } else {
-                Err(super::error::StoreError::NotCreated)
-            }
-        } else {
-            Err(super::error::StoreError::NotCreated)
-        }
+        self.write(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind(data)?;
+            T::from_rows(&mut statement)?
+                .pop()
+                .ok_or(StoreError::NotCreated)
+        })
     }
 
     fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
-        let id = match data.id() {
-            Some(id) => id,
-            None => {
-                println!("No id on request onject");
-                return Err(crate::store::error::StoreError::NotCreated);
-            }
+        Store::update_if(self, data, vec![])?.ok_or(StoreError::NotFound)
+    }
+
+    fn update_if<R: RequestObject, T: DataObject>(
+        &self,
+        data: R,
+        queries: Vec<QueryTypes>,
+    ) -> StoreResult<Option<T>> {
+        let id = data
+            .id()
+            .ok_or(StoreError::Constraint("id is required".to_string()))?;
+        let cols = data.sql_cols();
+        // the query values are bound after the request's
+        let set = match cols.is_empty() {
+            true => 0,
+            false => cols.split(',').count(),
+        };
+        let (clauses, bindables) = where_clause(queries.iter());
+        let bindables = bindables
+            .into_iter()
+            .map(|(i, v)| (i + set, v))
+            .collect::<Vec<_>>();
+        let condition = format!(
+            "{} {} {} = :id",
+            clauses,
+            if clauses.is_empty() { "where" } else { "and" },
+            T::id_col(),
+        );
+        // nothing to set, but the object still has to match
+        let query = match cols.is_empty() {
+            true => format!("SELECT * FROM {}{}", T::table_name(), condition),
+            false => format!(
+                "UPDATE {} SET ({}) = ({}){} returning {}",
+                T::table_name(),
+                cols,
+                data.sql_placeholders(),
+                condition,
+                T::sql_cols()
+            ),
         };
+        debug!("{}", query);
+        self.write(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind(data)?;
+            statement.bind::<&[(_, Value)]>(bindables.as_slice())?;
+            statement.bind((":id", id))?;
+            Ok(T::from_rows(&mut statement)?.pop())
+        })
+    }
+
+    fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
         let query = format!(
-            "UPDATE {} SET ({}) = ({}) where {} = :id returning {}",
+            "SELECT * FROM {} where {} = ?",
+            T::table_name(),
+            T::id_col()
+        );
+        self.read(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind((1, id))?;
+            Ok(T::from_rows(&mut statement)?.pop())
+        })
+    }
+
+    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        let (clauses, bindables) = where_clause(queries.iter());
+        let query = format!("SELECT * FROM {}{}", T::table_name(), clauses);
+        debug!("{}", query);
+        self.read(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind::<&[(_, Value)]>(bindables.as_slice())?;
+            T::from_rows(&mut statement)
+        })
+    }
+
+    fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        let count = page.count.then(|| {
+            let (clauses, bindables) = where_clause(queries.iter());
+            let query = format!(
+                "SELECT COUNT(*) AS total FROM {}{}",
+                T::table_name(),
+                clauses
+            );
+            (query, bindables)
+        });
+        let order_by = page.order_by(&T::id_col(), T::filter_cols());
+        let after = page.after.map(QueryTypes::Filter);
+        let (clauses, bindables) = where_clause(queries.iter().chain(after.iter()));
+        // a negative limit is no limit
+        let query = format!(
+            "SELECT * FROM {}{}{} LIMIT {} OFFSET {}",
+            T::table_name(),
+            clauses,
+            order_by,
+            page.limit.map(|l| l as i64).unwrap_or(-1),
+            page.offset
+        );
+        debug!("{}", query);
+        self.read(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind::<&[(_, Value)]>(bindables.as_slice())?;
+            let items = T::from_rows(&mut statement)?;
+            let total = match count {
+                Some((query, bindables)) => {
+                    let mut statement = conn.prepare(query)?;
+                    statement.bind::<&[(_, Value)]>(bindables.as_slice())?;
+                    statement.next()?;
+                    Some(statement.read::<i64, _>("total")?)
+                }
+                None => None,
+            };
+            Ok(Paged { items, total })
+        })
+    }
+
+    fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        let query = format!(
+            "DELETE FROM {} where {} = ? returning {}",
             T::table_name(),
-            data.sql_cols(),
-            data.sql_placeholders(),
             T::id_col(),
             T::sql_cols()
         );
-        if let Ok(conn) = self.conn.lock() {
-            let mut statement = conn.prepare(query).unwrap();
-            statement.bind(data).unwrap();
-            statement.bind((":id", id)).unwrap();
-            let data: Vec<T> = T::from_rows(&mut statement);
-            if data.len() >= 1 {
-                Ok(data[0].clone())
-            } else {
-                Err(super::error::StoreError::NotCreated)
-            }
-        } else {
-            Err(super::error::StoreError::NotCreated)
-        }
+        self.write(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind((1, id))?;
+            T::from_rows(&mut statement)?
+                .pop()
+                .ok_or(StoreError::NotFound)
+        })
     }
 
-    fn get<T: DataObject>(&self, id: i64) -> Option<T> {
-        let query = format!("SELECT * FROM {} where id = ?", T::table_name());
-        if let Ok(conn) = self.conn.lock() {
-            let mut statement = conn.prepare(query).unwrap();
-            statement.bind((1, id)).unwrap();
-            let data: Vec<T> = T::from_rows(&mut statement);
-            if data.len() >= 1 {
-                Some(data[0].clone())
-            } else {
-                None
-            }
-        } else {
-            None
+    fn matches<T: DataObject>(&self, snapshot: &str, queries: &[QueryTypes]) -> StoreResult<bool> {
+        if queries.is_empty() {
+            return Ok(true);
         }
-    }
-
-    fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> Vec<T> {
+        // the snapshot stands in for the table so the queries apply unchanged
+        let cols = T::sql_cols()
+            .split(',')
+            .map(|col| format!("json_extract(?1, '$.{}') AS {}", col, col))
+            .collect::<Vec<String>>()
+            .join(", ");
         let mut clauses = vec![];
-        let mut bindables = vec![];
-        let mut i = 0;
+        let mut bindables = vec![(1, Value::String(snapshot.to_string()))];
         for q in queries.iter() {
             let (clause, vals) = q.build();
             clauses.push(clause);
-            vals.into_iter().for_each(|v| {
-                bindables.push((i + 1, v));
-                i += 1;
-            });
-        }
-        let mut query = format!("SELECT * FROM {}", T::table_name());
-        if clauses.len() > 0 {
-            let clauses_str = format!(" where {}", clauses.join(" and "));
-            query.push_str(clauses_str.as_str());
+            vals.into_iter()
+                .for_each(|v| bindables.push((bindables.len() + 1, v)));
         }
+        let query = format!(
+            "SELECT 1 FROM (SELECT {}) where {}",
+            cols,
+            clauses.join(" and ")
+        );
         debug!("{}", query);
-        if let Ok(conn) = self.conn.lock() {
-            let mut statement = conn.prepare(query).unwrap();
-            statement
-                .bind::<&[(_, Value)]>(&bindables.as_slice()[..])
-                .unwrap();
-            let data: Vec<T> = T::from_rows(&mut statement);
-            data
-        } else {
-            vec![]
-        }
+        self.read(|conn| {
+            let mut statement = conn.prepare(query)?;
+            statement.bind::<&[(_, Value)]>(bindables.as_slice())?;
+            Ok(matches!(statement.next()?, sqlite::State::Row))
+        })
     }
+}
 
-    fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
-        let query = format!(
-            "DELETE FROM {} where id = ? returning {}",
-            T::table_name(),
-            T::sql_cols()
-        );
-        if let Ok(conn) = self.conn.lock() {
-            let mut statement = conn.prepare(query).unwrap();
-            statement.bind((1, id)).unwrap();
-            let data: Vec<T> = T::from_rows(&mut statement);
-            if data.len() >= 1 {
-                Ok(data[0].clone())
-            } else {
-                Err(super::error::StoreError::NotFound)
+// the sql where clause the queries make & the values it binds
+fn where_clause<'a>(
+    queries: impl Iterator<Item = &'a QueryTypes>,
+) -> (String, Vec<(usize, Value)>) {
+    let mut clauses = vec![];
+    let mut bindables = vec![];
+    for q in queries {
+        let (clause, vals) = q.build();
+        clauses.push(clause);
+        vals.into_iter()
+            .for_each(|v| bindables.push((bindables.len() + 1, v)));
+    }
+    if clauses.is_empty() {
+        return (String::new(), bindables);
+    }
+    (format!(" where {}", clauses.join(" and ")), bindables)
+}
+
+impl AsyncStore for SqliteStore {
+    async fn create<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        self.run(|store| Store::create(store, data)).await
+    }
+
+    async fn update<R: RequestObject, T: DataObject>(&self, data: R) -> StoreResult<T> {
+        self.run(|store| Store::update(store, data)).await
+    }
+
+    async fn get<T: DataObject>(&self, id: i64) -> StoreResult<Option<T>> {
+        self.run(move |store| Store::get(store, id)).await
+    }
+
+    async fn get_queries<T: DataObject>(&self, queries: Vec<QueryTypes>) -> StoreResult<Vec<T>> {
+        self.run(|store| Store::get_queries(store, queries)).await
+    }
+
+    async fn get_page<T: DataObject>(
+        &self,
+        queries: Vec<QueryTypes>,
+        page: Page,
+    ) -> StoreResult<Paged<T>> {
+        self.run(|store| Store::get_page(store, queries, page))
+            .await
+    }
+
+    async fn delete<T: DataObject>(&self, id: i64) -> StoreResult<T> {
+        self.run(move |store| Store::delete(store, id)).await
+    }
+}
+
+impl SqliteStore {
+    // runs `f` in a transaction, rolling back when it fails
+    fn transaction(
+        conn: &Connection,
+        f: impl FnOnce(&Connection) -> sqlite::Result<()>,
+    ) -> sqlite::Result<()> {
+        conn.execute("BEGIN")?;
+        match f(conn) {
+            Ok(()) => conn.execute("COMMIT"),
+            Err(e) => {
+                conn.execute("ROLLBACK")?;
+                Err(e)
             }
-        } else {
-            Err(super::error::StoreError::NotFound)
         }
     }
 }
+
+fn migration_error(e: impl std::fmt::Display) -> MigrationError {
+    MigrationError::Sql(e.to_string())
+}
+
+impl Migrate for SqliteStore {
+    fn migrations(&self) -> &'static [Migration] {
+        migrations::SQLITE
+    }
+
+    fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError> {
+        let conn = self.writer.lock().map_err(migration_error)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version integer primary key,
+                name text not null,
+                checksum text not null,
+                applied_at integer not null)",
+        )
+        .map_err(migration_error)?;
+        let mut statement = conn
+            .prepare("SELECT * FROM schema_migrations ORDER BY version")
+            .map_err(migration_error)?;
+        let mut res = vec![];
+        while let sqlite::State::Row = statement.next().map_err(migration_error)? {
+            res.push(AppliedMigration {
+                version: statement
+                    .read::<i64, _>("version")
+                    .map_err(migration_error)?,
+                name: statement
+                    .read::<String, _>("name")
+                    .map_err(migration_error)?,
+                checksum: statement
+                    .read::<String, _>("checksum")
+                    .map_err(migration_error)?,
+                applied_at: statement
+                    .read::<i64, _>("applied_at")
+                    .map_err(migration_error)?,
+            });
+        }
+        Ok(res)
+    }
+
+    fn apply(&self, migration: &Migration) -> Result<(), MigrationError> {
+        let conn = self.writer.lock().map_err(migration_error)?;
+        Self::transaction(&conn, |conn| {
+            conn.execute(migration.up)?;
+            let mut statement = conn.prepare(
+                "INSERT INTO schema_migrations(version, name, checksum, applied_at) VALUES (?, ?, ?, ?)",
+            )?;
+            statement.bind((1, migration.version))?;
+            statement.bind((2, migration.name))?;
+            statement.bind((3, migration.checksum().as_str()))?;
+            statement.bind((4, time::OffsetDateTime::now_utc().unix_timestamp()))?;
+            statement.next()?;
+            Ok(())
+        })
+        .map_err(migration_error)
+    }
+
+    fn revert(&self, migration: &Migration, down: &str) -> Result<(), MigrationError> {
+        let conn = self.writer.lock().map_err(migration_error)?;
+        Self::transaction(&conn, |conn| {
+            conn.execute(down)?;
+            let mut statement = conn.prepare("DELETE FROM schema_migrations where version = ?")?;
+            statement.bind((1, migration.version))?;
+            statement.next()?;
+            Ok(())
+        })
+        .map_err(migration_error)
+    }
+}
+
+// Opens the pooled read only connections
+pub(crate) struct SqliteConnectionManager {
+    config: SqliteConfig,
+}
+
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = sqlite::Error;
+
+    fn connect(&self) -> sqlite::Result<Connection> {
+        SqliteStore::open(&self.config)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> sqlite::Result<()> {
+        conn.execute("SELECT 1")
+    }
+
+    fn has_broken(&self, _: &mut Connection) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DataObject;
+
+    #[derive(Debug, Clone, DataObject)]
+    #[authrs(table = "widgets")]
+    struct Widget {
+        #[authrs(id)]
+        key: i64,
+        #[authrs(required)]
+        name: String,
+    }
+
+    #[test]
+    fn get_and_delete_by_the_id_column() {
+        let store = SqliteStore::new(&SqliteConfig {
+            path: ":memory:".to_string(),
+            ..SqliteConfig::default()
+        })
+        .unwrap();
+        store
+            .write(|conn| {
+                conn.execute("CREATE TABLE widgets (key integer primary key, name text not null)")?;
+                Ok(())
+            })
+            .unwrap();
+        let widget = Store::create::<_, Widget>(
+            &store,
+            RequestWidget {
+                name: Some("cog".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let got = Store::get::<Widget>(&store, widget.key).unwrap().unwrap();
+        assert_eq!((got.key, got.name.as_str()), (widget.key, "cog"));
+        let deleted = Store::delete::<Widget>(&store, widget.key).unwrap();
+        assert_eq!(deleted.key, widget.key);
+        assert!(Store::get::<Widget>(&store, widget.key).unwrap().is_none());
+        assert!(matches!(
+            Store::delete::<Widget>(&store, widget.key),
+            Err(StoreError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::store::{Comparison, ComparisonCriteria, EqualsCriteria, Query, Row};
+
+use super::DataObject;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, DataObject)]
+#[authrs(table = "audit_log")]
+pub struct AuditEntry {
+    pub id: i64,
+    // unix timestamp
+    #[authrs(required)]
+    pub timestamp: i64,
+    #[authrs(required)]
+    pub actor: String,
+    #[authrs(required)]
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<i64>,
+    // json snapshots of the target
+    pub before: Option<String>,
+    pub after: Option<String>,
+    #[authrs(required)]
+    pub prev_hash: String,
+    #[authrs(required)]
+    pub hash: String,
+}
+
+// Query types
+#[derive(Debug)]
+pub enum AuditEntryQuery {
+    ByActor(AuditEntryByActor),
+    ByTargetType(AuditEntryByTargetType),
+    From(AuditEntryFrom),
+    To(AuditEntryTo),
+}
+
+impl Query for AuditEntryQuery {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        match self {
+            AuditEntryQuery::ByActor(inner) => inner.build(),
+            AuditEntryQuery::ByTargetType(inner) => inner.build(),
+            AuditEntryQuery::From(inner) => inner.build(),
+            AuditEntryQuery::To(inner) => inner.build(),
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            AuditEntryQuery::ByActor(inner) => inner.matches(row),
+            AuditEntryQuery::ByTargetType(inner) => inner.matches(row),
+            AuditEntryQuery::From(inner) => inner.matches(row),
+            AuditEntryQuery::To(inner) => inner.matches(row),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditEntryByActor {
+    inner: EqualsCriteria,
+}
+
+impl AuditEntryByActor {
+    pub fn new(val: String) -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("actor"),
+                val: sqlite::Value::String(val),
+            },
+        }
+    }
+}
+
+impl Query for AuditEntryByActor {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditEntryByTargetType {
+    inner: EqualsCriteria,
+}
+
+impl AuditEntryByTargetType {
+    pub fn new(val: String) -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("target_type"),
+                val: sqlite::Value::String(val),
+            },
+        }
+    }
+}
+
+impl Query for AuditEntryByTargetType {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditEntryFrom {
+    inner: ComparisonCriteria,
+}
+
+impl AuditEntryFrom {
+    pub fn new(val: i64) -> Self {
+        Self {
+            inner: ComparisonCriteria {
+                field: String::from("timestamp"),
+                comparison: Comparison::AtLeast,
+                val: sqlite::Value::Integer(val),
+            },
+        }
+    }
+}
+
+impl Query for AuditEntryFrom {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditEntryTo {
+    inner: ComparisonCriteria,
+}
+
+impl AuditEntryTo {
+    pub fn new(val: i64) -> Self {
+        Self {
+            inner: ComparisonCriteria {
+                field: String::from("timestamp"),
+                comparison: Comparison::AtMost,
+                val: sqlite::Value::Integer(val),
+            },
+        }
+    }
+}
+
+impl Query for AuditEntryTo {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
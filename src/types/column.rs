@@ -0,0 +1,95 @@
+use sqlite::{BindableWithIndex, Statement, Value};
+
+// What a column holds, query string values are parsed to match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Integer,
+    Text,
+    Bool,
+}
+
+impl ColumnType {
+    pub fn parse(&self, val: &str) -> Result<Value, String> {
+        match self {
+            ColumnType::Integer => val
+                .parse::<i64>()
+                .map(Value::Integer)
+                .map_err(|_| format!("`{}` is not an integer", val)),
+            ColumnType::Text => Ok(Value::String(val.to_string())),
+            // stored as 0 or 1
+            ColumnType::Bool => match val {
+                "true" => Ok(Value::Integer(1)),
+                "false" => Ok(Value::Integer(0)),
+                _ => Err(format!("`{}` is not true or false", val)),
+            },
+        }
+    }
+}
+
+// A field type that maps to a single column, used by #[derive(DataObject)]
+pub trait Column: Sized {
+    const TYPE: ColumnType;
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()>;
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self>;
+}
+
+impl Column for i64 {
+    const TYPE: ColumnType = ColumnType::Integer;
+
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()> {
+        BindableWithIndex::bind(*self, statement, idx)
+    }
+
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self> {
+        statement.read::<i64, _>(col)
+    }
+}
+
+impl Column for String {
+    const TYPE: ColumnType = ColumnType::Text;
+
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()> {
+        BindableWithIndex::bind(self.as_str(), statement, idx)
+    }
+
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self> {
+        statement.read::<String, _>(col)
+    }
+}
+
+// stored as 0 or 1, sqlite has no booleans
+impl Column for bool {
+    const TYPE: ColumnType = ColumnType::Bool;
+
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()> {
+        BindableWithIndex::bind(*self as i64, statement, idx)
+    }
+
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self> {
+        Ok(statement.read::<i64, _>(col)? != 0)
+    }
+}
+
+impl Column for Option<i64> {
+    const TYPE: ColumnType = ColumnType::Integer;
+
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()> {
+        BindableWithIndex::bind(*self, statement, idx)
+    }
+
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self> {
+        statement.read::<Option<i64>, _>(col)
+    }
+}
+
+impl Column for Option<String> {
+    const TYPE: ColumnType = ColumnType::Text;
+
+    fn bind(&self, statement: &mut Statement, idx: usize) -> sqlite::Result<()> {
+        BindableWithIndex::bind(self.as_deref(), statement, idx)
+    }
+
+    fn read(statement: &Statement, col: &str) -> sqlite::Result<Self> {
+        statement.read::<Option<String>, _>(col)
+    }
+}
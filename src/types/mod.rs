@@ -1,30 +1,70 @@
-use serde::Deserialize;
+use serde::Serialize;
 use std::fmt;
 
+mod column;
+pub use authrs_derive::DataObject;
+pub use column::{Column, ColumnType};
 mod user;
 use sqlite::{Bindable, Statement};
-pub use user::{RequestUser, User, UserByGuid, UserQuery};
+pub use user::{RequestUser, User, UserByGuid, UserFailures, UserQuery};
 mod note;
 pub use note::{Note, NoteQuery, RequestNote};
+mod refresh_token;
+pub use refresh_token::{
+    RefreshToken, RefreshTokenByFamily, RefreshTokenByTokenHash, RefreshTokenQuery,
+    RefreshTokenUnused, RequestRefreshToken,
+};
+mod audit_entry;
+pub use audit_entry::{
+    AuditEntry, AuditEntryByActor, AuditEntryByTargetType, AuditEntryFrom, AuditEntryQuery,
+    AuditEntryTo, RequestAuditEntry,
+};
+mod webhook;
+pub use webhook::{RequestWebhook, Webhook};
+mod webhook_dead_letter;
+pub use webhook_dead_letter::{RequestWebhookDeadLetter, WebhookDeadLetter};
+mod service_account;
+pub use service_account::{
+    RequestServiceAccount, ServiceAccount, ServiceAccountByClientId, ServiceAccountQuery,
+};
 
-use crate::store::Query;
+use crate::store::{Query, Row, error::StoreResult, filter::Filter};
 
-pub trait DataObject: Sized + Bindable + std::fmt::Debug + Clone {
-    fn from_rows(statement: &mut Statement) -> Vec<Self>;
+pub trait DataObject: Sized + Bindable + std::fmt::Debug + Clone + Send + 'static {
+    fn from_rows(statement: &mut Statement) -> StoreResult<Vec<Self>>;
     fn table_name() -> String;
     fn sql_cols() -> String;
     fn id_col() -> String;
+    // the columns `field[op]=value` query parameters can filter on
+    fn filter_cols() -> &'static [(&'static str, ColumnType)];
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) enum DataType {
-    #[serde(rename = "user")]
-    User,
-    #[serde(rename = "note")]
-    Note,
+// Name a data type is served under, /data/{name}, & that its audit entries,
+// webhook events & changes are tagged with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub struct DataType(&'static str);
+
+impl DataType {
+    pub const USER: DataType = DataType("user");
+    pub const NOTE: DataType = DataType("note");
+
+    pub const fn new(name: &'static str) -> Self {
+        Self(name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.0)
+    }
 }
 
-pub(crate) trait RequestObject: Sized + Bindable + std::fmt::Debug + Clone {
+pub trait RequestObject: Sized + Bindable + std::fmt::Debug + Clone + Send + 'static {
     fn validate_create(&self) -> Result<(), ValidationError>;
     fn validate_update(&self) -> Result<(), ValidationError>;
     fn sql_cols(&self) -> String;
@@ -33,12 +73,31 @@ pub(crate) trait RequestObject: Sized + Bindable + std::fmt::Debug + Clone {
 }
 
 #[derive(Debug)]
-pub(crate) enum ValidationError {
+pub enum ValidationError {
     MissingIdOnUpdate,
     MissingRequiredOnCreate(String),
     IdProvidedOnCreate,
 }
 
+impl ValidationError {
+    // the request field at fault
+    pub fn field(&self) -> &str {
+        match self {
+            ValidationError::MissingIdOnUpdate | ValidationError::IdProvidedOnCreate => "id",
+            ValidationError::MissingRequiredOnCreate(field) => field,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ValidationError::MissingIdOnUpdate | ValidationError::MissingRequiredOnCreate(_) => {
+                "required"
+            }
+            ValidationError::IdProvidedOnCreate => "not_allowed",
+        }
+    }
+}
+
 impl fmt::Display for ValidationError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
@@ -74,33 +133,203 @@ impl std::error::Error for ValidationError {
 }
 
 #[derive(Debug)]
-pub(crate) enum QueryTypes {
-    UserQuery(UserQuery),
-    NoteQuery(NoteQuery),
+pub enum QueryTypes {
+    Filter(Filter),
+    // one of a data type's named queries, e.g. `UserQuery::ByGuid`, parsed from
+    // /data/{type}'s url or built by the server
+    Named(Box<dyn Query>),
+}
+
+impl QueryTypes {
+    pub fn named(query: impl Query + 'static) -> Self {
+        Self::Named(Box::new(query))
+    }
 }
 
 impl Query for QueryTypes {
     fn build(&self) -> (String, Vec<sqlite::Value>) {
         match self {
-            Self::UserQuery(inner) => inner.build(),
-            Self::NoteQuery(inner) => inner.build(),
+            Self::Filter(inner) => inner.build(),
+            Self::Named(inner) => inner.build(),
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            Self::Filter(inner) => inner.matches(row),
+            Self::Named(inner) => inner.matches(row),
         }
     }
 }
 
-impl TryFrom<(&DataType, (&String, &String))> for QueryTypes {
-    type Error = ();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn try_from((dt, (query, val)): (&DataType, (&String, &String))) -> Result<Self, Self::Error> {
-        match dt {
-            DataType::User => {
-                let uq = UserQuery::try_from((query, val))?;
-                Ok(QueryTypes::UserQuery(uq))
-            }
-            DataType::Note => {
-                let nq = NoteQuery::try_from((query, val))?;
-                Ok(QueryTypes::NoteQuery(nq))
-            }
+    #[derive(Debug, Clone, DataObject)]
+    #[authrs(table = "widgets")]
+    struct Widget {
+        #[authrs(id)]
+        key: i64,
+        #[authrs(required)]
+        name: String,
+        colour: Option<String>,
+        #[authrs(default)]
+        label: String,
+        #[authrs(internal)]
+        secret: String,
+        active: bool,
+    }
+
+    #[test]
+    fn user_hides_lock_state() {
+        let user = User {
+            id: 1,
+            guid: "google/1".to_string(),
+            name: None,
+            email: None,
+            picture: None,
+            failed_logins: 3,
+            lockouts: 1,
+            locked_until: 1_700_000_000,
+            known_devices: "abc".to_string(),
+        };
+        let json = serde_json::to_value(&user).unwrap();
+        for col in ["failed_logins", "lockouts", "locked_until", "known_devices"] {
+            assert!(json.get(col).is_none(), "{} is serialized", col);
         }
+        assert_eq!(json["guid"], "google/1");
+    }
+
+    #[test]
+    fn derive_names_the_table_and_columns() {
+        assert_eq!(Widget::table_name(), "widgets");
+        assert_eq!(Widget::sql_cols(), "key,name,colour,label,secret,active");
+        assert_eq!(Widget::id_col(), "key");
+    }
+
+    #[test]
+    fn derive_leaves_internal_fields_out_of_filters() {
+        assert_eq!(
+            Widget::filter_cols(),
+            &[
+                ("key", ColumnType::Integer),
+                ("name", ColumnType::Text),
+                ("colour", ColumnType::Text),
+                ("label", ColumnType::Text),
+                ("active", ColumnType::Bool),
+            ]
+        );
+    }
+
+    #[test]
+    fn derive_request_never_deserializes_internal_fields() {
+        let req: RequestWidget =
+            serde_json::from_str(r#"{"name": "cog", "colour": null, "secret": "hunter2"}"#)
+                .unwrap();
+        assert_eq!(req.name.as_deref(), Some("cog"));
+        // Option fields stay Option rather than nesting
+        assert_eq!(req.colour, None);
+        assert_eq!(req.secret, None);
+        assert_eq!(req.key, None);
+        assert_eq!(req.active, None);
+    }
+
+    #[test]
+    fn derive_request_validates() {
+        let req = RequestWidget::default();
+        match req.validate_create() {
+            Err(ValidationError::MissingRequiredOnCreate(col)) => assert_eq!(col, "name"),
+            other => panic!("expected a missing name, got {:?}", other),
+        }
+        assert!(matches!(
+            req.validate_update(),
+            Err(ValidationError::MissingIdOnUpdate)
+        ));
+
+        let req = RequestWidget {
+            key: Some(1),
+            name: Some("cog".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            req.validate_create(),
+            Err(ValidationError::IdProvidedOnCreate)
+        ));
+        assert!(req.validate_update().is_ok());
+        assert_eq!(req.id(), Some(1));
+
+        let req = RequestWidget {
+            name: Some("cog".to_string()),
+            ..Default::default()
+        };
+        assert!(req.validate_create().is_ok());
+    }
+
+    #[test]
+    fn derive_request_only_writes_set_fields() {
+        let req = RequestWidget {
+            name: Some("cog".to_string()),
+            secret: Some("hunter2".to_string()),
+            active: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(req.sql_cols(), "name,secret,active");
+        assert_eq!(req.sql_placeholders(), "?,?,?");
+        assert_eq!(RequestWidget::default().sql_cols(), "");
+    }
+
+    #[test]
+    fn derive_round_trips_rows() {
+        let conn = sqlite::open(":memory:").unwrap();
+        conn.execute(
+            "create table widgets (key integer primary key, name text not null, colour text, \
+             label text, secret text not null, active integer not null)",
+        )
+        .unwrap();
+        let widget = Widget {
+            key: 1,
+            name: "cog".to_string(),
+            colour: None,
+            label: "spare".to_string(),
+            secret: "hunter2".to_string(),
+            active: true,
+        };
+        let mut statement = conn
+            .prepare(format!(
+                "insert into widgets ({}) values (?,?,?,?,?,?)",
+                Widget::sql_cols()
+            ))
+            .unwrap();
+        statement.bind(widget).unwrap();
+        statement.next().unwrap();
+        // a null label reads as the default
+        conn.execute("insert into widgets values (2, 'gear', 'red', null, 'x', 0)")
+            .unwrap();
+
+        let mut statement = conn
+            .prepare(format!(
+                "select {} from widgets order by key",
+                Widget::sql_cols()
+            ))
+            .unwrap();
+        let rows = Widget::from_rows(&mut statement).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (
+                rows[0].key,
+                rows[0].name.as_str(),
+                rows[0].colour.as_deref()
+            ),
+            (1, "cog", None)
+        );
+        assert_eq!(
+            (rows[0].label.as_str(), rows[0].secret.as_str()),
+            ("spare", "hunter2")
+        );
+        assert!(rows[0].active);
+        assert_eq!(rows[1].colour.as_deref(), Some("red"));
+        assert_eq!(rows[1].label, "");
+        assert!(!rows[1].active);
     }
 }
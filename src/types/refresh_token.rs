@@ -0,0 +1,149 @@
+use crate::store::{EqualsCriteria, Query, Row};
+
+use super::DataObject;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, DataObject)]
+#[authrs(table = "refresh_tokens")]
+pub struct RefreshToken {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    #[authrs(required)]
+    pub token_hash: String,
+    #[authrs(required)]
+    pub family: String,
+    #[authrs(required)]
+    pub user_id: i64,
+    #[authrs(required)]
+    pub client_id: String,
+    #[authrs(required)]
+    pub scopes: String,
+    // unix timestamp
+    #[authrs(required)]
+    pub expires: i64,
+    pub used: bool,
+    pub revoked: bool,
+}
+
+impl RefreshToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= time::OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+// Query types
+#[derive(Debug)]
+pub enum RefreshTokenQuery {
+    ByTokenHash(RefreshTokenByTokenHash),
+    ByFamily(RefreshTokenByFamily),
+    Unused(RefreshTokenUnused),
+}
+
+impl Query for RefreshTokenQuery {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        match self {
+            RefreshTokenQuery::ByTokenHash(inner) => inner.build(),
+            RefreshTokenQuery::ByFamily(inner) => inner.build(),
+            RefreshTokenQuery::Unused(inner) => inner.build(),
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            RefreshTokenQuery::ByTokenHash(inner) => inner.matches(row),
+            RefreshTokenQuery::ByFamily(inner) => inner.matches(row),
+            RefreshTokenQuery::Unused(inner) => inner.matches(row),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RefreshTokenByTokenHash {
+    inner: EqualsCriteria,
+}
+
+impl RefreshTokenByTokenHash {
+    pub fn new(val: String) -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("token_hash"),
+                val: sqlite::Value::String(val),
+            },
+        }
+    }
+}
+
+impl Query for RefreshTokenByTokenHash {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+#[derive(Debug)]
+pub struct RefreshTokenByFamily {
+    inner: EqualsCriteria,
+}
+
+impl RefreshTokenByFamily {
+    pub fn new(val: String) -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("family"),
+                val: sqlite::Value::String(val),
+            },
+        }
+    }
+}
+
+impl Query for RefreshTokenByFamily {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+// tokens that haven't been rotated yet
+#[derive(Debug)]
+pub struct RefreshTokenUnused {
+    inner: EqualsCriteria,
+}
+
+impl RefreshTokenUnused {
+    pub fn new() -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("used"),
+                val: sqlite::Value::Integer(0),
+            },
+        }
+    }
+}
+
+impl Default for RefreshTokenUnused {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Query for RefreshTokenUnused {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::store::{EqualsCriteria, Query, Row};
+
+use super::DataObject;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, DataObject)]
+#[authrs(table = "service_accounts")]
+pub struct ServiceAccount {
+    pub id: i64,
+    #[authrs(required)]
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    #[authrs(required)]
+    pub secret_hash: String,
+    #[authrs(required)]
+    pub name: String,
+    #[authrs(required)]
+    pub scopes: String,
+    pub disabled: bool,
+}
+
+impl ServiceAccount {
+    pub fn scopes(&self) -> Vec<String> {
+        self.scopes.split_whitespace().map(String::from).collect()
+    }
+}
+
+// Query types
+#[derive(Debug)]
+pub enum ServiceAccountQuery {
+    ByClientId(ServiceAccountByClientId),
+}
+
+impl Query for ServiceAccountQuery {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        match self {
+            ServiceAccountQuery::ByClientId(inner) => inner.build(),
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            ServiceAccountQuery::ByClientId(inner) => inner.matches(row),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ServiceAccountByClientId {
+    inner: EqualsCriteria,
+}
+
+impl ServiceAccountByClientId {
+    pub fn new(val: String) -> Self {
+        Self {
+            inner: EqualsCriteria {
+                field: String::from("client_id"),
+                val: sqlite::Value::String(val),
+            },
+        }
+    }
+}
+
+impl Query for ServiceAccountByClientId {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
@@ -1,184 +1,45 @@
-use crate::store::{EqualsCriteria, Query};
+use crate::store::{AndCriteria, EqualsCriteria, Query, Row};
 
-use super::{DataObject, RequestObject, ValidationError};
+use super::DataObject;
 use serde::{Deserialize, Serialize};
-use sqlite::{Bindable, BindableWithIndex, State, Statement};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, DataObject)]
+#[authrs(table = "users")]
 pub struct User {
     pub id: i64,
+    #[authrs(required)]
     pub guid: String,
-    pub name: String,
-    pub email: String,
-    pub picture: String,
-}
-
-impl Bindable for User {
-    fn bind(self, statement: &mut Statement) -> sqlite::Result<()> {
-        self.id.clone().bind(statement, 1)?;
-        self.guid.clone().as_str().bind(statement, 2)?;
-        self.name.clone().as_str().bind(statement, 3)?;
-        self.email.clone().as_str().bind(statement, 4)?;
-        self.picture.clone().as_str().bind(statement, 5)?;
-        Ok(())
-    }
-}
-
-impl DataObject for User {
-    fn from_rows(statement: &mut Statement) -> Vec<Self> {
-        let mut res = vec![];
-        while let Ok(State::Row) = statement.next() {
-            res.push(Self {
-                id: statement.read::<i64, _>("id").unwrap(),
-                guid: statement.read::<String, _>("guid").unwrap(),
-                name: statement.read::<String, _>("name").unwrap(),
-                email: statement.read::<String, _>("email").unwrap(),
-                picture: statement.read::<String, _>("picture").unwrap(),
-            });
-        }
-        return res;
-    }
-
-    fn table_name() -> String {
-        "users".to_string()
-    }
-
-    fn sql_cols() -> String {
-        "id,guid,name,email,picture".to_string()
-    }
-
-    fn id_col() -> String {
-        "id".to_string()
-    }
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct RequestUser {
-    pub id: Option<i64>,
-    pub guid: Option<String>,
+    // nullable in the schema
+    #[authrs(required)]
     pub name: Option<String>,
+    #[authrs(required)]
     pub email: Option<String>,
+    #[authrs(required)]
     pub picture: Option<String>,
+    // lock state is only changed by the auth flows, & never read or written
+    // through the data api
+    // consecutive failed authentication attempts
+    #[serde(default, skip_serializing)]
+    #[authrs(internal)]
+    pub failed_logins: i64,
+    // times the account has been locked since the last success, drives the backoff
+    #[serde(default, skip_serializing)]
+    #[authrs(internal)]
+    pub lockouts: i64,
+    // unix timestamp, 0 when not locked
+    #[serde(default, skip_serializing)]
+    #[authrs(internal)]
+    pub locked_until: i64,
+    // space separated fingerprints of recently used devices, stores without
+    // column defaults leave it null
+    #[serde(default, skip_serializing)]
+    #[authrs(default, internal)]
+    pub known_devices: String,
 }
 
-impl Bindable for RequestUser {
-    fn bind(self, statement: &mut Statement) -> sqlite::Result<()> {
-        let mut idx = 1;
-        if let Some(id) = self.id {
-            id.clone().bind(statement, idx)?;
-            idx += 1;
-        }
-        if let Some(guid) = self.guid {
-            guid.clone().as_str().bind(statement, idx)?;
-            idx += 1;
-        }
-        if let Some(name) = self.name {
-            name.clone().as_str().bind(statement, idx)?;
-            idx += 1;
-        }
-        if let Some(email) = self.email {
-            email.clone().as_str().bind(statement, idx)?;
-            idx += 1;
-        }
-        if let Some(picture) = self.picture {
-            picture.clone().as_str().bind(statement, idx)?;
-        }
-        Ok(())
-    }
-}
-
-impl RequestObject for RequestUser {
-    fn validate_create(&self) -> Result<(), ValidationError> {
-        match self.guid {
-            Some(_) => {}
-            None => {
-                return Err(ValidationError::MissingRequiredOnCreate(String::from(
-                    "guid",
-                )));
-            }
-        }
-        match self.name {
-            Some(_) => {}
-            None => {
-                return Err(ValidationError::MissingRequiredOnCreate(String::from(
-                    "name",
-                )));
-            }
-        }
-        match self.email {
-            Some(_) => {}
-            None => {
-                return Err(ValidationError::MissingRequiredOnCreate(String::from(
-                    "email",
-                )));
-            }
-        }
-        match self.picture {
-            Some(_) => {}
-            None => {
-                return Err(ValidationError::MissingRequiredOnCreate(String::from(
-                    "picture",
-                )));
-            }
-        }
-        match self.id {
-            Some(_) => {
-                return Err(ValidationError::IdProvidedOnCreate);
-            }
-            None => {}
-        }
-        Ok(())
-    }
-
-    fn validate_update(&self) -> Result<(), ValidationError> {
-        match self.id {
-            Some(_) => Ok(()),
-            None => Err(ValidationError::MissingIdOnUpdate),
-        }
-    }
-
-    fn sql_cols(&self) -> String {
-        let mut cols = vec![];
-        if let Some(_) = self.id {
-            cols.push("id");
-        }
-        if let Some(_) = self.guid {
-            cols.push("guid");
-        }
-        if let Some(_) = self.name {
-            cols.push("name");
-        }
-        if let Some(_) = self.email {
-            cols.push("email");
-        }
-        if let Some(_) = self.picture {
-            cols.push("picture");
-        }
-        cols.join(",")
-    }
-
-    fn sql_placeholders(&self) -> String {
-        let mut ct = 0;
-        if let Some(_) = self.id {
-            ct += 1;
-        }
-        if let Some(_) = self.guid {
-            ct += 1;
-        }
-        if let Some(_) = self.name {
-            ct += 1;
-        }
-        if let Some(_) = self.email {
-            ct += 1;
-        }
-        if let Some(_) = self.picture {
-            ct += 1;
-        }
-        vec!["?"; ct].join(",")
-    }
-
-    fn id(&self) -> Option<i64> {
-        self.id
+impl User {
+    pub fn is_locked(&self) -> bool {
+        self.locked_until > time::OffsetDateTime::now_utc().unix_timestamp()
     }
 }
 
@@ -186,12 +47,21 @@ impl RequestObject for RequestUser {
 #[derive(Debug)]
 pub enum UserQuery {
     ByGuid(UserByGuid),
+    Failures(UserFailures),
 }
 
 impl Query for UserQuery {
     fn build(&self) -> (String, Vec<sqlite::Value>) {
         match self {
             UserQuery::ByGuid(inner) => inner.build(),
+            UserQuery::Failures(inner) => inner.build(),
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            UserQuery::ByGuid(inner) => inner.matches(row),
+            UserQuery::Failures(inner) => inner.matches(row),
         }
     }
 }
@@ -229,4 +99,45 @@ impl Query for UserByGuid {
         use crate::store::Criteria;
         self.inner.build()
     }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
+}
+
+// users whose failure counts are still the ones read, for updating them without
+// losing a concurrent failure
+#[derive(Debug)]
+pub struct UserFailures {
+    inner: AndCriteria<EqualsCriteria, EqualsCriteria>,
+}
+
+impl UserFailures {
+    pub fn new(failed_logins: i64, lockouts: i64) -> Self {
+        Self {
+            inner: AndCriteria {
+                left: EqualsCriteria {
+                    field: String::from("failed_logins"),
+                    val: sqlite::Value::Integer(failed_logins),
+                },
+                right: EqualsCriteria {
+                    field: String::from("lockouts"),
+                    val: sqlite::Value::Integer(lockouts),
+                },
+            },
+        }
+    }
+}
+
+impl Query for UserFailures {
+    fn build(&self) -> (String, Vec<sqlite::Value>) {
+        use crate::store::Criteria;
+        self.inner.build()
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        use crate::store::Criteria;
+        self.inner.matches(row)
+    }
 }
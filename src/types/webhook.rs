@@ -0,0 +1,23 @@
+use super::DataObject;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, DataObject)]
+#[authrs(table = "webhooks")]
+pub struct Webhook {
+    pub id: i64,
+    #[authrs(required)]
+    pub url: String,
+    // space separated event names
+    #[authrs(required)]
+    pub events: String,
+    // hmac key, only returned when the webhook is created
+    #[serde(skip_serializing)]
+    #[authrs(required)]
+    pub secret: String,
+}
+
+impl Webhook {
+    pub fn events(&self) -> Vec<String> {
+        self.events.split_whitespace().map(String::from).collect()
+    }
+}
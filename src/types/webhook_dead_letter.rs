@@ -0,0 +1,24 @@
+use super::DataObject;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, DataObject)]
+#[authrs(table = "webhook_dead_letters")]
+pub struct WebhookDeadLetter {
+    pub id: i64,
+    #[authrs(required)]
+    pub webhook_id: i64,
+    #[authrs(required)]
+    pub delivery_id: String,
+    #[authrs(required)]
+    pub event: String,
+    // the signed request body
+    #[authrs(required)]
+    pub payload: String,
+    #[authrs(required)]
+    pub attempts: i64,
+    #[authrs(required)]
+    pub last_error: String,
+    // unix timestamp
+    #[authrs(required)]
+    pub created: i64,
+}
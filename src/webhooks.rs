@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use oauth2::reqwest;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    Store,
+    auth::tokens,
+    config::WebhookConfig,
+    store::spawn_blocking,
+    types::{DataType, RequestWebhookDeadLetter, Webhook, WebhookDeadLetter},
+};
+
+pub const EVENT_HEADER: &str = "x-authrs-event";
+pub const DELIVERY_HEADER: &str = "x-authrs-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-authrs-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-authrs-signature";
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a, T: Serialize> {
+    id: &'a str,
+    event: &'a str,
+    timestamp: i64,
+    data: &'a T,
+}
+
+// A signed request waiting to be sent to a subscriber
+#[derive(Debug, Clone)]
+struct Delivery {
+    webhook: Webhook,
+    id: String,
+    event: String,
+    body: String,
+}
+
+// Fans events out to subscribed webhooks. Deliveries run in the background so
+// slow subscribers never hold up the request that triggered them.
+pub struct Webhooks<S: Store> {
+    store: Arc<S>,
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl<S: Store> Webhooks<S> {
+    pub fn new(store: Arc<S>, config: WebhookConfig) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF vulnerabilities.
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(config.timeout.unsigned_abs())
+            .build()
+            .expect("Client should build");
+        Self {
+            store,
+            client,
+            config,
+        }
+    }
+
+    // Queues `data` for every webhook subscribed to `data_type`.`action`. The
+    // subscribers are read in the background too, off the caller's worker
+    pub(crate) fn emit(&self, data_type: DataType, action: &str, data: &impl Serialize) {
+        let event = format!("{}.{}", data_type, action);
+        let id = tokens::generate_secret();
+        let payload = WebhookPayload {
+            id: &id,
+            event: &event,
+            timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+            data,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Could not serialize {} event: {:?}", event, e);
+                return;
+            }
+        };
+        let store = self.store.clone();
+        let client = self.client.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            let webhooks = {
+                let store = store.clone();
+                spawn_blocking(move |_| store.get_queries::<Webhook>(vec![])).await
+            };
+            let subscribers = match webhooks {
+                Ok(webhooks) => webhooks.into_iter().filter(|w| w.events().contains(&event)),
+                Err(e) => {
+                    error!("Could not read webhooks for {} event: {:?}", event, e);
+                    return;
+                }
+            };
+            for webhook in subscribers {
+                spawn(
+                    store.clone(),
+                    client.clone(),
+                    config.clone(),
+                    Delivery {
+                        webhook,
+                        id: id.clone(),
+                        event: event.clone(),
+                        body: body.clone(),
+                    },
+                );
+            }
+        });
+    }
+
+    // Sends a dead lettered delivery again, dropping it from the dead letters
+    pub(crate) fn redeliver(&self, dead_letter: WebhookDeadLetter) -> bool {
+        let webhook = match self.store.get::<Webhook>(dead_letter.webhook_id) {
+            Ok(Some(webhook)) => webhook,
+            Ok(None) => return false,
+            Err(e) => {
+                error!("Could not read webhook {}: {:?}", dead_letter.webhook_id, e);
+                return false;
+            }
+        };
+        if let Err(e) = self.store.delete::<WebhookDeadLetter>(dead_letter.id) {
+            error!("Could not remove dead letter {}: {:?}", dead_letter.id, e);
+            return false;
+        }
+        spawn(
+            self.store.clone(),
+            self.client.clone(),
+            self.config.clone(),
+            Delivery {
+                webhook,
+                id: dead_letter.delivery_id,
+                event: dead_letter.event,
+                body: dead_letter.payload,
+            },
+        );
+        true
+    }
+}
+
+fn spawn<S: Store>(
+    store: Arc<S>,
+    client: reqwest::Client,
+    config: WebhookConfig,
+    delivery: Delivery,
+) {
+    tokio::spawn(async move { deliver(store, client, config, delivery).await });
+}
+
+async fn deliver<S: Store>(
+    store: Arc<S>,
+    client: reqwest::Client,
+    config: WebhookConfig,
+    delivery: Delivery,
+) {
+    let mut delay = config.retry_delay.unsigned_abs();
+    let mut last_error = String::new();
+    for attempt in 1..=config.max_attempts {
+        match send(&client, &delivery).await {
+            Ok(()) => {
+                debug!(
+                    "delivered {} {} to webhook {}",
+                    delivery.event, delivery.id, delivery.webhook.id
+                );
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "delivery {} to webhook {} failed, attempt {}: {}",
+                    delivery.id, delivery.webhook.id, attempt, e
+                );
+                last_error = e;
+            }
+        }
+        if attempt < config.max_attempts {
+            tokio::time::sleep(delay).await;
+            delay = delay
+                .saturating_mul(2)
+                .min(config.max_retry_delay.unsigned_abs());
+        }
+    }
+
+    info!(target: "audit", "webhook {} dead lettered delivery {}", delivery.webhook.id, delivery.id);
+    let request = RequestWebhookDeadLetter {
+        webhook_id: Some(delivery.webhook.id),
+        delivery_id: Some(delivery.id),
+        event: Some(delivery.event),
+        payload: Some(delivery.body),
+        attempts: Some(config.max_attempts as i64),
+        last_error: Some(last_error),
+        created: Some(time::OffsetDateTime::now_utc().unix_timestamp()),
+        ..Default::default()
+    };
+    if let Err(e) = store.create::<_, WebhookDeadLetter>(request) {
+        error!("Could not record dead letter: {:?}", e);
+    }
+}
+
+async fn send(client: &reqwest::Client, delivery: &Delivery) -> Result<(), String> {
+    let timestamp = time::OffsetDateTime::now_utc().unix_timestamp().to_string();
+    let signature = sign(&delivery.webhook.secret, &timestamp, &delivery.body)?;
+    let response = client
+        .post(&delivery.webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(TIMESTAMP_HEADER, &timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .body(delivery.body.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("responded {}", status)),
+    }
+}
+
+// `sha256=` & the hex HMAC-SHA256 of "{timestamp}.{body}", covering the
+// timestamp lets subscribers reject replayed deliveries
+fn sign(secret: &str, timestamp: &str, body: &str) -> Result<String, String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|e| e.to_string())?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    Ok(format!("sha256={:x}", mac.finalize().into_bytes()))
+}